@@ -0,0 +1,37 @@
+// Where the current time comes from, so the handler can be given a fake one
+// to test time dependent behaviour without waiting.
+pub trait Clock: Send + Sync {
+    // The current unix time in seconds.
+    fn now(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        let now = std::time::SystemTime::now();
+        now.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+    }
+}
+
+// A clock which only moves when told to.
+#[cfg(test)]
+pub struct MockClock(std::sync::atomic::AtomicU64);
+
+#[cfg(test)]
+impl MockClock {
+    pub fn new(now: u64) -> Self {
+        Self(std::sync::atomic::AtomicU64::new(now))
+    }
+
+    pub fn advance(&self, seconds: u64) {
+        self.0.fetch_add(seconds, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> u64 {
+        self.0.load(std::sync::atomic::Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,836 @@
+use std::collections::{HashMap, HashSet};
+
+use serenity::builder::{
+    CreateCommand,
+    CreateCommandOption,
+    CreateEmbed,
+    CreateInteractionResponse,
+    CreateInteractionResponseMessage,
+    EditInteractionResponse,
+};
+use serenity::http::Http;
+use serenity::model::application::{
+    Command,
+    CommandDataOption,
+    CommandDataOptionValue,
+    CommandInteraction,
+    CommandOptionType,
+    ComponentInteraction,
+};
+use serenity::model::guild::{Member, Role};
+use serenity::model::id::{GuildId, RoleId, UserId};
+use serenity::model::permissions::Permissions;
+use serenity::prelude::Context;
+
+use crate::{Error, Handler, Hold, MemberHistory, RoleGrant, StatsSnapshot, RECENT_MEMBER_WINDOW};
+
+// How many roles a report lists before summarising the rest.
+const MAX_LISTED_ROLES: usize = 20;
+
+// Likewise for members, whose lines run longer.
+const MAX_LISTED_MEMBERS: usize = 15;
+
+// Prefixes of the IDs of buttons for approving restores, followed by the 
+// member's ID.
+const APPROVE_PREFIX: &str = "rolepersist-approve:";
+const DENY_PREFIX: &str = "rolepersist-deny:";
+
+// How many days /rolepersist trends covers by default, and at most.
+const DEFAULT_TREND_DAYS: u64 = 7;
+const MAX_TREND_DAYS: u64 = 30;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// The most characters Discord allows in an embed field's value.
+const EMBED_FIELD_LIMIT: usize = 1024;
+
+// Choices for /rolepersist mode.
+const MODE_ALL: &str = "all";
+const MODE_STICKY_ONLY: &str = "sticky-only";
+
+pub async fn register(http: &Http) -> Result<(), serenity::Error> {
+    let command = CreateCommand::new("rolepersist")
+        .description("Role persistence tools")
+        .default_member_permissions(Permissions::MANAGE_ROLES)
+        .dm_permission(false)
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "diagnose",
+            "Check whether the bot is able to restore roles in this server",
+        ))
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "stats",
+            "Show what the bot has stored for this server",
+        ))
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "trends",
+            "Show members tracked and roles restored in this server day by day",
+        ).add_sub_option(CreateCommandOption::new(
+            CommandOptionType::Integer,
+            "days",
+            "How many days back to go",
+        ).min_int_value(1).max_int_value(MAX_TREND_DAYS)))
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "unrestorable",
+            "List stored roles which are above the bot and can't be restored",
+        ))
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "clear",
+            "Forget a member's stored roles, they keep being tracked from now on",
+        ).add_sub_option(CreateCommandOption::new(
+            CommandOptionType::User,
+            "user",
+            "The member to forget the roles of",
+        ).required(true)))
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "history",
+            "Show when a member was first and last seen, and how often they've rejoined",
+        ).add_sub_option(CreateCommandOption::new(
+            CommandOptionType::User,
+            "user",
+            "The member to show the history of",
+        ).required(true)))
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "restore",
+            "Give a member back all their stored roles, however many there are",
+        ).add_sub_option(CreateCommandOption::new(
+            CommandOptionType::User,
+            "user",
+            "The member to restore the roles of",
+        ).required(true)))
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "hold",
+            "Stop a member's roles being restored while they're looked into, they're still stored",
+        ).add_sub_option(CreateCommandOption::new(
+            CommandOptionType::User,
+            "user",
+            "The member to hold",
+        ).required(true)))
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "unhold",
+            "Let a held member's roles be restored again",
+        ).add_sub_option(CreateCommandOption::new(
+            CommandOptionType::User,
+            "user",
+            "The member to let go",
+        ).required(true)))
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "holds",
+            "List the members whose roles aren't being restored",
+        ))
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "backup",
+            "Back up the bot's database (bot owner only)",
+        ))
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "dryrun",
+            "Only log what restores would do in this server, without changing anyone's roles",
+        ).add_sub_option(CreateCommandOption::new(
+            CommandOptionType::Boolean,
+            "enabled",
+            "Whether restores are only logged, leave out to go back to the configured setting",
+        )))
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "stick",
+            "Mark a role as sticky, kept by members who leave and rejoin",
+        ).add_sub_option(CreateCommandOption::new(
+            CommandOptionType::Role,
+            "role",
+            "The role to make sticky",
+        ).required(true)))
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "unstick",
+            "Stop a role being sticky",
+        ).add_sub_option(CreateCommandOption::new(
+            CommandOptionType::Role,
+            "role",
+            "The role to stop being sticky",
+        ).required(true)))
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "mode",
+            "Choose whether all roles are persisted in this server or only sticky ones",
+        ).add_sub_option(CreateCommandOption::new(
+            CommandOptionType::String,
+            "mode",
+            "Which roles are persisted",
+        ).required(true)
+            .add_string_choice("All roles", MODE_ALL)
+            .add_string_choice("Sticky roles only", MODE_STICKY_ONLY)))
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "status",
+            "Show how roles are persisted in this server",
+        ))
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "resync",
+            "Store what every member has now, without restoring anyone's roles",
+        ))
+        .add_option(CreateCommandOption::new(
+            CommandOptionType::SubCommand,
+            "undo-forget",
+            "Keep roles stored from before the bot was removed, which would otherwise be deleted",
+        ));
+
+    Command::create_global_command(http, command).await?;
+    Ok(())
+}
+
+pub async fn handle(handler: &Handler, context: &Context, command: &CommandInteraction) {
+    if command.data.name != "rolepersist" {
+        return;
+    }
+
+    let subcommand = command.data.options.first().map(|option| option.name.as_str());
+    let required = subcommand.map_or(Permissions::MANAGE_ROLES, required_permissions);
+    let mut embed = None;
+    let content = match (command.guild_id, subcommand) {
+        (None, _) => "This command can only be used in a server".to_string(),
+        // Server admins can let anyone use the command, so what each 
+        // subcommand needs is checked here too.
+        (Some(_), _) if !has_permissions(command.member.as_deref(), required) => format!(
+            "You need the {} permission to use this command",
+            required.get_permission_names().join(", "),
+        ),
+        (Some(server_id), Some("diagnose")) => {
+            match diagnose(handler, &context.http, server_id).await {
+                Ok(report) => report,
+                Err(error) => {
+                    println!("Error diagnosing guild {}: {}", server_id.get(), error);
+                    format!("Unable to diagnose this server: {}", error)
+                },
+            }
+        },
+        (Some(server_id), Some("stats")) => {
+            match stats(handler, server_id).await {
+                Ok(report) => report,
+                Err(error) => {
+                    println!("Error reading stats of guild {}: {}", server_id.get(), error);
+                    format!("Unable to read stats for this server: {}", error)
+                },
+            }
+        },
+        (Some(server_id), Some("trends")) => {
+            let days = integer_option(command).map_or(DEFAULT_TREND_DAYS, |days| days.clamp(1, MAX_TREND_DAYS as i64) as u64);
+            match trends(handler, server_id, days).await {
+                Ok(report) => report,
+                Err(error) => {
+                    println!("Error reading stats history of guild {}: {}", server_id.get(), error);
+                    format!("Unable to read stats history for this server: {}", error)
+                },
+            }
+        },
+        (Some(server_id), Some("unrestorable")) => {
+            match unrestorable(handler, &context.http, server_id).await {
+                Ok(report) => report,
+                Err(error) => {
+                    println!("Error listing unrestorable roles of guild {}: {}", server_id.get(), error);
+                    format!("Unable to check roles in this server: {}", error)
+                },
+            }
+        },
+        (Some(server_id), Some("clear")) => {
+            match user_option(command) {
+                Some(user_id) => match handler.clear_member(user_id, server_id).await {
+                    Ok(0) => format!("Nothing was stored for <@{}>.", user_id.get()),
+                    Ok(rows) => format!(
+                        "Cleared {} stored rows for <@{}>, they'll be tracked again from their next update.",
+                        rows,
+                        user_id.get(),
+                    ),
+                    Err(error) => {
+                        println!("Error clearing member {} in guild {}: {}", user_id.get(), server_id.get(), error);
+                        format!("Unable to clear stored roles: {}", error)
+                    },
+                },
+                None => "No user given".to_string(),
+            }
+        },
+        (Some(server_id), Some("history")) => {
+            match user_option(command) {
+                Some(user_id) => match handler.member_history(user_id, server_id).await {
+                    Ok(Some(history)) => {
+                        embed = Some(history_embed(server_id, user_id, &history));
+                        String::new()
+                    },
+                    Ok(None) => format!("<@{}> hasn't been seen in this server.", user_id.get()),
+                    Err(error) => {
+                        println!("Error reading history of member {} in guild {}: {}", user_id.get(), server_id.get(), error);
+                        format!("Unable to read their history: {}", error)
+                    },
+                },
+                None => "No user given".to_string(),
+            }
+        },
+        (Some(server_id), Some("restore")) => {
+            match user_option(command) {
+                Some(user_id) => match context.http.get_member(server_id, user_id).await {
+                    Ok(member) => {
+                        handler.enqueue_restore(context, member.into());
+                        format!("Restoring stored roles for <@{}>.", user_id.get())
+                    },
+                    Err(error) => format!("Unable to find <@{}> in this server: {}", user_id.get(), error),
+                },
+                None => "No user given".to_string(),
+            }
+        },
+        (Some(server_id), Some(name @ ("hold" | "unhold"))) => {
+            let hold = name == "hold";
+            match user_option(command) {
+                Some(user_id) => match handler.set_hold(user_id, server_id, command.user.id, hold).await {
+                    Ok(true) if hold => format!("<@{}> is held, their roles won't be restored until they're let go.", user_id.get()),
+                    Ok(true) => format!("<@{}> is no longer held, their roles are restored next time they rejoin or change. Use `/rolepersist restore` to do it now.", user_id.get()),
+                    Ok(false) if hold => format!("<@{}> was already held.", user_id.get()),
+                    Ok(false) => format!("<@{}> wasn't held.", user_id.get()),
+                    Err(error) => {
+                        println!("Error changing hold on member {} in guild {}: {}", user_id.get(), server_id.get(), error);
+                        format!("Unable to change their hold: {}", error)
+                    },
+                },
+                None => "No user given".to_string(),
+            }
+        },
+        (Some(server_id), Some("holds")) => {
+            match handler.holds(server_id).await {
+                Ok(holds) => holds_report(&holds),
+                Err(error) => {
+                    println!("Error listing holds in guild {}: {}", server_id.get(), error);
+                    format!("Unable to list held members: {}", error)
+                },
+            }
+        },
+        (Some(server_id), Some("dryrun")) => {
+            let enabled = boolean_option(command);
+            match handler.set_dry_run(server_id, enabled).await {
+                Ok(()) => match handler.is_dry_run(server_id.get()).await {
+                    true => "Dry run is on, restores in this server are only logged.".to_string(),
+                    false => "Dry run is off, roles are restored in this server.".to_string(),
+                },
+                Err(error) => {
+                    println!("Error setting dry run in guild {}: {}", server_id.get(), error);
+                    format!("Unable to change dry run: {}", error)
+                },
+            }
+        },
+        (Some(server_id), Some(name @ ("stick" | "unstick"))) => {
+            let sticky = name == "stick";
+            match role_option(command) {
+                Some(role_id) => match handler.set_sticky(server_id, role_id, sticky).await {
+                    Ok(true) if sticky => format!("<@&{}> is now sticky.", role_id.get()),
+                    Ok(true) => format!("<@&{}> is no longer sticky.", role_id.get()),
+                    Ok(false) if sticky => format!("<@&{}> was already sticky.", role_id.get()),
+                    Ok(false) => format!("<@&{}> wasn't sticky.", role_id.get()),
+                    Err(error) => {
+                        println!("Error changing sticky role {} in guild {}: {}", role_id.get(), server_id.get(), error);
+                        format!("Unable to change sticky roles: {}", error)
+                    },
+                },
+                None => "No role given".to_string(),
+            }
+        },
+        (Some(server_id), Some("mode")) => {
+            let sticky_only = string_option(command) == Some(MODE_STICKY_ONLY);
+            match handler.set_sticky_only(server_id, sticky_only).await {
+                Ok(()) if sticky_only => "Only sticky roles are persisted in this server now.".to_string(),
+                Ok(()) => "All roles are persisted in this server now.".to_string(),
+                Err(error) => {
+                    println!("Error setting mode in guild {}: {}", server_id.get(), error);
+                    format!("Unable to change mode: {}", error)
+                },
+            }
+        },
+        (Some(server_id), Some("status")) => {
+            match status(handler, server_id).await {
+                Ok(report) => report,
+                Err(error) => {
+                    println!("Error reading status of guild {}: {}", server_id.get(), error);
+                    format!("Unable to read settings for this server: {}", error)
+                },
+            }
+        },
+        // Resyncs take longer than Discord waits for a response.
+        (Some(server_id), Some("resync")) => return resync(handler, context, command, server_id).await,
+        (Some(server_id), Some("undo-forget")) => match handler.cancel_forget(server_id).await {
+            Ok(true) => "Roles stored for this server will be kept.".to_string(),
+            Ok(false) => "Nothing stored for this server is due to be deleted.".to_string(),
+            Err(error) => {
+                println!("Error keeping data for guild {}: {}", server_id.get(), error);
+                format!("Unable to keep stored roles: {}", error)
+            },
+        },
+        (Some(_), Some("backup")) => match is_owner(&context.http, command.user.id).await {
+            Ok(true) => match handler.backup().await {
+                Ok((path, size)) => format!("Backed up the database to `{}` ({} bytes).", path, size),
+                Err(error) => {
+                    println!("Error backing up database: {}", error);
+                    format!("Unable to back up the database: {}", error)
+                },
+            },
+            Ok(false) => "Only the bot's owner can back up its database".to_string(),
+            Err(error) => format!("Unable to check who owns the bot: {}", error),
+        },
+        _ => "Unknown command".to_string(),
+    };
+
+    let mut message = CreateInteractionResponseMessage::new()
+        .content(content)
+        .ephemeral(true);
+    if let Some(embed) = embed {
+        message = message.embed(embed);
+    }
+    let response = CreateInteractionResponse::Message(message);
+
+    if let Err(error) = command.create_response(&context.http, response).await {
+        println!("Error responding to command: {}", error);
+    }
+}
+
+async fn resync(handler: &Handler, context: &Context, command: &CommandInteraction, server_id: GuildId) {
+    let deferred = CreateInteractionResponse::Defer(CreateInteractionResponseMessage::new().ephemeral(true));
+    if let Err(error) = command.create_response(&context.http, deferred).await {
+        println!("Error responding to command: {}", error);
+        return;
+    }
+
+    let content = match handler.resync_guild(context, server_id).await {
+        Ok(Some((members, elapsed))) => format!(
+            "Resynced {} members in {:.1}s.",
+            members,
+            elapsed.as_secs_f64(),
+        ),
+        Ok(None) => "This server is already being resynced.".to_string(),
+        Err(error) => {
+            println!("Error resyncing guild {}: {}", server_id.get(), error);
+            format!("Unable to resync this server: {}", error)
+        },
+    };
+
+    let response = EditInteractionResponse::new().content(content);
+    if let Err(error) = command.edit_response(&context.http, response).await {
+        println!("Error responding to command: {}", error);
+    }
+}
+
+// What using a subcommand takes, ones changing how the bot behaves in a 
+// server need more than those which only report on it.
+fn required_permissions(subcommand: &str) -> Permissions {
+    match subcommand {
+        "diagnose" | "stats" | "trends" | "unrestorable" | "status" | "history" | "holds" => Permissions::MANAGE_ROLES,
+        // Checked against the bot's owner instead.
+        "backup" => Permissions::empty(),
+        _ => Permissions::MANAGE_GUILD,
+    }
+}
+
+// Whether the member using an interaction has all the given permissions.
+fn has_permissions(member: Option<&Member>, required: Permissions) -> bool {
+    member.and_then(|member| member.permissions)
+        .is_some_and(|permissions| permissions.contains(required))
+}
+
+// The ID of a button deciding whether a member's roles are restored.
+pub fn approval_id(approve: bool, user_id: u64) -> String {
+    match approve {
+        true => format!("{}{}", APPROVE_PREFIX, user_id),
+        false => format!("{}{}", DENY_PREFIX, user_id),
+    }
+}
+
+// Handles staff pressing the buttons on a restore approval request.
+pub async fn handle_component(handler: &Handler, context: &Context, component: &ComponentInteraction) {
+    let custom_id = component.data.custom_id.as_str();
+    let (approved, user_id) = match (custom_id.strip_prefix(APPROVE_PREFIX), custom_id.strip_prefix(DENY_PREFIX)) {
+        (Some(user_id), _) => (true, user_id),
+        (_, Some(user_id)) => (false, user_id),
+        _ => return,
+    };
+    let (server_id, user_id) = match (component.guild_id, user_id.parse().ok().filter(|id| *id != 0)) {
+        (Some(server_id), Some(user_id)) => (server_id, UserId::new(user_id)),
+        _ => return,
+    };
+
+    let response = if !has_permissions(component.member.as_ref(), Permissions::MANAGE_ROLES) {
+        CreateInteractionResponse::Message(
+            CreateInteractionResponseMessage::new()
+                .content("You need the Manage Roles permission to decide restores")
+                .ephemeral(true)
+        )
+    } else {
+        let content = match handler.decide_approval(user_id, server_id, approved).await {
+            Ok(true) => {
+                handler.apply_approval(context, user_id, server_id).await;
+                match approved {
+                    true => format!("<@{}> is having their roles restored, approved by <@{}>.", user_id.get(), component.user.id.get()),
+                    false => format!("<@{}> won't have their roles restored, decided by <@{}>.", user_id.get(), component.user.id.get()),
+                }
+            },
+            Ok(false) => format!("Restoring <@{}> has already been decided.", user_id.get()),
+            Err(error) => {
+                println!("Error deciding restore of member {} in guild {}: {}", user_id.get(), server_id.get(), error);
+                format!("Unable to record the decision: {}", error)
+            },
+        };
+        CreateInteractionResponse::UpdateMessage(
+            CreateInteractionResponseMessage::new()
+                .content(content)
+                .components(vec![])
+        )
+    };
+
+    if let Err(error) = component.create_response(&context.http, response).await {
+        println!("Error responding to button: {}", error);
+    }
+}
+
+// Whether a user owns the bot's application, or is on the team which does.
+async fn is_owner(http: &Http, user_id: UserId) -> Result<bool, serenity::Error> {
+    let info = http.get_current_application_info().await?;
+    let owner = info.owner.is_some_and(|owner| owner.id == user_id);
+    let on_team = info.team.is_some_and(|team| team.members.iter().any(|member| member.user.id == user_id));
+    Ok(owner || on_team)
+}
+
+// The user option given to a subcommand.
+fn user_option(command: &CommandInteraction) -> Option<UserId> {
+    subcommand_options(command).iter().find_map(|option| match option.value {
+        CommandDataOptionValue::User(user_id) => Some(user_id),
+        _ => None,
+    })
+}
+
+// The boolean option given to a subcommand.
+fn boolean_option(command: &CommandInteraction) -> Option<bool> {
+    subcommand_options(command).iter().find_map(|option| match option.value {
+        CommandDataOptionValue::Boolean(value) => Some(value),
+        _ => None,
+    })
+}
+
+// The role option given to a subcommand.
+fn role_option(command: &CommandInteraction) -> Option<RoleId> {
+    subcommand_options(command).iter().find_map(|option| match option.value {
+        CommandDataOptionValue::Role(role_id) => Some(role_id),
+        _ => None,
+    })
+}
+
+// The integer option given to a subcommand.
+fn integer_option(command: &CommandInteraction) -> Option<i64> {
+    subcommand_options(command).iter().find_map(|option| match option.value {
+        CommandDataOptionValue::Integer(value) => Some(value),
+        _ => None,
+    })
+}
+
+// The string option given to a subcommand.
+fn string_option(command: &CommandInteraction) -> Option<&str> {
+    subcommand_options(command).iter().find_map(|option| match &option.value {
+        CommandDataOptionValue::String(value) => Some(value.as_str()),
+        _ => None,
+    })
+}
+
+fn subcommand_options(command: &CommandInteraction) -> &[CommandDataOption] {
+    match command.data.options.first().map(|option| &option.value) {
+        Some(CommandDataOptionValue::SubCommand(options)) => options,
+        _ => &[],
+    }
+}
+
+// Checks the bot's permissions and position in a guild, reporting whether
+// restores can work at all and which stored roles are out of its reach.
+async fn diagnose(handler: &Handler, http: &Http, server_id: GuildId) -> Result<String, Error> {
+    let guild_roles = guild_roles(http, server_id).await?;
+    let bot_roles = bot_roles(http, server_id, &guild_roles).await?;
+
+    let everyone = guild_roles.get(&RoleId::new(server_id.get()))
+        .map(|role| role.permissions)
+        .unwrap_or_else(Permissions::empty);
+    let permissions = bot_roles.iter().fold(everyone, |permissions, role| permissions | role.permissions);
+    let can_manage = permissions.intersects(Permissions::ADMINISTRATOR | Permissions::MANAGE_ROLES);
+
+    let top_role = bot_roles.iter().max_by_key(|role| role.position);
+    let top_position = top_role.map(|role| role.position).unwrap_or(0);
+
+    let allowed_privileged = handler.config.guild(server_id.get())
+        .map(|guild| guild.allow_privileged_roles.as_slice())
+        .unwrap_or_default();
+
+    let mut deleted = 0;
+    let mut blocked = vec![];
+    for role in handler.stored_guild_roles(server_id.get()).await? {
+        match guild_roles.get(&RoleId::new(role)) {
+            None => deleted += 1,
+            Some(role) if role.managed => blocked.push(format!("{} (managed by an integration)", role.name)),
+            Some(role) if role.permissions.contains(Permissions::ADMINISTRATOR) && !allowed_privileged.contains(&role.id.get()) => {
+                blocked.push(format!("{} (blocked: privileged, not in allow_privileged_roles)", role.name))
+            },
+            Some(role) if role.position >= top_position => blocked.push(format!("{} (above the bot)", role.name)),
+            Some(_) => (),
+        }
+    }
+
+    let mut report = vec![];
+
+    if can_manage {
+        report.push("The bot has the Manage Roles permission.".to_string());
+    } else {
+        report.push("The bot is missing the Manage Roles permission, no roles can be restored.".to_string());
+    }
+
+    match top_role {
+        Some(role) => report.push(format!("The bot's highest role is {} (position {}).", role.name, role.position)),
+        None => report.push("The bot has no roles, it can't assign any roles.".to_string()),
+    }
+
+    if blocked.is_empty() {
+        report.push("All stored roles are within the bot's reach.".to_string());
+    } else {
+        report.push(format!("{} stored roles can't be restored:", blocked.len()));
+        for role in blocked.iter().take(MAX_LISTED_ROLES) {
+            report.push(format!("- {}", role));
+        }
+        if blocked.len() > MAX_LISTED_ROLES {
+            report.push(format!("- and {} more", blocked.len() - MAX_LISTED_ROLES));
+        }
+    }
+
+    if deleted > 0 {
+        report.push(format!("{} stored roles no longer exist and will be skipped.", deleted));
+    }
+
+    Ok(report.join("\n"))
+}
+
+// Lists stored roles which sit at or above the bot's highest role, along with
+// how often restoring them was recently refused.
+async fn unrestorable(handler: &Handler, http: &Http, server_id: GuildId) -> Result<String, Error> {
+    let guild_roles = guild_roles(http, server_id).await?;
+    let top_position = bot_roles(http, server_id, &guild_roles).await?.iter()
+        .map(|role| role.position)
+        .max()
+        .unwrap_or(0);
+
+    let failures = handler.restore_failures(server_id.get()).await?;
+    let mut roles: HashSet<u64> = handler.stored_guild_roles(server_id.get()).await?.into_iter().collect();
+    roles.extend(failures.keys());
+
+    let mut blocked: Vec<(&Role, u64, Option<u64>)> = roles.iter()
+        .filter_map(|role| guild_roles.get(&RoleId::new(*role)))
+        .filter(|role| !role.managed && role.position >= top_position)
+        .map(|role| match failures.get(&role.id.get()) {
+            Some((count, last)) => (role, *count, Some(*last)),
+            None => (role, 0, None),
+        })
+        .collect();
+    blocked.sort_by_key(|(role, count, _)| (std::cmp::Reverse(*count), std::cmp::Reverse(role.position)));
+
+    if blocked.is_empty() {
+        return Ok("All stored roles are below the bot's highest role.".to_string());
+    }
+
+    let mut report = vec![format!(
+        "{} stored roles are at or above the bot's highest role, move the bot's role above them to restore them:",
+        blocked.len(),
+    )];
+    for (role, count, last) in blocked.iter().take(MAX_LISTED_ROLES) {
+        match last {
+            Some(last) => report.push(format!("- {} ({}): {} failed restores, last <t:{}:R>", role.name, role.id.get(), count, last)),
+            None => report.push(format!("- {} ({})", role.name, role.id.get())),
+        }
+    }
+    if blocked.len() > MAX_LISTED_ROLES {
+        report.push(format!("- and {} more", blocked.len() - MAX_LISTED_ROLES));
+    }
+
+    Ok(report.join("\n"))
+}
+
+async fn guild_roles(http: &Http, server_id: GuildId) -> Result<HashMap<RoleId, Role>, Error> {
+    Ok(http.get_guild_roles(server_id).await?
+        .into_iter()
+        .map(|role| (role.id, role))
+        .collect())
+}
+
+async fn bot_roles<'a>(
+    http: &Http,
+    server_id: GuildId,
+    guild_roles: &'a HashMap<RoleId, Role>,
+) -> Result<Vec<&'a Role>, Error> {
+    let bot = http.get_current_user().await?;
+    Ok(http.get_member(server_id, bot.id).await?.roles.iter()
+        .filter_map(|role| guild_roles.get(role))
+        .collect())
+}
+
+async fn stats(handler: &Handler, server_id: GuildId) -> Result<String, Error> {
+    let stats = handler.guild_stats(server_id.get()).await?;
+
+    let mut report = vec![format!("{} members tracked.", stats.members)];
+    if let Some(first_seen) = stats.first_seen {
+        report.push(format!("Tracking since <t:{}:D>.", first_seen));
+    }
+    report.push(format!(
+        "{} members first seen in the last {} days.",
+        stats.recent_members,
+        RECENT_MEMBER_WINDOW.as_secs() / (24 * 60 * 60),
+    ));
+    if let Some(latency) = handler.restore_latency() {
+        report.push(format!(
+            "Restores across all servers take {}ms (p50), {}ms (p99) over the last {}.",
+            latency.p50.as_millis(),
+            latency.p99.as_millis(),
+            latency.samples,
+        ));
+    }
+
+    Ok(report.join("\n"))
+}
+
+// Sums a guild's hourly stats snapshots into a line for each day.
+async fn trends(handler: &Handler, server_id: GuildId, days: u64) -> Result<String, Error> {
+    let history = handler.stats_history(server_id.get(), days).await?;
+
+    if history.is_empty() {
+        return Ok("No stats have been recorded for this server yet, they're snapshotted hourly.".to_string());
+    }
+
+    let mut by_day: Vec<(u64, StatsSnapshot)> = vec![];
+    for snapshot in history {
+        let day = snapshot.time / SECONDS_PER_DAY;
+        match by_day.last_mut() {
+            Some((last_day, total)) if *last_day == day => {
+                // Members tracked is a level rather than a count, the day's
+                // last snapshot says where it ended up.
+                total.members = snapshot.members;
+                total.restored += snapshot.restored;
+                total.errors += snapshot.errors;
+            },
+            _ => by_day.push((day, snapshot)),
+        }
+    }
+
+    let mut report = vec![format!("Last {} days:", days)];
+    for (day, total) in &by_day {
+        report.push(format!(
+            "<t:{}:D>: {} members tracked, {} roles restored, {} errors",
+            day * SECONDS_PER_DAY,
+            total.members,
+            total.restored,
+            total.errors,
+        ));
+    }
+    report.push(format!(
+        "Total: {} roles restored, {} errors",
+        by_day.iter().map(|(_, total)| total.restored).sum::<u64>(),
+        by_day.iter().map(|(_, total)| total.errors).sum::<u64>(),
+    ));
+
+    Ok(report.join("\n"))
+}
+
+fn history_embed(server_id: GuildId, user_id: UserId, history: &MemberHistory) -> CreateEmbed {
+    let embed = CreateEmbed::new()
+        .title("Member history")
+        .description(format!("<@{}>", user_id.get()))
+        .field("First seen", history.first_seen.map_or("Unknown".to_string(), |time| format!("<t:{}:f>", time)), true)
+        .field("Last seen", format!("<t:{}:R>", history.last_seen), true)
+        .field("Rejoins", history.rejoins.to_string(), true)
+        .field("Nickname when last seen", history.nick.as_deref().unwrap_or("None stored"), true);
+
+    let embed = match history.grants.is_empty() {
+        true => embed,
+        false => embed.field("How roles were granted", grants_summary(&history.grants), false),
+    };
+
+    // The hash may be of an avatar the member has since removed, which the
+    // CDN may no longer have.
+    match &history.avatar {
+        Some(avatar) => embed.thumbnail(format!(
+            "https://cdn.discordapp.com/guilds/{}/users/{}/avatars/{}.png",
+            server_id.get(),
+            user_id.get(),
+            avatar,
+        )),
+        None => embed,
+    }
+}
+
+// One line for each role grant, as many as fit in an embed field.
+fn grants_summary(grants: &[RoleGrant]) -> String {
+    let mut lines: Vec<String> = vec![];
+    let mut length = 0;
+    for (index, grant) in grants.iter().enumerate() {
+        let line = match &grant.reason {
+            Some(reason) => format!("<@&{}> by <@{}> <t:{}:R>: {}", grant.role_id, grant.granted_by, grant.time, reason),
+            None => format!("<@&{}> by <@{}> <t:{}:R>", grant.role_id, grant.granted_by, grant.time),
+        };
+        // Leaves room for saying how many more there are.
+        if length + line.len() + 1 > EMBED_FIELD_LIMIT - 32 {
+            lines.push(format!("…and {} more", grants.len() - index));
+            break;
+        }
+        length += line.len() + 1;
+        lines.push(line);
+    }
+    lines.join("\n")
+}
+
+// Lists the members staff are holding restores back for and who held them.
+fn holds_report(holds: &[Hold]) -> String {
+    if holds.is_empty() {
+        return "Nobody is held in this server.".to_string();
+    }
+
+    let mut report = vec![format!("{} held members:", holds.len())];
+    for hold in holds.iter().take(MAX_LISTED_MEMBERS) {
+        report.push(format!("- <@{}>, held by <@{}> <t:{}:R>", hold.user_id, hold.held_by, hold.time));
+    }
+    if holds.len() > MAX_LISTED_MEMBERS {
+        report.push(format!("- and {} more", holds.len() - MAX_LISTED_MEMBERS));
+    }
+    report.join("\n")
+}
+
+// Reports which roles are persisted in a guild and whether restores happen.
+async fn status(handler: &Handler, server_id: GuildId) -> Result<String, Error> {
+    let (sticky_only, sticky) = handler.sticky_settings(server_id).await?;
+
+    let mut report = vec![match sticky_only {
+        true => "Mode: only sticky roles are persisted.".to_string(),
+        false => "Mode: all roles are persisted.".to_string(),
+    }];
+
+    if sticky.is_empty() {
+        report.push("No roles are sticky.".to_string());
+    } else {
+        report.push(format!("{} sticky roles:", sticky.len()));
+        for role in sticky.iter().take(MAX_LISTED_ROLES) {
+            report.push(format!("- <@&{}>", role));
+        }
+        if sticky.len() > MAX_LISTED_ROLES {
+            report.push(format!("- and {} more", sticky.len() - MAX_LISTED_ROLES));
+        }
+    }
+
+    if handler.is_dry_run(server_id.get()).await {
+        report.push("Dry run is on, restores are only logged.".to_string());
+    }
+
+    Ok(report.join("\n"))
+}
@@ -0,0 +1,5549 @@
+use std::fs;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::{Arc, OnceLock, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use rusqlite::{Connection, OptionalExtension, Result};
+use serenity::all::UnavailableGuild;
+
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+
+use futures::{FutureExt, StreamExt};
+
+use serenity::{async_trait, prelude::*};
+use serenity::gateway::{ChunkGuildFilter, ConnectionStage, ShardManager, ShardMessenger, ShardStageUpdateEvent};
+use serenity::builder::{CreateActionRow, CreateAllowedMentions, CreateButton, CreateMessage, EditMember};
+use serenity::model::application::{ButtonStyle, Interaction};
+use serenity::model::gateway::Ready;
+use serenity::model::id::{ChannelId, UserId, GuildId, RoleId};
+use serenity::http::Http;
+use serenity::model::guild::{Member, Guild, Role};
+use serenity::model::permissions::Permissions;
+use serenity::model::guild::audit_log::{Action, AuditLogEntry, Change, MemberAction};
+use serenity::model::event::{GuildMemberUpdateEvent, GuildMembersChunkEvent, ResumedEvent};
+
+use tokio::sync::mpsc;
+
+use serde::Deserialize;
+use serde::de::{Deserializer, Visitor};
+
+use dashmap::DashMap;
+
+pub mod clock;
+mod commands;
+mod queue;
+mod retry;
+mod role_queue;
+mod scheduler;
+mod storage;
+#[cfg(test)]
+mod tests;
+mod writer;
+
+use clock::Clock;
+use queue::{Work, WorkQueue};
+
+pub use queue::{Origin, Task};
+pub use retry::is_fatal_gateway_error;
+use role_queue::RoleQueue;
+use scheduler::Scheduler;
+use storage::{Extras, Storage};
+use writer::Writer;
+
+// What's kept of a member, built from serenity's member types.
+#[derive(Clone)]
+pub struct SimpleMember {
+    joined_at: Option<i64>,
+    user_id: u64,
+    server_id: u64,
+    roles: HashSet<u64>,
+    // Whether the member has yet to pass membership screening.
+    pending: bool,
+    bot: bool,
+    // Their nickname and server avatar hash, stored if store_profiles is on.
+    nick: Option<String>,
+    avatar: Option<String>,
+    // When their timeout ends, if they've been timed out.
+    timeout: Option<i64>,
+}
+
+impl From<&Member> for SimpleMember {
+    fn from(member: &Member) -> Self {
+        SimpleMember {
+            joined_at: member.joined_at.map(|time| time.unix_timestamp()),
+            user_id: member.user.id.get(),
+            server_id: member.guild_id.get(),
+            roles: member.roles.iter().cloned().map(|r| r.get()).collect(),
+            pending: member.pending,
+            bot: member.user.bot,
+            nick: member.nick.clone(),
+            avatar: member.avatar.map(|hash| hash.to_string()),
+            timeout: member.communication_disabled_until.map(|time| time.unix_timestamp()),
+        }
+    }
+}
+
+impl From<Member> for SimpleMember {
+    fn from(member: Member) -> Self {
+        Self::from(&member)
+    }
+}
+
+impl From<&GuildMemberUpdateEvent> for SimpleMember {
+    fn from(member: &GuildMemberUpdateEvent) -> Self {
+        SimpleMember {
+            joined_at: Some(member.joined_at.unix_timestamp()),
+            user_id: member.user.id.get(),
+            server_id: member.guild_id.get(),
+            roles: member.roles.iter().cloned().map(|r| r.get()).collect(),
+            pending: member.pending,
+            bot: member.user.bot,
+            nick: member.nick.clone(),
+            avatar: member.avatar.map(|hash| hash.to_string()),
+            timeout: member.communication_disabled_until.map(|time| time.unix_timestamp()),
+        }
+    }
+}
+
+impl From<GuildMemberUpdateEvent> for SimpleMember {
+    fn from(member: GuildMemberUpdateEvent) -> Self {
+        Self::from(&member)
+    }
+}
+
+impl SimpleMember {
+    // Which of Handler's member locks guards this member's data.
+    fn lock_key(&self) -> (UserId, GuildId) {
+        (UserId::new(self.user_id), GuildId::new(self.server_id))
+    }
+}
+
+// Where the binary keeps its database, anything embedding the library picks
+// its own.
+pub const DATABASE_PATH: &str = "data.db";
+
+// How long a connection waits for another to finish with the database before
+// giving up, which only checkpoints should take long enough to matter.
+const DATABASE_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Config files looked for, in order. TOML is an option for anyone wanting 
+// comments in their config.
+const CONFIG_PATHS: [&str; 2] = ["config.toml", "config.json"];
+
+// What staff decided about restoring a member's roles.
+enum Approval {
+    Pending,
+    Approved,
+    Denied,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Verdict {
+    Restore,
+    Everyone,
+    Deleted,
+    Booster,
+    Linked,
+    Managed,
+    SystemManaged,
+    Excluded,
+    AlreadyHeld,
+    SelfAssignable,
+    CoolingDown,
+    ManuallyRemoved,
+    Privileged,
+    Sensitive,
+    AboveBot,
+    RoleLimit,
+    OverCap,
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Verdict::Restore => formatter.write_str("would be added"),
+            Verdict::Everyone => formatter.write_str("the @everyone role"),
+            Verdict::Deleted => formatter.write_str("no longer exists"),
+            Verdict::Booster => formatter.write_str("the server booster role, cannot restore"),
+            Verdict::Linked => formatter.write_str("a linked or purchasable role, cannot restore"),
+            Verdict::Managed => formatter.write_str("managed, cannot restore"),
+            Verdict::SystemManaged => formatter.write_str("given out by Discord's own automation, left to it"),
+            Verdict::Excluded => formatter.write_str("excluded by config"),
+            Verdict::AlreadyHeld => formatter.write_str("already held"),
+            Verdict::SelfAssignable => formatter.write_str("self-assignable, left for the member"),
+            Verdict::CoolingDown => formatter.write_str("recently removed, cooling down"),
+            Verdict::ManuallyRemoved => formatter.write_str("removed by a moderator"),
+            Verdict::Privileged => formatter.write_str("blocked: privileged"),
+            Verdict::Sensitive => formatter.write_str("has sensitive permissions and isn't allowlisted"),
+            Verdict::AboveBot => formatter.write_str("above the bot's highest role, cannot restore"),
+            Verdict::RoleLimit => formatter.write_str("over the limit of roles a member can have"),
+            Verdict::OverCap => formatter.write_str("over the cap on roles restored automatically"),
+        }
+    }
+}
+
+impl Verdict {
+    // How a role left out of a restore is counted in its summary.
+    fn skip_reason(self) -> Option<&'static str> {
+        match self {
+            Verdict::Restore => None,
+            Verdict::AlreadyHeld => Some("already had"),
+            Verdict::Everyone
+            | Verdict::Excluded
+            | Verdict::SelfAssignable
+            | Verdict::CoolingDown
+            | Verdict::ManuallyRemoved
+            | Verdict::Sensitive => Some("excluded"),
+            Verdict::Privileged => Some("privileged"),
+            Verdict::Booster | Verdict::Managed | Verdict::SystemManaged => Some("managed"),
+            Verdict::Linked => Some("linked/purchasable"),
+            Verdict::AboveBot => Some("hierarchy"),
+            Verdict::Deleted => Some("deleted"),
+            Verdict::RoleLimit => Some("role limit"),
+            Verdict::OverCap => Some("over cap"),
+        }
+    }
+}
+
+// What came of restoring a member's roles.
+#[derive(Default)]
+struct RestoreSummary {
+    user_id: u64,
+    server_id: u64,
+    // Roles the member could have had restored.
+    planned: usize,
+    restored: Vec<u64>,
+    // How many roles were left out, by why.
+    skipped: BTreeMap<&'static str, usize>,
+    // Roles which couldn't be added, with why.
+    failed: Vec<(u64, String)>,
+    // How many of the failures were still happening after retrying.
+    failed_after_retries: usize,
+    // Whether the roles were only worked out, not actually restored.
+    dry_run: bool,
+    // Whether the member turned out to have left, cutting the restore short.
+    member_left: bool,
+    // Whether the bot couldn't add roles in the guild, so the restore is 
+    // left for when it can.
+    held_back: bool,
+    // Whether the member's roles were replaced with the stored ones, rather 
+    // than the stored ones added to what they had.
+    replaced: bool,
+    // Roles taken away by replacing.
+    removed: Vec<u64>,
+}
+
+impl fmt::Display for RestoreSummary {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            formatter,
+            "{}{}Restore for member {} in server {}: {} roles stored, {} restored",
+            if self.dry_run { "[DRY RUN] " } else { "" },
+            if self.replaced { "[REPLACE] " } else { "" },
+            self.user_id,
+            self.server_id,
+            self.planned,
+            self.restored.len(),
+        )?;
+
+        if self.replaced {
+            write!(formatter, ", {} removed {:?}", self.removed.len(), self.removed)?;
+        }
+
+        if !self.skipped.is_empty() {
+            let skipped: Vec<String> = self.skipped.iter()
+                .map(|(reason, count)| format!("{} {}", count, reason))
+                .collect();
+            write!(formatter, ", skipped {}", skipped.join(", "))?;
+        }
+
+        if !self.failed.is_empty() {
+            write!(
+                formatter,
+                ", {} failed ({} after retrying): {:?}",
+                self.failed.len(),
+                self.failed_after_retries,
+                self.failed,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Database(rusqlite::Error),
+    Discord(serenity::Error),
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(error: rusqlite::Error) -> Self {
+        Error::Database(error)
+    }
+}
+
+impl From<serenity::Error> for Error {
+    fn from(error: serenity::Error) -> Self {
+        Error::Discord(error)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Database(error) => write!(formatter, "database error: {}", error),
+            Error::Discord(error) => write!(formatter, "discord error: {}", error),
+        }
+    }
+}
+
+// Held while changing a member's data outside their guild's worker. Each is
+// removed once nothing holds it or waits for it.
+type MemberLocks = DashMap<(UserId, GuildId), Arc<Mutex<()>>>;
+
+// How long to wait for the next member chunk before giving up on the gateway 
+// and fetching the remaining members over REST instead.
+const CHUNK_TIMEOUT: Duration = Duration::from_secs(30);
+
+// The shortest time between two resyncs of a guild caused by reconnects, so 
+// that a flappy connection doesn't keep triggering full syncs.
+const RECONNECT_RESYNC_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+// How long a full sync is trusted for before a guild becoming available again
+// is considered worth syncing.
+const RECENT_SYNC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// How many role additions can be in flight at once, across all guilds.
+const ROLE_ADD_WORKERS: usize = 4;
+
+// How much work each guild queues before member updates start being dropped.
+const QUEUE_CAPACITY: usize = 4096;
+
+// How often expired entries are cleared out of the member lock map, unless
+// configured otherwise.
+const LOCK_COMPACTION_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// How often shard and job statuses are logged and checked, unless configured
+// otherwise.
+const SHARD_MONITOR_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// How often guilds whose sync failed are tried again, unless configured
+// otherwise.
+const SYNC_RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// How long a shard can be disconnected before a warning is logged.
+const SHARD_DOWN_WARNING: Duration = Duration::from_secs(5 * 60);
+
+// The window joins are counted over to detect raids.
+const RAID_WINDOW: Duration = Duration::from_secs(60);
+
+// How often raided guilds are checked for having calmed down.
+const RAID_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+// How recently members must have first been seen to count as new in stats.
+const RECENT_MEMBER_WINDOW: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+// Roles with any of these permissions are only restored if allowlisted, in 
+// case the stored roles are stale or have been tampered with.
+const SENSITIVE_PERMISSIONS: Permissions = Permissions::ADMINISTRATOR
+    .union(Permissions::MANAGE_GUILD)
+    .union(Permissions::MANAGE_ROLES)
+    .union(Permissions::MANAGE_CHANNELS)
+    .union(Permissions::MANAGE_WEBHOOKS)
+    .union(Permissions::BAN_MEMBERS)
+    .union(Permissions::KICK_MEMBERS)
+    .union(Permissions::MODERATE_MEMBERS);
+
+// The most roles Discord lets a member have.
+const MAX_MEMBER_ROLES: usize = 250;
+
+// How many times adding a role is tried before giving up on it.
+const ROLE_ADD_ATTEMPTS: u32 = 3;
+
+// How long retrying role additions can hold up a single member's restore.
+const ROLE_RETRY_BUDGET: Duration = Duration::from_secs(30);
+
+// How often each guild's log gets a warning about roles above the bot.
+const HIERARCHY_WARNING_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// How long refused restores of a role are reported for.
+const RESTORE_FAILURE_WINDOW: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+// How far before a member was last seen a kick can be recorded and still be 
+// counted as the reason they left.
+const KICK_WINDOW: Duration = Duration::from_secs(60);
+
+// How long after restoring a member another rejoin of theirs is taken to be 
+// the same one, seen again through a second event.
+const RESTORE_DEDUPE_WINDOW: Duration = Duration::from_secs(30);
+
+// How long restores in a guild are held back after failing for lack of 
+// permissions before one is tried again to see if that's been fixed.
+const PERMISSION_RECHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+// How often guilds whose grace period after removing the bot has run out are
+// checked for.
+const FORGET_CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// The most members of one guild whose last_seen rows are kept in memory.
+const SEEN_CACHE_GUILD_LIMIT: usize = 100_000;
+
+// How many of the slowest guilds are named after the initial sync.
+const SLOWEST_SYNCS_LOGGED: usize = 5;
+
+// How often held back member updates are checked for being due.
+const DEBOUNCE_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+// How far back the audit log is searched for who gave a member a role.
+const GRANT_LOOKBACK: Duration = Duration::from_secs(60 * 60);
+
+// How many of the latest role updates in the audit log are searched.
+const GRANT_AUDIT_LOG_ENTRIES: u8 = 100;
+
+// How often a member whose roles haven't changed has when they were last 
+// seen written anyway.
+const LAST_SEEN_REFRESH_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+// How often each guild's stats are stored for /rolepersist trends.
+const STATS_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// How much of the database is copied at a time when backing it up, with a 
+// pause in between for anything else wanting to write.
+const BACKUP_PAGES_PER_STEP: std::os::raw::c_int = 256;
+const BACKUP_STEP_PAUSE: Duration = Duration::from_millis(10);
+
+// How often to check whether the workers have finished everything queued.
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// The most members Discord will return from a single member list request.
+const MEMBER_PAGE_SIZE: u64 = 1000;
+
+// The longest audit log reason Discord accepts, in bytes.
+const MAX_AUDIT_REASON: usize = 512;
+
+// How many of the latest restore durations latency percentiles are taken from.
+const RESTORE_LATENCY_SAMPLES: usize = 1000;
+
+struct ChunkSync {
+    nonce: String,
+    // What the members received are observed as.
+    origin: Origin,
+    received: u32,
+    expected: Option<u32>,
+    members: usize,
+    progress: mpsc::UnboundedSender<()>,
+}
+
+impl ChunkSync {
+    fn is_complete(&self) -> bool {
+        matches!(self.expected, Some(expected) if self.received >= expected)
+    }
+}
+
+struct ShardStatus {
+    stage: ConnectionStage,
+    since: Instant,
+    latency: Option<Duration>,
+    guilds: usize,
+    warned: bool,
+}
+
+// Running totals since the bot started.
+pub struct GuildStats {
+    // Members ever seen in the guild.
+    pub members: u64,
+    // When the earliest of them was first seen.
+    pub first_seen: Option<u64>,
+    // Members first seen within RECENT_MEMBER_WINDOW.
+    pub recent_members: u64,
+}
+
+pub struct MemberHistory {
+    pub last_seen: u64,
+    pub first_seen: Option<u64>,
+    // How many times they've been seen rejoining.
+    pub rejoins: u64,
+    // Their nickname and server avatar hash when last seen, if profiles are
+    // stored.
+    pub nick: Option<String>,
+    pub avatar: Option<String>,
+    // How they got the roles they have stored, where that was found.
+    pub grants: Vec<RoleGrant>,
+}
+
+// Who gave a member a role and why, as best the audit log around when the
+// role was stored could tell.
+pub struct RoleGrant {
+    pub role_id: u64,
+    pub granted_by: u64,
+    pub reason: Option<String>,
+    pub time: u64,
+}
+
+// A member staff have stopped roles being restored to while they look into
+// them, and who did and when.
+pub struct Hold {
+    pub user_id: u64,
+    pub held_by: u64,
+    pub time: u64,
+}
+
+// What happened in a guild since its stats were last snapshotted.
+#[derive(Default, Clone, Copy)]
+struct GuildCounts {
+    restored: u64,
+    errors: u64,
+}
+
+// A guild's stats at one point in time, with what happened since the
+// snapshot before.
+pub struct StatsSnapshot {
+    pub time: u64,
+    // Members tracked at the time.
+    pub members: u64,
+    pub restored: u64,
+    pub errors: u64,
+}
+
+#[derive(Default)]
+struct Stats {
+    restored: AtomicU64,
+    errors: AtomicU64,
+    // Counted by guild too, until the next snapshot.
+    guilds: DashMap<u64, GuildCounts>,
+    // Members saved with something changed, and without.
+    saves: AtomicU64,
+    unchanged_saves: AtomicU64,
+    // How long the most recent restores took, from observing the member to
+    // their roles being added.
+    restore_latency: std::sync::Mutex<VecDeque<Duration>>,
+}
+
+impl Stats {
+    fn record_restored(&self, server_id: u64, roles: u64) {
+        self.restored.fetch_add(roles, Ordering::Relaxed);
+        self.guilds.entry(server_id).or_default().restored += roles;
+    }
+
+    fn record_error(&self, server_id: u64) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        self.guilds.entry(server_id).or_default().errors += 1;
+    }
+
+    // What each guild has done since this was last called.
+    fn take_guild_counts(&self) -> HashMap<u64, GuildCounts> {
+        let servers: Vec<u64> = self.guilds.iter().map(|entry| *entry.key()).collect();
+        servers.into_iter()
+            .filter_map(|server_id| self.guilds.remove(&server_id))
+            .collect()
+    }
+
+    fn record_restore_latency(&self, latency: Duration) {
+        let mut samples = self.restore_latency.lock().unwrap();
+        if samples.len() >= RESTORE_LATENCY_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(latency);
+    }
+
+    // The given percentiles of recent restore durations, None if nothing has
+    // been restored yet.
+    fn restore_latency_percentiles<const N: usize>(&self, percentiles: [usize; N]) -> Option<[Duration; N]> {
+        let mut samples: Vec<Duration> = self.restore_latency.lock().unwrap().iter().copied().collect();
+        if samples.is_empty() {
+            return None;
+        }
+
+        samples.sort_unstable();
+        Some(percentiles.map(|percentile| {
+            let index = (samples.len() * percentile).div_ceil(100).saturating_sub(1);
+            samples[index.min(samples.len() - 1)]
+        }))
+    }
+}
+
+// Percentiles of how long recent restores took.
+pub struct RestoreLatency {
+    pub samples: usize,
+    pub p50: Duration,
+    pub p99: Duration,
+}
+
+// A member's row in last_seen, as far as deciding whether they rejoined goes.
+#[derive(Clone, Copy)]
+struct Seen {
+    time: i64,
+    pending_restore: bool,
+}
+
+// What's in last_seen for members looked up or saved, by guild, None for
+// members not in it. Only updated by the database writer, once what it wrote
+// is committed.
+#[derive(Default)]
+struct SeenCache(std::sync::Mutex<HashMap<u64, HashMap<u64, Option<Seen>>>>);
+
+impl SeenCache {
+    fn get(&self, member: &SimpleMember) -> Option<Option<Seen>> {
+        self.0.lock().unwrap()
+            .get(&member.server_id)
+            .and_then(|guild| guild.get(&member.user_id).copied())
+    }
+
+    fn insert(&self, member: &SimpleMember, seen: Option<Seen>) {
+        let mut cache = self.0.lock().unwrap();
+        let guild = cache.entry(member.server_id).or_default();
+        // Bounds the memory used by very large guilds, which are read back
+        // from the database as their members are seen again.
+        if guild.len() >= SEEN_CACHE_GUILD_LIMIT {
+            guild.clear();
+        }
+        guild.insert(member.user_id, seen);
+    }
+
+    fn forget_member(&self, user_id: u64, server_id: u64) {
+        if let Some(guild) = self.0.lock().unwrap().get_mut(&server_id) {
+            guild.insert(user_id, None);
+        }
+    }
+
+    fn forget_guild(&self, server_id: u64) {
+        self.0.lock().unwrap().remove(&server_id);
+    }
+}
+
+// What saving a member came to.
+enum Save {
+    // They have no roles and weren't stored before.
+    Skipped,
+    // Nothing about them changed since they were last stored.
+    Unchanged,
+    // Their row was written, with the roles stored which weren't before if
+    // they were stored at all.
+    Written(Vec<u64>),
+}
+
+// A member's nickname and server avatar hash.
+type Profile = (Option<String>, Option<String>);
+
+// A member update waiting for any more to come in before it's stored.
+struct DebouncedUpdate {
+    context: Context,
+    member: SimpleMember,
+    // When the first update being held back for the member came in.
+    first: Instant,
+    last: Instant,
+    // Whether it's been queued to be stored, it's left here until then.
+    flushed: bool,
+}
+
+#[derive(Default)]
+struct Raid {
+    joins: VecDeque<Instant>,
+    // When raid mode ends, if the guild is in raid mode.
+    until: Option<Instant>,
+    // Restores waiting for raid mode to end, at most max_deferred of them.
+    deferred: Vec<Work>,
+}
+
+pub struct Handler {
+    // Only read from, everything written goes through the writer. A blocking
+    // mutex, as its guard can't be held across an await (such as a request to
+    // Discord) without the handler's futures no longer being Send.
+    data: std::sync::Mutex<Connection>,
+    writer: Writer,
+    config: Config,
+    member_locks: MemberLocks,
+    chunk_syncs: Mutex<HashMap<GuildId, ChunkSync>>,
+    chunk_nonce: AtomicU64,
+    guilds: Mutex<HashSet<GuildId>>,
+    shard_count: AtomicU32,
+    reconnect_syncs: Mutex<HashMap<GuildId, Instant>>,
+    shards: Mutex<HashMap<u32, ShardMessenger>>,
+    shards_ready: AtomicBool,
+    initial_sync_done: AtomicBool,
+    // Set once the initial sync is waiting on guilds which are unavailable.
+    initial_sync_waiting: AtomicBool,
+    // Guilds left out of the initial sync for being unavailable, synced once
+    // they're back.
+    late_guilds: Mutex<HashSet<GuildId>>,
+    // Each guild's work is processed in order by its own worker, so a member's
+    // events can't overtake one another.
+    queues: DashMap<GuildId, Arc<WorkQueue>>,
+    workers: std::sync::Mutex<Vec<tokio::task::JoinHandle<()>>>,
+    queues_closed: AtomicBool,
+    // The handler itself, for spawning guild workers.
+    this: OnceLock<Weak<Handler>>,
+    // Queues for work which arrived before start_workers, left for it to 
+    // start their workers.
+    unstarted_queues: std::sync::Mutex<Vec<(GuildId, Arc<WorkQueue>)>>,
+    shard_status: Mutex<HashMap<u32, ShardStatus>>,
+    // Replaced each time the client is rebuilt after losing the gateway.
+    shard_manager: std::sync::RwLock<Option<Arc<ShardManager>>>,
+    raids: Mutex<HashMap<GuildId, Raid>>,
+    stats: Stats,
+    scheduler: Arc<Scheduler>,
+    // Guilds whose sync failed for reasons which may have since passed.
+    failed_syncs: Mutex<HashMap<GuildId, Context>>,
+    // Kept apart from the rest of the config so they can be reloaded.
+    restrict: Arc<std::sync::RwLock<Option<Restriction>>>,
+    restrict_restores: std::sync::RwLock<Option<Restriction>>,
+    exclusions: std::sync::RwLock<Exclusions>,
+    // Restores found by syncs, waiting to be carried out.
+    restore_backlog: Mutex<Vec<Work>>,
+    // The latest update for members whose updates are being debounced.
+    debounced_updates: Mutex<HashMap<(UserId, GuildId), DebouncedUpdate>>,
+    // Set once the members found by the initial sync have all been observed.
+    initial_sync_settled: AtomicBool,
+    clock: Arc<dyn Clock>,
+    role_queue: RoleQueue,
+    // When each guild was last warned about roles above the bot.
+    hierarchy_warnings: Mutex<HashMap<GuildId, Instant>>,
+    // Guilds where restoring failed for lack of permissions, with when it 
+    // was last tried.
+    permission_failures: Mutex<HashMap<GuildId, Instant>>,
+    // Guilds being resynced by staff.
+    resyncs: Mutex<HashSet<GuildId>>,
+    // Bounds how many guilds are synced at once to sync_concurrency.
+    sync_permits: tokio::sync::Semaphore,
+    seen_cache: Arc<SeenCache>,
+    // Guilds Discord has said are unavailable through an outage, with the
+    // restores cut short by it to try again once they're back.
+    unavailable_guilds: Mutex<HashMap<GuildId, Vec<Work>>>,
+    // Members whose restore is waiting out the guild's restore delay.
+    delayed_restores: Mutex<HashSet<(UserId, GuildId)>>,
+    // When members were last restored, to catch events for the same rejoin.
+    recent_restores: Mutex<HashMap<(u64, u64), u64>>,
+    storage: Arc<dyn Storage>,
+    database_path: PathBuf,
+}
+
+impl Handler {
+    pub fn new(mut config: Config, database_path: impl AsRef<Path>, clock: Arc<dyn Clock>) -> Result<Self> {
+        let database_path = database_path.as_ref().to_path_buf();
+        let mut connection = Connection::open(&database_path)?;
+
+        let storage: Arc<dyn Storage> = storage::open(config.storage).into();
+        storage.create(&connection)?;
+        let migrated = storage::migrate(&mut connection, config.storage)?;
+        if migrated > 0 {
+            println!("Moved the stored roles of {} members to the configured storage layout", migrated);
+        }
+
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS last_seen(
+                user_id NUMBER,
+                server_id NUMBER,
+                time INTEGER,
+                first_seen INTEGER,
+                pending_restore INTEGER,
+                rejoins INTEGER,
+                last_rejoin INTEGER,
+                nick TEXT,
+                avatar TEXT,
+                PRIMARY KEY(user_id, server_id)
+            )", 
+            []
+        )?;
+
+        // Databases from before first_seen was tracked only know when members
+        // were last seen, which is the best guess there is.
+        if !has_column(&connection, "last_seen", "first_seen")? {
+            connection.execute_batch(
+                "ALTER TABLE last_seen ADD COLUMN first_seen INTEGER;
+                UPDATE last_seen SET first_seen=time;"
+            )?;
+        }
+
+        if !has_column(&connection, "last_seen", "pending_restore")? {
+            connection.execute("ALTER TABLE last_seen ADD COLUMN pending_restore INTEGER", [])?;
+        }
+
+        if !has_column(&connection, "last_seen", "rejoins")? {
+            connection.execute_batch(
+                "ALTER TABLE last_seen ADD COLUMN rejoins INTEGER;
+                ALTER TABLE last_seen ADD COLUMN last_rejoin INTEGER;"
+            )?;
+        }
+
+        if !has_column(&connection, "last_seen", "nick")? {
+            connection.execute_batch(
+                "ALTER TABLE last_seen ADD COLUMN nick TEXT;
+                ALTER TABLE last_seen ADD COLUMN avatar TEXT;"
+            )?;
+        }
+
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS role_removals(
+                user_id NUMBER,
+                server_id NUMBER,
+                role_id NUMBER,
+                time INTEGER,
+                PRIMARY KEY(user_id, server_id, role_id)
+            )", 
+            []
+        )?;
+
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS manual_removals(
+                user_id NUMBER,
+                server_id NUMBER,
+                role_id NUMBER,
+                time INTEGER,
+                PRIMARY KEY(user_id, server_id, role_id)
+            )", 
+            []
+        )?;
+
+        // Roles left out of a restore by max_auto_restore_roles, kept apart 
+        // from stored roles so saving the member doesn't lose them.
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS capped_roles(
+                user_id NUMBER,
+                server_id NUMBER,
+                role_id NUMBER,
+                PRIMARY KEY(user_id, server_id, role_id)
+            )", 
+            []
+        )?;
+
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS role_grants(
+                user_id NUMBER,
+                server_id NUMBER,
+                role_id NUMBER,
+                granted_by NUMBER,
+                reason TEXT,
+                time INTEGER,
+                PRIMARY KEY(user_id, server_id, role_id)
+            )",
+            []
+        )?;
+
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS holds(
+                user_id NUMBER,
+                server_id NUMBER,
+                held_by NUMBER,
+                time INTEGER,
+                PRIMARY KEY(user_id, server_id)
+            )",
+            []
+        )?;
+
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS kicks(
+                user_id NUMBER,
+                server_id NUMBER,
+                time INTEGER,
+                PRIMARY KEY(user_id, server_id)
+            )", 
+            []
+        )?;
+
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS restore_failures(
+                server_id NUMBER,
+                role_id NUMBER,
+                failures INTEGER,
+                last_failure INTEGER,
+                PRIMARY KEY(server_id, role_id)
+            )", 
+            []
+        )?;
+
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS guild_settings(
+                server_id NUMBER PRIMARY KEY,
+                last_sync INTEGER,
+                dry_run INTEGER,
+                sticky_only INTEGER,
+                forget_at INTEGER
+            )", 
+            []
+        )?;
+
+        if !has_column(&connection, "guild_settings", "dry_run")? {
+            connection.execute("ALTER TABLE guild_settings ADD COLUMN dry_run INTEGER", [])?;
+        }
+
+        if !has_column(&connection, "guild_settings", "sticky_only")? {
+            connection.execute("ALTER TABLE guild_settings ADD COLUMN sticky_only INTEGER", [])?;
+        }
+
+        if !has_column(&connection, "guild_settings", "forget_at")? {
+            connection.execute("ALTER TABLE guild_settings ADD COLUMN forget_at INTEGER", [])?;
+        }
+
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS pending_approvals(
+                user_id NUMBER,
+                server_id NUMBER,
+                requested INTEGER,
+                approved INTEGER,
+                PRIMARY KEY(user_id, server_id)
+            )", 
+            []
+        )?;
+
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS stats_history(
+                server_id NUMBER,
+                time INTEGER,
+                members INTEGER,
+                restored INTEGER,
+                errors INTEGER
+            )",
+            []
+        )?;
+
+        connection.execute(
+            "CREATE INDEX IF NOT EXISTS stats_history_time ON stats_history(server_id, time)",
+            []
+        )?;
+
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS sticky_roles(
+                server_id NUMBER,
+                role_id NUMBER,
+                PRIMARY KEY(server_id, role_id)
+            )", 
+            []
+        )?;
+
+        // Lets this connection read while the writer's is writing.
+        connection.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
+        connection.busy_timeout(DATABASE_BUSY_TIMEOUT)?;
+        let write_connection = Connection::open(&database_path)?;
+        write_connection.busy_timeout(DATABASE_BUSY_TIMEOUT)?;
+
+        let sync_permits = tokio::sync::Semaphore::new(config.sync_concurrency.max(1));
+        Ok(Self {
+            data: std::sync::Mutex::new(connection),
+            writer: Writer::start(write_connection),
+            restrict: Arc::new(std::sync::RwLock::new(config.restrict.take())),
+            restrict_restores: std::sync::RwLock::new(config.restrict_restores.take()),
+            exclusions: std::sync::RwLock::new(Exclusions::take(&mut config)),
+            role_queue: RoleQueue::new(config.role_adds_per_second),
+            config,
+            member_locks: DashMap::new(),
+            chunk_syncs: Mutex::new(HashMap::new()),
+            chunk_nonce: AtomicU64::new(0),
+            guilds: Mutex::new(HashSet::new()),
+            shard_count: AtomicU32::new(1),
+            reconnect_syncs: Mutex::new(HashMap::new()),
+            shards: Mutex::new(HashMap::new()),
+            shards_ready: AtomicBool::new(false),
+            initial_sync_done: AtomicBool::new(false),
+            initial_sync_waiting: AtomicBool::new(false),
+            late_guilds: Mutex::new(HashSet::new()),
+            queues: DashMap::new(),
+            workers: std::sync::Mutex::new(vec![]),
+            queues_closed: AtomicBool::new(false),
+            this: OnceLock::new(),
+            unstarted_queues: std::sync::Mutex::new(vec![]),
+            shard_status: Mutex::new(HashMap::new()),
+            shard_manager: std::sync::RwLock::new(None),
+            raids: Mutex::new(HashMap::new()),
+            stats: Stats::default(),
+            scheduler: Arc::new(Scheduler::new()),
+            failed_syncs: Mutex::new(HashMap::new()),
+            restore_backlog: Mutex::new(vec![]),
+            debounced_updates: Mutex::new(HashMap::new()),
+            initial_sync_settled: AtomicBool::new(false),
+            clock,
+            hierarchy_warnings: Mutex::new(HashMap::new()),
+            permission_failures: Mutex::new(HashMap::new()),
+            unavailable_guilds: Mutex::new(HashMap::new()),
+            resyncs: Mutex::new(HashSet::new()),
+            sync_permits,
+            seen_cache: Arc::new(SeenCache::default()),
+            delayed_restores: Mutex::new(HashSet::new()),
+            recent_restores: Mutex::new(HashMap::new()),
+            storage,
+            database_path,
+        })
+    }
+
+    pub async fn save_member(&self, member: &SimpleMember) -> Result<()> {
+        self.do_locked(member.lock_key(), || self.write_member(member)).await.map(|_| ())
+    }
+
+    // Saves what's storable of an observed member, looking up how they got
+    // any roles new since they were last saved if grant reasons are recorded
+    // and asked for. Only the last GRANT_LOOKBACK of the audit log is searched,
+    // so that's left out for saves which mostly turn up older roles.
+    async fn save_observed(&self, context: &Context, member: &SimpleMember, find_grants: bool) -> Result<()> {
+        let member = self.storable(context, member);
+        let added = self.write_member(&member).await?;
+        if find_grants && self.config.record_grant_reasons && !added.is_empty() {
+            self.record_grants(context, &member, &added).await?;
+        }
+        Ok(())
+    }
+
+    // Saves a member, returning the roles stored for them which weren't 
+    // before. A member's first save returns none, what they came with is
+    // rarely theirs recently enough to have a grant found for it.
+    async fn write_member(&self, member: &SimpleMember) -> Result<Vec<u64>> {
+        let member = member.clone();
+        let storage = self.storage.clone();
+        let seen_cache = self.seen_cache.clone();
+        let now = self.clock.now();
+        let skip_roleless_members = self.config.skip_roleless_members;
+        let store_profiles = self.config.store_profiles;
+
+        let save = self.writer.write(move |connection| {
+            // Members already stored keep being tracked after losing their roles,
+            // both so stale roles aren't restored and so rejoins are still noticed.
+            let seen: Option<(i64, bool, Profile)> = connection.query_row(
+                "SELECT time, pending_restore IS NOT NULL, nick, avatar FROM last_seen
+                WHERE user_id=?1 AND server_id=?2",
+                [member.user_id, member.server_id],
+                |row| Ok((row.get(0)?, row.get(1)?, (row.get(2)?, row.get(3)?))),
+            ).optional()?;
+
+            let first = seen.is_none();
+            let roleless = member.roles.iter().all(|role| *role == member.server_id);
+            if skip_roleless_members && roleless && first {
+                return Ok(Save::Skipped);
+            }
+
+            let roles = Self::storable_roles(connection, &member)?;
+            let profile = match store_profiles {
+                true => (member.nick.clone(), member.avatar.clone()),
+                false => (None, None),
+            };
+            let extras = Extras { nick: profile.0.clone(), timeout: member.timeout };
+
+            // Most updates are to nicknames, avatars, timeouts and the like, which
+            // leave nothing to store but that the member was seen, and that only
+            // every LAST_SEEN_REFRESH_INTERVAL. A rejoin or a restore still to 
+            // happen always gets written.
+            if let Some((time, false, stored_profile)) = seen {
+                let rejoined = member.joined_at.is_some_and(|joined_at| time < joined_at);
+                let stored: HashSet<u64> = storage.roles(connection, member.user_id, member.server_id)?
+                    .into_iter()
+                    .collect();
+                let stored_extras = storage.extras(connection, member.user_id, member.server_id)?;
+                let extras_changed = stored_extras.is_some_and(|stored| stored != extras);
+                if !rejoined && stored == roles && stored_profile == profile && !extras_changed {
+                    if now.saturating_sub(time as u64) >= LAST_SEEN_REFRESH_INTERVAL.as_secs() {
+                        connection.execute(
+                            "UPDATE last_seen SET time=?3 WHERE user_id=?1 AND server_id=?2",
+                            [member.user_id, member.server_id, now],
+                        )?;
+                        seen_cache.insert(&member, Some(Seen { time: now as i64, pending_restore: false }));
+                    }
+                    return Ok(Save::Unchanged);
+                }
+            }
+
+            let transaction = connection.transaction()?;
+
+            transaction.execute(
+                "INSERT INTO last_seen (user_id, server_id, time, first_seen, nick, avatar) VALUES (?1, ?2, ?3, ?3, ?4, ?5)
+                ON CONFLICT(user_id, server_id) DO UPDATE SET
+                    time=excluded.time, pending_restore=NULL, nick=excluded.nick, avatar=excluded.avatar",
+                rusqlite::params![member.user_id, member.server_id, now, profile.0, profile.1],
+            )?;
+
+            let added = Self::store_roles(storage.as_ref(), &transaction, &member, &roles)?;
+            storage.set_extras(&transaction, member.user_id, member.server_id, &extras)?;
+
+            transaction.commit()?;
+            seen_cache.insert(&member, Some(Seen { time: now as i64, pending_restore: false }));
+            Ok(Save::Written(if first { vec![] } else { added }))
+        }).await?;
+
+        Ok(match save {
+            Save::Skipped => vec![],
+            Save::Unchanged => {
+                self.stats.unchanged_saves.fetch_add(1, Ordering::Relaxed);
+                vec![]
+            },
+            Save::Written(added) => {
+                self.stats.saves.fetch_add(1, Ordering::Relaxed);
+                added
+            },
+        })
+    }
+
+    // Records who gave a member each of the given roles and why, from recent
+    // role updates in the audit log. Best effort: roles the bot can't find an
+    // entry for, or any if it lacks View Audit Log, have no grant recorded.
+    async fn record_grants(&self, context: &Context, member: &SimpleMember, roles: &[u64]) -> Result<()> {
+        let server_id = GuildId::new(member.server_id);
+        let logs = server_id.audit_logs(
+            &context.http,
+            Some(Action::Member(MemberAction::RoleUpdate)),
+            None,
+            None,
+            Some(GRANT_AUDIT_LOG_ENTRIES),
+        ).await;
+        let entries = match logs {
+            Ok(logs) => logs.entries,
+            Err(error) => {
+                println!(
+                    "Unable to read audit log for grants to member {} in server {}: {}",
+                    member.user_id,
+                    member.server_id,
+                    error,
+                );
+                return Ok(());
+            },
+        };
+
+        // Entries come newest first, so the first adding a role is the grant.
+        let since = self.clock.now().saturating_sub(GRANT_LOOKBACK.as_secs());
+        let mut grants: HashMap<u64, RoleGrant> = HashMap::new();
+        for entry in entries {
+            let time = entry.id.created_at().unix_timestamp() as u64;
+            if entry.target_id.map(|target| target.get()) != Some(member.user_id) || time < since {
+                continue;
+            }
+
+            let added = entry.changes.iter().flatten()
+                .filter_map(|change| match change {
+                    Change::RolesAdded { old, new } => new.as_ref().or(old.as_ref()),
+                    _ => None,
+                })
+                .flatten()
+                .map(|role| role.id.get());
+            for role_id in added {
+                if roles.contains(&role_id) && !grants.contains_key(&role_id) {
+                    grants.insert(role_id, RoleGrant {
+                        role_id,
+                        granted_by: entry.user_id.get(),
+                        reason: entry.reason.clone(),
+                        time,
+                    });
+                }
+            }
+        }
+
+        let (user_id, server_id) = (member.user_id, member.server_id);
+        let roles = roles.to_vec();
+        self.writer.write(move |connection| {
+            let transaction = connection.transaction()?;
+            for role_id in roles {
+                match grants.get(&role_id) {
+                    Some(grant) => transaction.execute(
+                        "REPLACE INTO role_grants (user_id, server_id, role_id, granted_by, reason, time)
+                        VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                        rusqlite::params![user_id, server_id, role_id, grant.granted_by, grant.reason, grant.time],
+                    )?,
+                    // What's recorded is from an earlier time they had the role.
+                    None => transaction.execute(
+                        "DELETE FROM role_grants WHERE user_id=?1 AND server_id=?2 AND role_id=?3",
+                        [user_id, server_id, role_id],
+                    )?,
+                };
+            }
+            transaction.commit()
+        }).await
+    }
+
+    // When a member was last seen and whether they're waiting on a restore,
+    // from memory if they've been looked up or saved before.
+    async fn seen(&self, member: &SimpleMember) -> Result<Option<Seen>> {
+        if let Some(seen) = self.seen_cache.get(member) {
+            return Ok(seen);
+        }
+
+        // Read by the writer, which is what updates the cache, so nothing
+        // newer can be cached in between reading and caching this.
+        let key = member.clone();
+        let seen_cache = self.seen_cache.clone();
+        self.writer.write(move |connection| {
+            let seen = connection.query_row(
+                "SELECT time, pending_restore FROM last_seen
+                WHERE user_id=?1 AND server_id=?2",
+                [key.user_id, key.server_id],
+                |row| Ok(Seen {
+                    time: row.get(0)?,
+                    pending_restore: row.get::<_, Option<i64>>(1)?.is_some_and(|pending| pending != 0),
+                }),
+            ).optional()?;
+            seen_cache.insert(&key, seen);
+            Ok(seen)
+        }).await
+    }
+
+    // The roles of a member which get stored.
+    fn storable_roles(connection: &Connection, member: &SimpleMember) -> Result<HashSet<u64>> {
+        // The @everyone role shares its ID with the server, and everyone has it.
+        let sticky = Self::sticky_roles_in(connection, member.server_id)?;
+        Ok(member.roles.iter()
+            .filter(|role| **role != member.server_id)
+            .filter(|role| sticky.as_ref().is_none_or(|sticky| sticky.contains(role)))
+            .copied()
+            .collect())
+    }
+
+    // Brings a member's stored roles in line with what they have, returning
+    // the roles added.
+    fn store_roles(storage: &dyn Storage, connection: &Connection, member: &SimpleMember, roles: &HashSet<u64>) -> Result<Vec<u64>> {
+        let added = storage.set_roles(connection, member.user_id, member.server_id, roles)?;
+        for role_id in &added {
+            // Having the role again means any removal was undone.
+            connection.execute(
+                "DELETE FROM manual_removals WHERE user_id=?1 AND server_id=?2 AND role_id=?3",
+                [member.user_id, member.server_id, *role_id],
+            )?;
+        }
+
+        Ok(added)
+    }
+
+    fn stored_roles(&self, connection: &Connection, user_id: u64, server_id: u64) -> Result<Vec<u64>> {
+        self.storage.roles(connection, user_id, server_id)
+    }
+
+    // Every role stored for any member of a guild.
+    pub async fn stored_guild_roles(&self, server_id: u64) -> Result<Vec<u64>> {
+        let connection = self.data.lock().unwrap();
+        self.storage.guild_roles(&connection, server_id)
+    }
+
+    pub fn restore_latency(&self) -> Option<RestoreLatency> {
+        let [p50, p99] = self.stats.restore_latency_percentiles([50, 99])?;
+        let samples = self.stats.restore_latency.lock().unwrap().len();
+        Some(RestoreLatency { samples, p50, p99 })
+    }
+
+    // Counts a member rejoining, once however many times it's seen before 
+    // they're saved again.
+    async fn count_rejoin(&self, member: &SimpleMember, joined_at: i64) -> Result<()> {
+        let (user_id, server_id) = (member.user_id, member.server_id);
+        self.writer.write(move |connection| {
+            let transaction = connection.transaction()?;
+
+            // The join before this one, if this rejoin is new and there was one.
+            let previous: Option<Option<i64>> = transaction.query_row(
+                "SELECT last_rejoin FROM last_seen 
+                WHERE user_id=?1 AND server_id=?2 AND (last_rejoin IS NULL OR last_rejoin<>?3)",
+                rusqlite::params![user_id, server_id, joined_at],
+                |row| row.get(0),
+            ).optional()?;
+
+            // Removals from before the stay they just left were already
+            // skipped once, by the restore when they joined for it.
+            if let Some(Some(previous)) = previous {
+                transaction.execute(
+                    "DELETE FROM manual_removals WHERE user_id=?1 AND server_id=?2 AND time<?3",
+                    rusqlite::params![user_id, server_id, previous],
+                )?;
+            }
+
+            transaction.execute(
+                "UPDATE last_seen SET rejoins=COALESCE(rejoins, 0)+1, last_rejoin=?3 
+                WHERE user_id=?1 AND server_id=?2 AND (last_rejoin IS NULL OR last_rejoin<>?3)",
+                rusqlite::params![user_id, server_id, joined_at],
+            )?;
+
+            transaction.commit()
+        }).await
+    }
+
+    // What's known about a member's comings and goings in a guild.
+    pub async fn member_history(&self, user_id: UserId, server_id: GuildId) -> Result<Option<MemberHistory>> {
+        let connection = self.data.lock().unwrap();
+        let mut history_query = connection.prepare(
+            "SELECT time, first_seen, rejoins, nick, avatar FROM last_seen
+            WHERE user_id=?1 AND server_id=?2",
+        )?;
+
+        let history: Vec<MemberHistory> = history_query.query_map(
+            [user_id.get(), server_id.get()],
+            |row| Ok(MemberHistory {
+                last_seen: row.get(0)?,
+                first_seen: row.get(1)?,
+                rejoins: row.get::<_, Option<u64>>(2)?.unwrap_or(0),
+                nick: row.get(3)?,
+                avatar: row.get(4)?,
+                grants: vec![],
+            }),
+        )?.collect::<Result<_>>()?;
+        std::mem::drop(history_query);
+
+        let mut history = match history.into_iter().next() {
+            Some(history) => history,
+            None => return Ok(None),
+        };
+
+        // Grants of roles taken away since aren't kept up to date, so only
+        // those of roles still stored are shown.
+        let stored: HashSet<u64> = self.stored_roles(&connection, user_id.get(), server_id.get())?
+            .into_iter()
+            .collect();
+        let mut grants_query = connection.prepare(
+            "SELECT role_id, granted_by, reason, time FROM role_grants
+            WHERE user_id=?1 AND server_id=?2 ORDER BY time",
+        )?;
+        history.grants = grants_query.query_map(
+            [user_id.get(), server_id.get()],
+            |row| Ok(RoleGrant {
+                role_id: row.get(0)?,
+                granted_by: row.get(1)?,
+                reason: row.get(2)?,
+                time: row.get(3)?,
+            }),
+        )?
+            .filter(|grant| grant.as_ref().map_or(true, |grant| stored.contains(&grant.role_id)))
+            .collect::<Result<_>>()?;
+
+        Ok(Some(history))
+    }
+
+    pub async fn guild_stats(&self, server_id: u64) -> Result<GuildStats> {
+        let connection = self.data.lock().unwrap();
+        let recent_since = self.clock.now() - RECENT_MEMBER_WINDOW.as_secs();
+
+        connection.query_row(
+            "SELECT COUNT(*), MIN(first_seen), COUNT(CASE WHEN first_seen >= ?2 THEN 1 END)
+            FROM last_seen WHERE server_id=?1",
+            [server_id, recent_since],
+            |row| Ok(GuildStats {
+                members: row.get(0)?,
+                first_seen: row.get(1)?,
+                recent_members: row.get(2)?,
+            }),
+        )
+    }
+
+    fn mapped_roles(&self, connection: &Connection, member: &SimpleMember) -> Result<Vec<u64>> {
+        let mut roles = vec![];
+
+        for mapping in &self.config.role_mapping {
+            if mapping.to.server != member.server_id {
+                continue;
+            }
+
+            let old_roles = self.stored_roles(connection, member.user_id, mapping.from.server)?;
+            if old_roles.contains(&mapping.from.role) {
+                roles.push(mapping.to.role);
+            }
+        }
+
+        Ok(roles)
+    }
+
+    async fn record_removals(&self, member: &SimpleMember) -> Result<()> {
+        if self.config.restore_cooldown == 0 {
+            return Ok(());
+        }
+
+        let now = self.clock.now();
+        let cooldown = self.config.restore_cooldown;
+        let member = member.clone();
+        let storage = self.storage.clone();
+        self.writer.write(move |connection| {
+            let transaction = connection.transaction()?;
+
+            let removed = storage.roles(&transaction, member.user_id, member.server_id)?
+                .into_iter()
+                .filter(|role| !member.roles.contains(role));
+
+            for role_id in removed {
+                transaction.execute(
+                    "REPLACE INTO role_removals (user_id, server_id, role_id, time) VALUES (?1, ?2, ?3, ?4)",
+                    [member.user_id, member.server_id, role_id, now],
+                )?;
+            }
+
+            transaction.execute(
+                "DELETE FROM role_removals WHERE time<?1",
+                [now.saturating_sub(cooldown)],
+            )?;
+
+            transaction.commit()
+        }).await
+    }
+
+    fn cooling_down_roles(&self, connection: &Connection, member: &SimpleMember) -> Result<HashSet<u64>> {
+        if self.config.restore_cooldown == 0 {
+            return Ok(HashSet::new());
+        }
+
+        let mut removals_query = connection.prepare(
+            "SELECT role_id FROM role_removals 
+            WHERE user_id=?1 AND server_id=?2 AND time>=?3",
+        )?;
+
+        let cooling_down = removals_query.query_map(
+            [member.user_id, member.server_id, self.clock.now().saturating_sub(self.config.restore_cooldown)],
+            |row| row.get(0)
+        )?.collect::<Result<_>>();
+        cooling_down
+    }
+
+    fn manually_removed_roles(&self, connection: &Connection, member: &SimpleMember) -> Result<HashSet<u64>> {
+        let skip = self.config.guild(member.server_id)
+            .is_some_and(|guild| guild.skip_manual_removals);
+        if !skip {
+            return Ok(HashSet::new());
+        }
+
+        let mut removals_query = connection.prepare(
+            "SELECT role_id FROM manual_removals 
+            WHERE user_id=?1 AND server_id=?2",
+        )?;
+
+        let removed = removals_query.query_map(
+            [member.user_id, member.server_id],
+            |row| row.get(0)
+        )?.collect::<Result<_>>();
+        removed
+    }
+
+    // Records roles someone other than the bot took away from a member.
+    pub async fn record_manual_removals(
+        &self, 
+        user_id: UserId, 
+        server_id: GuildId, 
+        roles: &[RoleId],
+    ) -> Result<()> {
+        self.do_locked((user_id, server_id), || self.record_manual_removals_locked(user_id, server_id, roles)).await
+    }
+
+    async fn record_manual_removals_locked(
+        &self, 
+        user_id: UserId, 
+        server_id: GuildId, 
+        roles: &[RoleId],
+    ) -> Result<()> {
+        let now = self.clock.now();
+        let roles = roles.to_vec();
+        self.writer.write(move |connection| {
+            let transaction = connection.transaction()?;
+
+            for role in roles {
+                transaction.execute(
+                    "REPLACE INTO manual_removals (user_id, server_id, role_id, time) VALUES (?1, ?2, ?3, ?4)",
+                    [user_id.get(), server_id.get(), role.get(), now],
+                )?;
+            }
+
+            transaction.commit()
+        }).await
+    }
+
+    // Decides what to do with each role a member could have restored, in the 
+    // order they should be applied.
+    fn plan_restore(
+        &self, 
+        connection: &Connection, 
+        member: &SimpleMember, 
+        mut roles: Vec<u64>,
+        special: &SpecialRoles,
+    ) -> Result<Vec<(u64, Verdict)>> {
+        let mut seen = HashSet::new();
+        roles.retain(|role| seen.insert(*role));
+
+        let priority = self.config.guild(member.server_id)
+            .and_then(|guild| guild.priority_roles.as_deref())
+            .unwrap_or(&self.config.priority_roles);
+        sort_by_priority(&mut roles, priority);
+
+        let cooling_down = self.cooling_down_roles(connection, member)?;
+        let manually_removed = self.manually_removed_roles(connection, member)?;
+        let sticky = Self::sticky_roles_in(connection, member.server_id)?;
+
+        let allowed_privileged = self.config.guild(member.server_id)
+            .map(|guild| guild.allow_privileged_roles.as_slice())
+            .unwrap_or_default();
+
+        // Discord refuses any roles past the limit, the roles coming first are 
+        // the ones which get restored.
+        let held = member.roles.iter().filter(|role| **role != member.server_id).count();
+        let mut room = MAX_MEMBER_ROLES.saturating_sub(held);
+
+        let exclusions = self.exclusions.read().unwrap();
+
+        Ok(roles.into_iter().map(|role| {
+            let verdict = if role == member.server_id {
+                Verdict::Everyone
+            } else if special.existing.as_ref().is_some_and(|existing| !existing.contains(&role)) {
+                Verdict::Deleted
+            } else if special.booster == Some(role) {
+                Verdict::Booster
+            } else if special.linked.contains(&role) {
+                Verdict::Linked
+            } else if special.managed.contains(&role) {
+                Verdict::Managed
+            } else if exclusions.system_managed_roles.contains(&role) {
+                Verdict::SystemManaged
+            } else if !exclusions.persists_role(role) || sticky.as_ref().is_some_and(|sticky| !sticky.contains(&role)) {
+                Verdict::Excluded
+            } else if member.roles.contains(&role) {
+                Verdict::AlreadyHeld
+            } else if exclusions.self_assignable_roles.contains(&role) {
+                Verdict::SelfAssignable
+            } else if cooling_down.contains(&role) {
+                Verdict::CoolingDown
+            } else if manually_removed.contains(&role) {
+                Verdict::ManuallyRemoved
+            } else if special.privileged.contains(&role) && !allowed_privileged.contains(&role) {
+                Verdict::Privileged
+            } else if special.sensitive.contains(&role) && !exclusions.sensitive_roles_allowed.contains(&role) {
+                Verdict::Sensitive
+            } else if special.above_bot.contains(&role) {
+                Verdict::AboveBot
+            } else if room == 0 {
+                Verdict::RoleLimit
+            } else {
+                room -= 1;
+                Verdict::Restore
+            };
+            (role, verdict)
+        }).collect())
+    }
+
+    fn is_account_too_young(&self, member: &SimpleMember) -> bool {
+        let min_age = match self.config.min_account_age_days {
+            Some(days) => days * 24 * 60 * 60,
+            None => return false,
+        };
+
+        let age = self.clock.now().saturating_sub(snowflake_time(member.user_id));
+        if age < min_age {
+            println!(
+                "Not restoring roles for member {} in server {}, their account is only {} days old",
+                member.user_id,
+                member.server_id,
+                age / (24 * 60 * 60),
+            );
+            true
+        } else {
+            false
+        }
+    }
+
+    // Plans giving a rejoining member back their stored (and mapped) roles,
+    // None if they aren't to be restored at all.
+    async fn plan_rejoin(&self, context: &Context, member: &SimpleMember) -> Result<Option<Vec<(u64, Verdict)>>> {
+        if self.is_account_too_young(member) {
+            return Ok(None);
+        }
+
+        let special = self.special_roles(context, member.server_id).await;
+        let connection = self.data.lock().unwrap();
+        let mut roles = self.stored_roles(&connection, member.user_id, member.server_id)?;
+        roles.extend(self.mapped_roles(&connection, member)?);
+        roles.extend(Self::capped_roles(&connection, member)?);
+        self.plan_restore(&connection, member, roles, &special).map(Some)
+    }
+
+    // Roles the restore cap left out when the member last rejoined.
+    fn capped_roles(connection: &Connection, member: &SimpleMember) -> Result<Vec<u64>> {
+        let mut capped_query = connection.prepare(
+            "SELECT role_id FROM capped_roles 
+            WHERE user_id=?1 AND server_id=?2",
+        )?;
+
+        let capped = capped_query.query_map(
+            [member.user_id, member.server_id],
+            |row| row.get(0)
+        )?.collect::<Result<_>>();
+        capped
+    }
+
+    // Keeps the roles a rejoin's restore left out for being over the cap, 
+    // for the next restore to give back. Any kept from before were part of 
+    // this restore, so they go.
+    async fn keep_capped_roles(&self, member: &SimpleMember, roles: Vec<u64>) -> Result<()> {
+        let (user_id, server_id) = (member.user_id, member.server_id);
+        self.writer.write(move |connection| {
+            let transaction = connection.transaction()?;
+
+            transaction.execute(
+                "DELETE FROM capped_roles WHERE user_id=?1 AND server_id=?2",
+                [user_id, server_id],
+            )?;
+
+            for role_id in roles {
+                transaction.execute(
+                    "INSERT INTO capped_roles (user_id, server_id, role_id) VALUES (?1, ?2, ?3)",
+                    [user_id, server_id, role_id],
+                )?;
+            }
+
+            transaction.commit()
+        }).await
+    }
+
+    fn profile_roles(
+        &self, 
+        context: &Context, 
+        connection: &Connection, 
+        member: &SimpleMember,
+    ) -> Result<Vec<u64>> {
+        let source = match self.config.guild(member.server_id).and_then(|guild| guild.profile_source) {
+            Some(source) => source,
+            None => return Ok(vec![]),
+        };
+
+        let source_roles = self.stored_roles(connection, member.user_id, source)?;
+
+        // Role IDs are specific to each server, so roles are matched by name.
+        let names: HashSet<String> = match context.cache.guild(source) {
+            Some(guild) => source_roles.into_iter()
+                .filter(|role| *role != source)
+                .filter_map(|role| guild.roles.get(&RoleId::new(role)))
+                .filter(|role| !role.managed)
+                .map(|role| role.name.clone())
+                .collect(),
+            None => return Ok(vec![]),
+        };
+
+        Ok(match context.cache.guild(member.server_id) {
+            Some(guild) => guild.roles.values()
+                .filter(|role| role.id.get() != member.server_id)
+                .filter(|role| !role.managed && names.contains(&role.name))
+                .map(|role| role.id.get())
+                .collect(),
+            None => vec![],
+        })
+    }
+
+    // Plans giving a member seen here for the first time the roles they 
+    // have through mappings or their profile.
+    async fn plan_new_member(
+        &self, 
+        context: &Context, 
+        member: &SimpleMember
+    ) -> Result<Vec<(u64, Verdict)>> {
+        if self.is_account_too_young(member) {
+            return Ok(vec![]);
+        }
+
+        let special = self.special_roles(context, member.server_id).await;
+        let connection = self.data.lock().unwrap();
+        let mut roles = self.mapped_roles(&connection, member)?;
+        roles.extend(self.profile_roles(context, &connection, member)?);
+        self.plan_restore(&connection, member, roles, &special)
+    }
+
+    // Starts the summary of a restore, warning about and pruning roles which
+    // can't be restored along the way.
+    async fn summarise_plan(&self, member: &SimpleMember, plan: &[(u64, Verdict)]) -> RestoreSummary {
+        let mut summary = RestoreSummary {
+            user_id: member.user_id,
+            server_id: member.server_id,
+            planned: plan.len(),
+            dry_run: self.is_dry_run(member.server_id).await,
+            ..Default::default()
+        };
+
+        for (_, verdict) in plan {
+            if let Some(reason) = verdict.skip_reason() {
+                *summary.skipped.entry(reason).or_default() += 1;
+            }
+        }
+
+        let above_bot: Vec<u64> = plan.iter()
+            .filter(|(_, verdict)| *verdict == Verdict::AboveBot)
+            .map(|(role, _)| *role)
+            .collect();
+
+        if !above_bot.is_empty() {
+            self.warn_hierarchy(member, &above_bot).await;
+        }
+
+        let privileged: Vec<u64> = plan.iter()
+            .filter(|(_, verdict)| *verdict == Verdict::Privileged)
+            .map(|(role, _)| *role)
+            .collect();
+
+        // Someone demoted from staff could otherwise get it all back by 
+        // leaving and rejoining, which staff should know about.
+        if !privileged.is_empty() {
+            println!(
+                "WARNING: not restoring administrator roles {:?} to member {} in server {}, \
+                list them in the server's allow_privileged_roles to restore them",
+                privileged,
+                member.user_id,
+                member.server_id,
+            );
+        }
+
+        let deleted: Vec<u64> = plan.iter()
+            .filter(|(_, verdict)| *verdict == Verdict::Deleted)
+            .map(|(role, _)| *role)
+            .collect();
+
+        if !deleted.is_empty() && self.config.prune_deleted_roles {
+            if let Err(error) = self.forget_roles(member.server_id, &deleted).await {
+                println!("Error removing deleted roles in server {}: {}", member.server_id, error);
+            }
+        }
+
+        summary
+    }
+
+    async fn restore_roles(
+        &self, 
+        context: &Context, 
+        member: &mut SimpleMember,
+        plan: Vec<(u64, Verdict)>,
+        reason: &str,
+    ) -> RestoreSummary {
+        let mut summary = self.summarise_plan(member, &plan).await;
+
+        let roles = plan.into_iter()
+            .filter(|(_, verdict)| *verdict == Verdict::Restore)
+            .map(|(role, _)| RoleId::new(role));
+
+        // The roles aren't added to the member here, what gets saved should
+        // be what they really have.
+        if summary.dry_run {
+            summary.restored = roles.map(|role| role.get()).collect();
+            if summary.planned > 0 {
+                println!("{}", summary);
+            }
+            return summary;
+        }
+
+        // Someone else may have given the member roles since the event this
+        // restore came from, which there's no need to add again.
+        let mut roles: Vec<RoleId> = roles.collect();
+        if let Some(current) = self.current_roles(context, member).await {
+            let held = roles.len();
+            roles.retain(|role| !current.contains(&role.get()));
+            let held = held - roles.len();
+            if held > 0 {
+                *summary.skipped.entry("already had").or_default() += held;
+            }
+            member.roles = current;
+        }
+
+        let server_id = GuildId::new(member.server_id);
+        let user_id = UserId::new(member.user_id);
+        let deadline = Instant::now() + ROLE_RETRY_BUDGET;
+
+        if !roles.is_empty() && !self.may_try_restoring(server_id).await {
+            summary.held_back = true;
+            println!(
+                "Holding back restore for member {} in server {} until the bot has permission to add roles",
+                member.user_id,
+                member.server_id,
+            );
+            return summary;
+        }
+
+        if !roles.is_empty() && self.hold_while_unavailable(context, member).await {
+            summary.held_back = true;
+            println!(
+                "Server {} is unavailable, restoring roles for member {} once it's back",
+                member.server_id,
+                member.user_id,
+            );
+            return summary;
+        }
+
+        // All roles still go through the role queue, so adding several at 
+        // once doesn't get around its pacing.
+        let total = roles.len();
+        let mut attempts = futures::stream::iter(roles)
+            .map(|role| async move {
+                let result = retry::with_limits(ROLE_ADD_ATTEMPTS, Some(deadline), || self.role_queue.add_role(
+                    context.http.clone(),
+                    server_id, 
+                    user_id, 
+                    role,
+                    reason,
+                )).await;
+                (role, result)
+            })
+            .buffer_unordered(self.config.restore_concurrency.max(1));
+
+        let mut handled = 0;
+        while let Some((role, role_add_attempt)) = attempts.next().await {
+            match role_add_attempt {
+                // Dropping the stream cancels the rest, which would only fail
+                // the same way.
+                Err(error) if retry::is_unknown_member(&error) => {
+                    summary.member_left = true;
+                    println!(
+                        "Member {} left server {} during restore, aborted with {} roles remaining",
+                        member.user_id,
+                        member.server_id,
+                        total - handled,
+                    );
+                    return summary;
+                },
+                // Without Manage Roles every other role would fail the same 
+                // way, and the role itself was already checked to be below
+                // the bot.
+                Err(error) if retry::is_missing_permissions(&error) => {
+                    self.stats.record_error(member.server_id);
+                    summary.held_back = true;
+                    if let Err(error) = self.record_restore_failure(member.server_id, role.get()).await {
+                        println!("Error recording failed restore of role {}: {}", role.get(), error);
+                    }
+                    println!(
+                        "Missing permissions restoring roles for member {} in server {}, aborted with {} roles remaining",
+                        member.user_id,
+                        member.server_id,
+                        total - handled,
+                    );
+                    self.record_permission_failure(context, server_id).await;
+                    return summary;
+                },
+                // The rest would fail the same way until the outage is over.
+                Err(error) if retry::classify(&error) == retry::Failure::Transient
+                    && self.hold_while_unavailable(context, member).await => {
+                    summary.held_back = true;
+                    println!(
+                        "Server {} went unavailable during restore for member {}, aborted with {} roles remaining, \
+                        trying again once it's back",
+                        member.server_id,
+                        member.user_id,
+                        total - handled,
+                    );
+                    return summary;
+                },
+                Ok(()) => {
+                    self.clear_permission_failure(server_id).await;
+                    self.stats.record_restored(member.server_id, 1);
+                    summary.restored.push(role.get());
+                    member.roles.insert(role.get());
+                },
+                Err(error) => {
+                    self.stats.record_error(member.server_id);
+                    if retry::classify(&error) == retry::Failure::Transient {
+                        summary.failed_after_retries += 1;
+                    }
+                    summary.failed.push((role.get(), error.to_string()));
+                },
+            }
+            handled += 1;
+        }
+
+        if summary.planned > 0 {
+            println!("{}", summary);
+        }
+        summary
+    }
+
+    // Makes a member's roles exactly the ones stored for them with a single 
+    // edit, taking away any they've been given since they rejoined. Roles the
+    // bot couldn't give back or doesn't look after are never taken away.
+    async fn replace_roles(
+        &self, 
+        context: &Context, 
+        member: &mut SimpleMember,
+        plan: Vec<(u64, Verdict)>,
+        reason: &str,
+    ) -> RestoreSummary {
+        let mut summary = self.summarise_plan(member, &plan).await;
+        summary.replaced = true;
+
+        // Nothing stored leaves nothing to replace the member's roles with,
+        // going ahead would take away every role they have.
+        if plan.is_empty() {
+            return summary;
+        }
+
+        let server_id = GuildId::new(member.server_id);
+        let user_id = UserId::new(member.user_id);
+
+        // The edit sets every role at once, so anything the member was given 
+        // since the event this came from has to be seen first, the cache may 
+        // not have caught up.
+        let live: HashSet<u64> = match retry::with_backoff(|| context.http.get_member(server_id, user_id)).await {
+            Ok(fetched) => fetched.roles.iter().map(|role| role.get()).collect(),
+            Err(error) if retry::is_unknown_member(&error) => {
+                summary.member_left = true;
+                return summary;
+            },
+            Err(error) => {
+                summary.held_back = true;
+                println!(
+                    "Error fetching roles of member {} in server {}, not replacing their roles: {}",
+                    member.user_id,
+                    member.server_id,
+                    error,
+                );
+                return summary;
+            },
+        };
+
+        let special = self.special_roles(context, member.server_id).await;
+        let stored: HashSet<u64> = plan.iter().map(|(role, _)| *role).collect();
+        let (kept, removing): (Vec<u64>, Vec<u64>) = {
+            let exclusions = self.exclusions.read().unwrap();
+            live.iter().partition(|role| {
+                stored.contains(role)
+                    || **role == member.server_id
+                    || special.managed.contains(role)
+                    || special.booster == Some(**role)
+                    || special.linked.contains(role)
+                    || special.above_bot.contains(role)
+                    || exclusions.system_managed_roles.contains(role)
+                    || !exclusions.persists_role(**role)
+            })
+        };
+
+        let restoring: Vec<u64> = plan.iter()
+            .filter(|(_, verdict)| *verdict == Verdict::Restore)
+            .map(|(role, _)| *role)
+            .collect();
+        let adding: Vec<u64> = restoring.iter()
+            .filter(|role| !live.contains(role))
+            .copied()
+            .collect();
+        let held = restoring.len() - adding.len();
+        if held > 0 {
+            *summary.skipped.entry("already had").or_default() += held;
+        }
+
+        if summary.dry_run {
+            summary.restored = adding;
+            summary.removed = removing;
+            println!("{}", summary);
+            return summary;
+        }
+
+        if adding.is_empty() && removing.is_empty() {
+            member.roles = live;
+            if summary.planned > 0 {
+                println!("{}", summary);
+            }
+            return summary;
+        }
+
+        if !self.may_try_restoring(server_id).await {
+            summary.held_back = true;
+            println!(
+                "Holding back restore for member {} in server {} until the bot has permission to add roles",
+                member.user_id,
+                member.server_id,
+            );
+            return summary;
+        }
+
+        if self.hold_while_unavailable(context, member).await {
+            summary.held_back = true;
+            println!(
+                "Server {} is unavailable, replacing roles for member {} once it's back",
+                member.server_id,
+                member.user_id,
+            );
+            return summary;
+        }
+
+        let roles: Vec<RoleId> = kept.iter().chain(&adding).map(|role| RoleId::new(*role)).collect();
+        let deadline = Instant::now() + ROLE_RETRY_BUDGET;
+        let result = retry::with_limits(ROLE_ADD_ATTEMPTS, Some(deadline), || {
+            let edit = EditMember::new()
+                .roles(roles.clone())
+                .audit_log_reason(reason);
+            server_id.edit_member(context, user_id, edit)
+        }).await;
+
+        match result {
+            Err(error) if retry::is_unknown_member(&error) => {
+                summary.member_left = true;
+                println!(
+                    "Member {} left server {} before their roles could be replaced",
+                    member.user_id,
+                    member.server_id,
+                );
+            },
+            Err(error) if retry::is_missing_permissions(&error) => {
+                self.stats.record_error(member.server_id);
+                summary.held_back = true;
+                for role in &adding {
+                    if let Err(error) = self.record_restore_failure(member.server_id, *role).await {
+                        println!("Error recording failed restore of role {}: {}", role, error);
+                    }
+                }
+                println!(
+                    "Missing permissions replacing roles for member {} in server {}",
+                    member.user_id,
+                    member.server_id,
+                );
+                self.record_permission_failure(context, server_id).await;
+            },
+            Ok(_) => {
+                self.clear_permission_failure(server_id).await;
+                self.stats.record_restored(member.server_id, adding.len() as u64);
+                member.roles = kept.into_iter().chain(adding.iter().copied()).collect();
+                summary.restored = adding;
+                summary.removed = removing;
+                println!("{}", summary);
+            },
+            Err(error) => {
+                self.stats.record_error(member.server_id);
+                summary.failed = adding.iter().chain(&removing)
+                    .map(|role| (*role, error.to_string()))
+                    .collect();
+                if retry::classify(&error) == retry::Failure::Transient {
+                    summary.failed_after_retries = summary.failed.len();
+                }
+                member.roles = live;
+                println!("{}", summary);
+            },
+        }
+
+        summary
+    }
+
+    // Gives a rejoining member back the nickname they had when last seen,
+    // unless they've already picked a new one. Discord doesn't let bots set
+    // anyone else's server avatar, so a stored one is only shown by
+    // /rolepersist history.
+    async fn restore_nickname(&self, context: &Context, member: &mut SimpleMember) -> Result<()> {
+        if !self.config.restore_nicknames || member.nick.is_some() {
+            return Ok(());
+        }
+
+        let nick: Option<String> = {
+            let connection = self.data.lock().unwrap();
+            connection.query_row(
+                "SELECT nick FROM last_seen WHERE user_id=?1 AND server_id=?2",
+                [member.user_id, member.server_id],
+                |row| row.get(0),
+            ).optional()?.flatten()
+        };
+        let nick = match nick {
+            Some(nick) => nick,
+            None => return Ok(()),
+        };
+
+        if self.is_dry_run(member.server_id).await {
+            println!(
+                "[DRY RUN] Would restore nickname {:?} of member {} in server {}",
+                nick,
+                member.user_id,
+                member.server_id,
+            );
+            return Ok(());
+        }
+
+        let edit = EditMember::new()
+            .nickname(&nick)
+            .audit_log_reason("Restoring nickname held when last seen (rolepersist)");
+        match GuildId::new(member.server_id).edit_member(context, UserId::new(member.user_id), edit).await {
+            Ok(_) => member.nick = Some(nick),
+            // Most likely the bot lacks Manage Nicknames or the member is
+            // above it, which isn't worth more than a note.
+            Err(error) => println!(
+                "Unable to restore nickname of member {} in server {}: {}",
+                member.user_id,
+                member.server_id,
+                error,
+            ),
+        }
+        Ok(())
+    }
+
+    // Gives a rejoining member their stored roles the way their guild is 
+    // configured to.
+    async fn restore_rejoin(
+        &self, 
+        context: &Context, 
+        member: &mut SimpleMember,
+        plan: Vec<(u64, Verdict)>,
+        last_seen: Option<i64>,
+    ) -> RestoreSummary {
+        if self.replaces_roles(member.server_id) {
+            self.replace_roles(context, member, plan, &replace_reason(last_seen)).await
+        } else {
+            self.restore_roles(context, member, plan, &restore_reason(last_seen)).await
+        }
+    }
+
+    fn replaces_roles(&self, server_id: u64) -> bool {
+        self.config.guild(server_id)
+            .is_some_and(|guild| guild.restore_strategy == RestoreStrategy::Replace)
+    }
+
+    // Removes stored roles from every member of a guild.
+    async fn forget_roles(&self, server_id: u64, roles: &[u64]) -> Result<()> {
+        let roles = roles.to_vec();
+        let storage = self.storage.clone();
+        self.writer.write(move |connection| {
+            let transaction = connection.transaction()?;
+
+            storage.forget_roles(&transaction, server_id, &roles)?;
+            transaction.commit()
+        }).await
+    }
+
+    // The guild's roles which need special treatment when restoring, asking 
+    // Discord for the guild's roles if they aren't cached.
+    async fn special_roles(&self, context: &Context, server_id: u64) -> SpecialRoles {
+        if context.cache.guild(server_id).is_some() {
+            return SpecialRoles::cached(context, server_id);
+        }
+
+        match context.http.get_guild_roles(GuildId::new(server_id)).await {
+            Ok(roles) => SpecialRoles::from_roles(roles.iter(), None),
+            Err(error) => {
+                println!("Error fetching roles of server {}: {}", server_id, error);
+                SpecialRoles::default()
+            },
+        }
+    }
+
+    // Puts off restoring a member until their guild is available again, if
+    // it's in an outage, returning whether it was.
+    async fn hold_while_unavailable(&self, context: &Context, member: &SimpleMember) -> bool {
+        let mut unavailable = self.unavailable_guilds.lock().await;
+        match unavailable.get_mut(&GuildId::new(member.server_id)) {
+            Some(held) => {
+                held.push(Work {
+                    context: context.clone(),
+                    task: Task::Observe { member: member.clone(), origin: Origin::Backlog },
+                });
+                true
+            },
+            None => false,
+        }
+    }
+
+    async fn mark_unavailable(&self, server_id: GuildId) {
+        self.unavailable_guilds.lock().await.entry(server_id).or_default();
+    }
+
+    // Takes a guild out of its outage, queueing the restores held back by it.
+    async fn mark_available(&self, server_id: GuildId) {
+        let held = self.unavailable_guilds.lock().await.remove(&server_id);
+        if let Some(held) = held.filter(|held| !held.is_empty()) {
+            println!("Server {} is available again, retrying {} restores", server_id.get(), held.len());
+            for work in held {
+                self.enqueue(&work.context, server_id, work.task);
+            }
+        }
+    }
+
+    // Whether restores in a guild should go ahead, once every 
+    // PERMISSION_RECHECK_INTERVAL one is let through while they're failing.
+    async fn may_try_restoring(&self, server_id: GuildId) -> bool {
+        let mut failures = self.permission_failures.lock().await;
+        match failures.get_mut(&server_id) {
+            Some(last_try) if last_try.elapsed() < PERMISSION_RECHECK_INTERVAL => false,
+            Some(last_try) => {
+                *last_try = Instant::now();
+                true
+            },
+            None => true,
+        }
+    }
+
+    // Holds back restores in a guild, telling its staff the first time.
+    async fn record_permission_failure(&self, context: &Context, server_id: GuildId) {
+        let first = self.permission_failures.lock().await
+            .insert(server_id, Instant::now())
+            .is_none();
+        if first {
+            self.alert_staff(context, server_id, &format!(
+                "Roles can't be restored to members rejoining this server because the bot is missing \
+                permissions, check it has Manage Roles. Restores are held back and tried again every {} minutes.",
+                PERMISSION_RECHECK_INTERVAL.as_secs() / 60,
+            )).await;
+        }
+    }
+
+    async fn clear_permission_failure(&self, server_id: GuildId) {
+        if self.permission_failures.lock().await.remove(&server_id).is_some() {
+            println!("Restoring roles in server {} works again", server_id.get());
+        }
+    }
+
+    // Records roles skipped for being above the bot, warning about them at 
+    // most once per interval for each guild rather than for every member.
+    async fn warn_hierarchy(&self, member: &SimpleMember, roles: &[u64]) {
+        for role in roles {
+            if let Err(error) = self.record_restore_failure(member.server_id, *role).await {
+                println!("Error recording failed restore of role {}: {}", role, error);
+            }
+        }
+
+        let now = Instant::now();
+        let mut warnings = self.hierarchy_warnings.lock().await;
+        let server_id = GuildId::new(member.server_id);
+        if warnings.get(&server_id).is_some_and(|last| now.duration_since(*last) < HIERARCHY_WARNING_INTERVAL) {
+            return;
+        }
+        warnings.insert(server_id, now);
+
+        println!(
+            "Warning: not restoring roles {:?} for member {} in server {}, they're above the bot's highest role \
+            (run /rolepersist unrestorable for details, further warnings for this server are held back for {}m)",
+            roles,
+            member.user_id,
+            member.server_id,
+            HIERARCHY_WARNING_INTERVAL.as_secs() / 60,
+        );
+    }
+
+    // Prints what restoring a member would do with each of their roles, 
+    // without changing anything on Discord.
+    pub async fn explain_restore(
+        &self, 
+        http: &Http, 
+        server_id: GuildId, 
+        user_id: UserId,
+    ) -> std::result::Result<(), Error> {
+        let guild_roles: HashMap<RoleId, Role> = http.get_guild_roles(server_id).await?
+            .into_iter()
+            .map(|role| (role.id, role))
+            .collect();
+
+        let bot = http.get_current_user().await?;
+        let bot_position = http.get_member(server_id, bot.id).await?.roles.iter()
+            .filter_map(|role| guild_roles.get(role))
+            .map(|role| role.position)
+            .max()
+            .unwrap_or(0);
+
+        let member = match http.get_member(server_id, user_id).await {
+            Ok(member) => SimpleMember::from(member),
+            Err(_) => {
+                println!("User {} is not in server {}, assuming they are joining now", user_id.get(), server_id.get());
+                SimpleMember {
+                    joined_at: Some(self.clock.now() as i64),
+                    user_id: user_id.get(),
+                    server_id: server_id.get(),
+                    roles: HashSet::new(),
+                    pending: false,
+                    bot: false,
+                    nick: None,
+                    avatar: None,
+                    timeout: None,
+                }
+            },
+        };
+
+        let connection = self.data.lock().unwrap();
+        let last_seen = Self::last_seen_in(&connection, &member)?;
+        let rejoined = match (last_seen, member.joined_at) {
+            (Some(last_seen), Some(joined_at)) => last_seen < joined_at,
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+
+        let stored = self.stored_roles(&connection, member.user_id, member.server_id)?;
+        let mapped = self.mapped_roles(&connection, &member)?;
+
+        let mut roles = vec![];
+        if rejoined {
+            println!("Member would be treated as rejoining");
+            roles.extend(stored.iter().copied());
+        } else if last_seen.is_none() {
+            println!("Member would be treated as new");
+            if self.config.guild(member.server_id).and_then(|guild| guild.profile_source).is_some() {
+                println!("Profile roles depend on the running bot's cache and aren't shown");
+            }
+        } else {
+            println!("Member would not be treated as rejoining, nothing would be restored");
+            for role in &stored {
+                println!("  role {}: stored", role);
+            }
+            return Ok(());
+        }
+        roles.extend(mapped.iter().copied());
+
+        let special = SpecialRoles::from_roles(guild_roles.values(), Some(bot_position));
+
+        for (role, verdict) in self.plan_restore(&connection, &member, roles, &special)? {
+            let source = if stored.contains(&role) { "stored" } else { "mapped" };
+            let outcome = match guild_roles.get(&RoleId::new(role)) {
+                None => "no longer exists".to_string(),
+                Some(_) => verdict.to_string(),
+            };
+
+            let name = guild_roles.get(&RoleId::new(role))
+                .map(|role| role.name.as_str())
+                .unwrap_or("?");
+            println!("  role {} ({}): {}, {}", role, name, source, outcome);
+        }
+
+        Ok(())
+    }
+
+    fn last_seen_in(connection: &Connection, member: &SimpleMember) -> Result<Option<i64>> {
+        let mut last_seen_query = connection.prepare(
+            "SELECT time FROM last_seen 
+            WHERE user_id=?1 AND server_id=?2",
+        )?;
+
+        let last_seen: Vec<i64> = last_seen_query.query_map(
+            [member.user_id, member.server_id],
+            |row| row.get::<usize, i64>(0)
+        )?.collect::<Result<_>>()?;
+
+        assert!(last_seen.len() <= 1);
+        Ok(last_seen.first().copied())
+    }
+
+    async fn last_seen(&self, member: &SimpleMember) -> Result<Option<i64>> {
+        Ok(self.seen(member).await?.map(|seen| seen.time))
+    }
+
+    // Gives a member back their stored roles as if they'd just rejoined, for 
+    // bots embedding this one which decide for themselves when to restore.
+    pub async fn restore_member(&self, context: &Context, member: &mut SimpleMember) -> Result<()> {
+        self.do_locked(member.lock_key(), || self.restore_member_locked(context, member)).await
+    }
+
+    async fn restore_member_locked(&self, context: &Context, member: &mut SimpleMember) -> Result<()> {
+        let last_seen = self.last_seen(member).await?;
+        let plan = match self.plan_rejoin(context, member).await? {
+            Some(plan) => plan,
+            None => return self.save_observed(context, member, true).await,
+        };
+        let summary = self.restore_rejoin(context, member, plan, last_seen).await;
+        if summary.member_left || summary.held_back {
+            return Ok(());
+        }
+        self.keep_capped_roles(member, vec![]).await?;
+        self.restore_nickname(context, member).await?;
+        self.save_observed(context, member, true).await
+    }
+
+    // Saves a member and restores their roles if they rejoined, for bots 
+    // embedding this one which pass events on themselves. Going through
+    // enqueue_observe instead keeps each guild's events in order.
+    pub async fn observe_member(&self, context: &Context, member: &mut SimpleMember, origin: Origin) {
+        self.do_locked(member.lock_key(), || self.observe_member_locked(context, member, origin)).await
+    }
+
+    async fn observe_member_locked(&self, context: &Context, member: &mut SimpleMember, origin: Origin) {
+        if let Err(error) = self.observe(context, member, origin).await {
+            self.stats.record_error(member.server_id);
+            println!(
+                "Error observing member {} in server {}: {}",
+                member.user_id,
+                member.server_id,
+                error,
+            );
+        }
+    }
+
+    async fn observe(&self, context: &Context, member: &mut SimpleMember, origin: Origin) -> Result<()> {
+        if !self.persists(member) {
+            return Ok(());
+        }
+
+        let start = Instant::now();
+
+        if member.joined_at.is_none() {
+            member.joined_at = self.fetch_joined_at(context, member).await;
+        }
+
+        // An update held back for the member happened before this, so it's
+        // stored first unless this is a later update which replaces it.
+        let mut held_since = None;
+        if origin != Origin::Debounced {
+            match self.take_debounced(member).await {
+                Some(held) if origin == Origin::Update && held.member.joined_at == member.joined_at => {
+                    held_since = Some(held.first);
+                },
+                Some(mut held) => {
+                    Box::pin(self.observe(&held.context, &mut held.member, Origin::Debounced)).await?;
+                },
+                None => (),
+            }
+        }
+
+        let pending_restore = self.has_pending_restore(member).await?;
+        let last_seen = self.last_seen(member).await?;
+        let mut rejoined = false;
+        let plan = match (last_seen, member.joined_at) {
+            (Some(last_seen), Some(joined_at)) if last_seen < joined_at || pending_restore => {
+                // Member has left and rejoined since we last observed at them.
+                self.count_rejoin(member, joined_at).await?;
+                if self.skip_kicked(member, last_seen).await? {
+                    println!(
+                        "Not restoring roles for member {} in server {} who was kicked",
+                        member.user_id,
+                        member.server_id,
+                    );
+                    Some(vec![])
+                } else {
+                    let plan = self.plan_rejoin(context, member).await?;
+                    rejoined = plan.is_some();
+                    Some(plan.unwrap_or_default())
+                }
+            },
+            (Some(_), None) => {
+                // Without a join time there's no telling if they rejoined,
+                // assume they did rather than risk losing their roles.
+                println!(
+                    "No join time for member {} in server {}, restoring roles anyway",
+                    member.user_id,
+                    member.server_id,
+                );
+                let plan = self.plan_rejoin(context, member).await?;
+                rejoined = plan.is_some();
+                Some(plan.unwrap_or_default())
+            },
+            (None, _) => {
+                // First time seeing this member here, they may still have 
+                // roles from a mapped server or their profile.
+                Some(self.plan_new_member(context, member).await?)
+            },
+            _ => None,
+        };
+
+        // Members of guilds restores are restricted from are only saved, 
+        // without so much as their nickname given back.
+        let plan = match plan {
+            Some(plan) if !self.restores_allowed(member.server_id) => {
+                if plan.iter().any(|(_, verdict)| *verdict == Verdict::Restore) {
+                    println!(
+                        "Not restoring roles for member {} in server {}, restores aren't allowed there",
+                        member.user_id,
+                        member.server_id,
+                    );
+                }
+                rejoined = false;
+                Some(vec![])
+            },
+            plan => plan,
+        };
+
+        // Held members get nothing back until staff let them go. A rejoin is
+        // left unsaved meanwhile, so the roles from before are still stored 
+        // to be restored once they are.
+        let plan = match plan {
+            Some(_) if last_seen.is_some() && self.is_held(member)? => {
+                println!(
+                    "Not restoring roles for member {} in server {}, they're held",
+                    member.user_id,
+                    member.server_id,
+                );
+                return Ok(());
+            },
+            Some(_) if self.is_held(member)? => {
+                rejoined = false;
+                Some(vec![])
+            },
+            plan => plan,
+        };
+
+        match plan {
+            Some(mut plan) => {
+                // The member is left unsaved so the rejoin is still there to
+                // be found when the backlog gets to them, even after a restart.
+                // Replacing may only take roles away, which goes through the
+                // same checks as adding them.
+                let replacing = rejoined && self.replaces_roles(member.server_id);
+                let restoring = plan.iter().any(|(_, verdict)| *verdict == Verdict::Restore)
+                    || (replacing && !plan.is_empty());
+                if !restoring && !self.saves_on(origin) {
+                    return Ok(());
+                }
+
+                // Resyncs only record what members have, a rejoin one turns
+                // up is left unsaved for the member's next event or sync.
+                if origin == Origin::Resync && restoring {
+                    return Ok(());
+                }
+
+                // Adding roles before screening is done fails or lets them 
+                // skip it, so the restore waits for the update saying 
+                // they're through. Their stored roles are left alone meanwhile.
+                if member.pending && restoring {
+                    println!(
+                        "Member {} in server {} is pending membership screening, restoring roles once they're through",
+                        member.user_id,
+                        member.server_id,
+                    );
+                    return self.mark_pending_restore(member).await;
+                }
+
+                // Likewise giving back the verified role before the server's
+                // verification bot has let them through would skip it.
+                if restoring && self.awaiting_verification(member, &plan) {
+                    println!(
+                        "Member {} in server {} hasn't verified yet, restoring roles once they have",
+                        member.user_id,
+                        member.server_id,
+                    );
+                    return self.mark_pending_restore(member).await;
+                }
+
+                // Raids only put off restores, the member is saved as usual
+                // unless they have roles waiting, which are kept for them.
+                if restoring && self.hold_during_raid(context, member).await {
+                    println!(
+                        "Server {} is in raid mode, restoring roles for member {} once it's over",
+                        member.server_id,
+                        member.user_id,
+                    );
+                    return self.mark_pending_restore(member).await;
+                }
+
+                let delay = self.config.guild(member.server_id).map_or(0, |guild| guild.restore_delay_secs);
+                if delay > 0 && restoring && !matches!(origin, Origin::Delayed | Origin::Approval) {
+                    self.delay_restore(context, member, Duration::from_secs(delay)).await;
+                    return Ok(());
+                }
+
+                // Restores over the cap are put to staff like any needing 
+                // approval, if there's anywhere to ask them.
+                let guild = self.config.guild(member.server_id);
+                let over_cap = self.restore_cap(member.server_id)
+                    .filter(|cap| plan.iter().filter(|(_, verdict)| *verdict == Verdict::Restore).count() > *cap);
+                let ask = over_cap.is_some()
+                    && self.config.over_restore_cap == OverCap::Ask
+                    && guild.is_some_and(|guild| guild.staff_channel.is_some());
+
+                // The member is left unsaved while staff decide, so the rejoin
+                // is still found for as long as that takes.
+                let requires_approval = ask || guild.is_some_and(|guild| guild.require_approval);
+                let mut approved = false;
+                if requires_approval && restoring {
+                    match self.approval(member).await? {
+                        None => {
+                            if self.request_approval(context, member, &plan).await? {
+                                return Ok(());
+                            }
+                            // With nobody to ask it's as good as denied,
+                            // saving them rather than asking on every event.
+                            return self.save_observed(context, member, self.finds_grants(origin)).await;
+                        },
+                        Some(Approval::Pending) => return Ok(()),
+                        Some(Approval::Denied) => {
+                            println!(
+                                "Not restoring roles for member {} in server {}, staff denied it",
+                                member.user_id,
+                                member.server_id,
+                            );
+                            self.resolve_approval(member.user_id, member.server_id).await?;
+                            return self.save_observed(context, member, self.finds_grants(origin)).await;
+                        },
+                        Some(Approval::Approved) => {
+                            self.resolve_approval(member.user_id, member.server_id).await?;
+                            approved = true;
+                        },
+                    }
+                }
+
+                if let Some(cap) = over_cap.filter(|_| !approved) {
+                    println!(
+                        "Member {} in server {} has more roles to restore than the cap of {}, only restoring the first",
+                        member.user_id,
+                        member.server_id,
+                        cap,
+                    );
+                    self.notify_staff(context, GuildId::new(member.server_id), &format!(
+                        "<@{}> rejoined with more roles than are restored automatically, only the first {} were. \
+                        Review their roles and use `/rolepersist restore` to give back the rest.",
+                        member.user_id,
+                        cap,
+                    )).await;
+                    apply_restore_cap(&mut plan, cap);
+                }
+
+                if origin == Origin::Sync && restoring {
+                    self.restore_backlog.lock().await.push(Work {
+                        context: context.clone(),
+                        task: Task::Observe { member: member.clone(), origin: Origin::Backlog },
+                    });
+                    return Ok(());
+                }
+
+                // The event was sent before the restore it repeats, so the 
+                // roles it gave back are counted as theirs when saving.
+                if restoring && self.restored_recently(member).await {
+                    println!(
+                        "Member {} in server {} was just restored, ignoring the repeated rejoin",
+                        member.user_id,
+                        member.server_id,
+                    );
+                    member.roles.extend(plan.iter()
+                        .filter(|(_, verdict)| *verdict == Verdict::Restore)
+                        .map(|(role, _)| *role));
+                    return self.save_observed(context, member, self.finds_grants(origin)).await;
+                }
+
+                let capped: Vec<u64> = plan.iter()
+                    .filter(|(_, verdict)| *verdict == Verdict::OverCap)
+                    .map(|(role, _)| *role)
+                    .collect();
+
+                // With nothing to give back there's no restore to carry out,
+                // and replacing with nothing would strip the member's roles.
+                if !plan.is_empty() {
+                    let summary = if rejoined {
+                        self.restore_rejoin(context, member, plan, last_seen).await
+                    } else {
+                        self.restore_roles(context, member, plan, &restore_reason(last_seen)).await
+                    };
+                    // What they were partway to having isn't worth storing, 
+                    // the roles from before stay for when they're back or the
+                    // bot can add them.
+                    if summary.member_left || summary.held_back {
+                        return Ok(());
+                    }
+                    if !summary.restored.is_empty() && !summary.dry_run {
+                        self.stats.record_restore_latency(start.elapsed());
+                    }
+                }
+                if rejoined {
+                    self.keep_capped_roles(member, capped).await?;
+                    self.restore_nickname(context, member).await?;
+                }
+                if restoring {
+                    self.mark_restored(member).await;
+                }
+            },
+            // Something else has dealt with the member since they were put in
+            // the backlog, and the roles held here may be out of date.
+            None if origin == Origin::Backlog || origin == Origin::Delayed => return Ok(()),
+            None if origin == Origin::Approval => return self.resolve_approval(member.user_id, member.server_id).await,
+            None if !self.saves_on(origin) => return Ok(()),
+            // Role menus and onboarding bots change several roles in a row,
+            // only the last of which is worth storing.
+            None if origin == Origin::Update && self.config.update_debounce_ms > 0 => {
+                self.debounce(context, member, held_since).await;
+                return Ok(());
+            },
+            None => {
+                if self.is_suspicious_loss(member).await? && !self.confirm_roles(context, member).await {
+                    println!(
+                        "Discarding implausible role update for member {} in server {}",
+                        member.user_id,
+                        member.server_id,
+                    );
+                    return Ok(());
+                }
+
+                if origin == Origin::Sync && self.restores_allowed(member.server_id) && !self.is_held(member)? {
+                    if let Some(plan) = self.plan_drift_repair(context, member).await? {
+                        let summary = self.restore_roles(context, member, plan, "Repairing roles missing since last stored").await;
+                        if summary.member_left || summary.held_back {
+                            return Ok(());
+                        }
+                    }
+                }
+
+                // Any roles missing since last time were taken away while 
+                // they were still a member.
+                self.record_removals(member).await?;
+            },
+        }
+        
+        self.save_observed(context, member, self.finds_grants(origin)).await
+    }
+
+    // Plans adding back stored roles a member lost without leaving, if the 
+    // guild repairs drift and the roles were stored recently enough.
+    async fn plan_drift_repair(&self, context: &Context, member: &SimpleMember) -> Result<Option<Vec<(u64, Verdict)>>> {
+        let window = match self.config.guild(member.server_id).and_then(|guild| guild.repair_drift_within_secs) {
+            Some(window) => window,
+            None => return Ok(None),
+        };
+
+        let special = self.special_roles(context, member.server_id).await;
+        let connection = self.data.lock().unwrap();
+        match Self::last_seen_in(&connection, member)? {
+            Some(last_seen) if self.clock.now().saturating_sub(last_seen as u64) <= window => (),
+            _ => return Ok(None),
+        }
+
+        let missing: Vec<u64> = self.stored_roles(&connection, member.user_id, member.server_id)?
+            .into_iter()
+            .filter(|role| !member.roles.contains(role))
+            .collect();
+        if missing.is_empty() {
+            return Ok(None);
+        }
+
+        let plan = self.plan_restore(&connection, member, missing, &special)?;
+        let repairing = plan.iter().filter(|(_, verdict)| *verdict == Verdict::Restore).count();
+        if repairing == 0 {
+            return Ok(None);
+        }
+
+        println!(
+            "Member {} in server {} is missing {} stored roles without having left, repairing",
+            member.user_id,
+            member.server_id,
+            repairing,
+        );
+        Ok(Some(plan))
+    }
+
+    // Whether a member who had verified before is still to do so again.
+    fn awaiting_verification(&self, member: &SimpleMember, plan: &[(u64, Verdict)]) -> bool {
+        let verification = match self.config.guild(member.server_id).and_then(|guild| guild.verification.as_ref()) {
+            Some(verification) => verification,
+            None => return false,
+        };
+
+        member.roles.contains(&verification.unverified)
+            && plan.iter().any(|(role, _)| *role == verification.verified)
+    }
+
+    // The most roles restored to a member of a guild automatically, if capped.
+    fn restore_cap(&self, server_id: u64) -> Option<usize> {
+        self.config.guild(server_id)
+            .and_then(|guild| guild.max_auto_restore_roles)
+            .or(self.config.max_auto_restore_roles)
+    }
+
+    // Whether the member was restored within RESTORE_DEDUPE_WINDOW.
+    async fn restored_recently(&self, member: &SimpleMember) -> bool {
+        let now = self.clock.now();
+        self.recent_restores.lock().await
+            .get(&(member.user_id, member.server_id))
+            .is_some_and(|restored| now.saturating_sub(*restored) < RESTORE_DEDUPE_WINDOW.as_secs())
+    }
+
+    async fn mark_restored(&self, member: &SimpleMember) {
+        let now = self.clock.now();
+        let mut restores = self.recent_restores.lock().await;
+        restores.retain(|_, restored| now.saturating_sub(*restored) < RESTORE_DEDUPE_WINDOW.as_secs());
+        restores.insert((member.user_id, member.server_id), now);
+    }
+
+    // Bots usually get their roles from being added to a server, so they're 
+    // left alone unless configured otherwise.
+    fn persists(&self, member: &SimpleMember) -> bool {
+        !member.bot || self.config.persist_bots
+    }
+
+    // Whether members observed for this reason are saved when there's nothing 
+    // to restore for them.
+    fn saves_on(&self, origin: Origin) -> bool {
+        match origin {
+            Origin::Join => self.config.observe_on.contains(&ObserveOn::Join),
+            Origin::Update | Origin::Debounced => self.config.observe_on.contains(&ObserveOn::Update),
+            Origin::Sync | Origin::Resync | Origin::Backlog | Origin::Delayed | Origin::Approval => true,
+        }
+    }
+
+    // Whether roles new to members observed for this reason are looked up in
+    // the audit log. Syncs turn up roles given at any time since the last.
+    fn finds_grants(&self, origin: Origin) -> bool {
+        !matches!(origin, Origin::Sync | Origin::Resync)
+    }
+
+    // The member with any roles which shouldn't be stored left out.
+    fn storable(&self, context: &Context, member: &SimpleMember) -> SimpleMember {
+        let mut member = member.clone();
+        let special = SpecialRoles::cached(context, member.server_id);
+
+        // Boosting is up to the member, the role is never worth keeping.
+        member.roles.retain(|role| special.booster != Some(*role));
+        // Nor are roles which come from the member's connections or purchases.
+        member.roles.retain(|role| !special.linked.contains(role));
+        if self.config.skip_managed_roles {
+            member.roles.retain(|role| !special.managed.contains(role));
+        }
+        let server_id = member.server_id;
+        let exclusions = self.exclusions.read().unwrap();
+        member.roles.retain(|role| *role == server_id || exclusions.persists_role(*role));
+        member
+    }
+
+    // Counts a restore of a role which Discord refused for lack of permission.
+    async fn record_restore_failure(&self, server_id: u64, role_id: u64) -> Result<()> {
+        let now = self.clock.now();
+        self.writer.write(move |connection| {
+            connection.execute(
+                "INSERT INTO restore_failures (server_id, role_id, failures, last_failure) VALUES (?1, ?2, 1, ?3)
+                ON CONFLICT(server_id, role_id) DO UPDATE SET failures=failures+1, last_failure=excluded.last_failure",
+                [server_id, role_id, now],
+            )?;
+            Ok(())
+        }).await
+    }
+
+    // Roles whose restores were refused for lack of permission within the 
+    // failure window, with how many times and when it last happened.
+    pub async fn restore_failures(&self, server_id: u64) -> Result<HashMap<u64, (u64, u64)>> {
+        let connection = self.data.lock().unwrap();
+        let mut failures_query = connection.prepare(
+            "SELECT role_id, failures, last_failure FROM restore_failures 
+            WHERE server_id=?1 AND last_failure>=?2",
+        )?;
+
+        let failures = failures_query.query_map(
+            [server_id, self.clock.now().saturating_sub(RESTORE_FAILURE_WINDOW.as_secs())],
+            |row| Ok((row.get(0)?, (row.get(1)?, row.get(2)?)))
+        )?.collect::<Result<_>>();
+        failures
+    }
+
+    // Restores the member's roles after a delay, giving other bots time to
+    // finish with them first. The member is left unsaved meanwhile so the 
+    // rejoin is still found when the delay is up.
+    async fn delay_restore(&self, context: &Context, member: &SimpleMember, delay: Duration) {
+        let key = member.lock_key();
+        // Updates during the delay find the rejoin too, but the restore 
+        // already waiting will see their changes.
+        if !self.delayed_restores.lock().await.insert(key) {
+            return;
+        }
+
+        let handler = match self.this.get().and_then(Weak::upgrade) {
+            Some(handler) => handler,
+            None => return,
+        };
+
+        let context = context.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            handler.delayed_restores.lock().await.remove(&key);
+
+            // Roles given in the meantime are kept by observing the member 
+            // as they are now.
+            match context.http.get_member(key.1, key.0).await {
+                Ok(current) => handler.enqueue_observe(&context, current.into(), Origin::Delayed),
+                Err(error) => println!(
+                    "Not restoring roles for member {} in server {}, unable to fetch them after the delay: {}",
+                    key.0.get(),
+                    key.1.get(),
+                    error,
+                ),
+            }
+        });
+    }
+
+    async fn has_pending_restore(&self, member: &SimpleMember) -> Result<bool> {
+        Ok(self.seen(member).await?.is_some_and(|seen| seen.pending_restore))
+    }
+
+    // Whether staff have been asked to approve restoring a member, and what
+    // they decided.
+    async fn approval(&self, member: &SimpleMember) -> Result<Option<Approval>> {
+        let connection = self.data.lock().unwrap();
+        let mut approval_query = connection.prepare(
+            "SELECT approved FROM pending_approvals 
+            WHERE user_id=?1 AND server_id=?2",
+        )?;
+
+        let approvals: Vec<Option<bool>> = approval_query.query_map(
+            [member.user_id, member.server_id],
+            |row| row.get(0)
+        )?.collect::<Result<_>>()?;
+
+        Ok(approvals.first().map(|approved| match approved {
+            None => Approval::Pending,
+            Some(true) => Approval::Approved,
+            Some(false) => Approval::Denied,
+        }))
+    }
+
+    // Asks a guild's staff whether to restore a member's roles, returning 
+    // false if there's no staff channel to ask in.
+    async fn request_approval(&self, context: &Context, member: &SimpleMember, plan: &[(u64, Verdict)]) -> Result<bool> {
+        let server_id = GuildId::new(member.server_id);
+        let channel = match self.config.guild(member.server_id).and_then(|guild| guild.staff_channel) {
+            Some(channel) => ChannelId::new(channel),
+            None => {
+                println!(
+                    "Not restoring roles for member {} in server {}, they need approval but there's no staff channel to ask in",
+                    member.user_id,
+                    member.server_id,
+                );
+                return Ok(false);
+            },
+        };
+
+        let roles: Vec<String> = plan.iter()
+            .filter(|(_, verdict)| *verdict == Verdict::Restore)
+            .map(|(role, _)| format!("<@&{}>", role))
+            .collect();
+
+        let message = CreateMessage::new()
+            .content(format!(
+                "<@{}> rejoined and would get back {} roles: {}",
+                member.user_id,
+                roles.len(),
+                roles.join(", "),
+            ))
+            .allowed_mentions(CreateAllowedMentions::new())
+            .components(vec![CreateActionRow::Buttons(vec![
+                CreateButton::new(commands::approval_id(true, member.user_id))
+                    .label("Restore")
+                    .style(ButtonStyle::Success),
+                CreateButton::new(commands::approval_id(false, member.user_id))
+                    .label("Don't restore")
+                    .style(ButtonStyle::Danger),
+            ])]);
+
+        if let Err(error) = channel.send_message(&context.http, message).await {
+            println!("Error asking staff of guild {} for approval: {}", server_id.get(), error);
+            return Ok(true);
+        }
+
+        let (user_id, now) = (member.user_id, self.clock.now());
+        self.writer.write(move |connection| {
+            connection.execute(
+                "INSERT OR REPLACE INTO pending_approvals (user_id, server_id, requested) VALUES (?1, ?2, ?3)",
+                [user_id, server_id.get(), now],
+            )?;
+            Ok(true)
+        }).await
+    }
+
+    // Records staff's decision on restoring a member, returning false if it 
+    // wasn't waiting on one.
+    pub async fn decide_approval(&self, user_id: UserId, server_id: GuildId, approved: bool) -> Result<bool> {
+        self.do_locked((user_id, server_id), || self.decide_approval_locked(user_id, server_id, approved)).await
+    }
+
+    async fn decide_approval_locked(&self, user_id: UserId, server_id: GuildId, approved: bool) -> Result<bool> {
+        self.writer.write(move |connection| {
+            let decided = connection.execute(
+                "UPDATE pending_approvals SET approved=?3 
+                WHERE user_id=?1 AND server_id=?2 AND approved IS NULL",
+                rusqlite::params![user_id.get(), server_id.get(), approved],
+            )?;
+            Ok(decided > 0)
+        }).await
+    }
+
+    async fn resolve_approval(&self, user_id: u64, server_id: u64) -> Result<()> {
+        self.writer.write(move |connection| {
+            connection.execute(
+                "DELETE FROM pending_approvals WHERE user_id=?1 AND server_id=?2",
+                [user_id, server_id],
+            )?;
+            Ok(())
+        }).await
+    }
+
+    // Carries out staff's decision on a member, if they're still around.
+    pub async fn apply_approval(&self, context: &Context, user_id: UserId, server_id: GuildId) {
+        match context.http.get_member(server_id, user_id).await {
+            Ok(member) => self.enqueue_observe(context, member.into(), Origin::Approval),
+            Err(error) => {
+                println!(
+                    "Error fetching member {} in server {} after approval: {}",
+                    user_id.get(),
+                    server_id.get(),
+                    error,
+                );
+                let resolve = || self.resolve_approval(user_id.get(), server_id.get());
+                if let Err(error) = self.do_locked((user_id, server_id), resolve).await {
+                    println!("Error discarding approval for member {}: {}", user_id.get(), error);
+                }
+            },
+        }
+    }
+
+    // Remembers that a member's restore is waiting on membership screening,
+    // so it's still carried out if the bot restarts in between.
+    async fn mark_pending_restore(&self, member: &SimpleMember) -> Result<()> {
+        let member = member.clone();
+        let seen_cache = self.seen_cache.clone();
+        self.writer.write(move |connection| {
+            let marked = connection.execute(
+                "UPDATE last_seen SET pending_restore=1 WHERE user_id=?1 AND server_id=?2",
+                [member.user_id, member.server_id],
+            )?;
+            if marked > 0 {
+                let seen = Self::last_seen_in(connection, &member)?
+                    .map(|time| Seen { time, pending_restore: true });
+                seen_cache.insert(&member, seen);
+            }
+            Ok(())
+        }).await
+    }
+
+    // Deletes what's stored about a member in a guild, returning how many rows
+    // went. They're treated as new the next time they're seen.
+    pub async fn clear_member(&self, user_id: UserId, server_id: GuildId) -> Result<usize> {
+        self.do_locked((user_id, server_id), || self.clear_member_locked(user_id, server_id)).await
+    }
+
+    async fn clear_member_locked(&self, user_id: UserId, server_id: GuildId) -> Result<usize> {
+        let storage = self.storage.clone();
+        let seen_cache = self.seen_cache.clone();
+        self.writer.write(move |connection| {
+            let transaction = connection.transaction()?;
+
+            let roles = storage.forget_member(&transaction, user_id.get(), server_id.get())?;
+
+            let last_seen = transaction.execute(
+                "DELETE FROM last_seen WHERE user_id=?1 AND server_id=?2",
+                [user_id.get(), server_id.get()],
+            )?;
+
+            transaction.execute(
+                "DELETE FROM role_grants WHERE user_id=?1 AND server_id=?2",
+                [user_id.get(), server_id.get()],
+            )?;
+
+            transaction.execute(
+                "DELETE FROM capped_roles WHERE user_id=?1 AND server_id=?2",
+                [user_id.get(), server_id.get()],
+            )?;
+
+            transaction.commit()?;
+            seen_cache.forget_member(user_id.get(), server_id.get());
+            Ok(roles + last_seen)
+        }).await
+    }
+
+    // Whether staff have held a member's roles back from being restored.
+    fn is_held(&self, member: &SimpleMember) -> Result<bool> {
+        let connection = self.data.lock().unwrap();
+        connection.query_row(
+            "SELECT 1 FROM holds WHERE user_id=?1 AND server_id=?2",
+            [member.user_id, member.server_id],
+            |_| Ok(()),
+        ).optional().map(|held| held.is_some())
+    }
+
+    // Holds a member's roles back from being restored or lets them go, 
+    // returning whether that changed anything. A held member's rejoin is 
+    // left unsaved, so their roles are restored by their next event after.
+    pub async fn set_hold(&self, user_id: UserId, server_id: GuildId, held_by: UserId, hold: bool) -> Result<bool> {
+        self.do_locked((user_id, server_id), || self.set_hold_locked(user_id, server_id, held_by, hold)).await
+    }
+
+    async fn set_hold_locked(&self, user_id: UserId, server_id: GuildId, held_by: UserId, hold: bool) -> Result<bool> {
+        let now = self.clock.now();
+        self.writer.write(move |connection| {
+            let changed = if hold {
+                connection.execute(
+                    "INSERT OR IGNORE INTO holds (user_id, server_id, held_by, time) VALUES (?1, ?2, ?3, ?4)",
+                    [user_id.get(), server_id.get(), held_by.get(), now],
+                )?
+            } else {
+                connection.execute(
+                    "DELETE FROM holds WHERE user_id=?1 AND server_id=?2",
+                    [user_id.get(), server_id.get()],
+                )?
+            };
+            Ok(changed > 0)
+        }).await
+    }
+
+    // The members held in a guild, longest held first.
+    pub async fn holds(&self, server_id: GuildId) -> Result<Vec<Hold>> {
+        let connection = self.data.lock().unwrap();
+        let mut holds_query = connection.prepare(
+            "SELECT user_id, held_by, time FROM holds
+            WHERE server_id=?1 ORDER BY time",
+        )?;
+
+        let holds = holds_query.query_map(
+            [server_id.get()],
+            |row| Ok(Hold {
+                user_id: row.get(0)?,
+                held_by: row.get(1)?,
+                time: row.get(2)?,
+            }),
+        )?.collect::<Result<_>>();
+        holds
+    }
+
+    pub async fn record_kick(&self, user_id: UserId, server_id: GuildId) -> Result<()> {
+        self.do_locked((user_id, server_id), || self.record_kick_locked(user_id, server_id)).await
+    }
+
+    async fn record_kick_locked(&self, user_id: UserId, server_id: GuildId) -> Result<()> {
+        let now = self.clock.now();
+        self.writer.write(move |connection| {
+            connection.execute(
+                "REPLACE INTO kicks (user_id, server_id, time) VALUES (?1, ?2, ?3)",
+                [user_id.get(), server_id.get(), now],
+            )?;
+            Ok(())
+        }).await
+    }
+
+    // Whether a rejoining member was kicked when they last left and the 
+    // server doesn't want roles restored for them.
+    async fn skip_kicked(&self, member: &SimpleMember, last_seen: i64) -> Result<bool> {
+        let restore_after_kick = self.config.guild(member.server_id)
+            .is_none_or(|guild| guild.restore_after_kick);
+        if restore_after_kick {
+            return Ok(false);
+        }
+
+        let connection = self.data.lock().unwrap();
+        let mut kick_query = connection.prepare(
+            "SELECT time FROM kicks 
+            WHERE user_id=?1 AND server_id=?2",
+        )?;
+
+        let kicks: Vec<i64> = kick_query.query_map(
+            [member.user_id, member.server_id],
+            |row| row.get(0)
+        )?.collect::<Result<_>>()?;
+
+        // A kick since they were last seen is for this absence, the window 
+        // allows for the audit log entry and our last sighting being close.
+        Ok(kicks.iter().any(|kicked| *kicked >= last_seen - KICK_WINDOW.as_secs() as i64))
+    }
+
+    async fn is_suspicious_loss(&self, member: &SimpleMember) -> Result<bool> {
+        let guard = match &self.config.wipe_guard {
+            Some(guard) => guard,
+            None => return Ok(false),
+        };
+
+        let connection = self.data.lock().unwrap();
+        let stored = self.stored_roles(&connection, member.user_id, member.server_id)?;
+        let lost = stored.iter()
+            .filter(|role| !member.roles.contains(role))
+            .count();
+
+        if stored.len() > guard.min_roles && member.roles.is_empty() {
+            return Ok(true);
+        }
+
+        Ok(match guard.max_loss_percent {
+            Some(percent) => !stored.is_empty() && lost * 100 > stored.len() * percent as usize,
+            None => false,
+        })
+    }
+
+    // Checks the roles a member has with Discord directly, returning true if 
+    // they match what we were told.
+    async fn confirm_roles(&self, context: &Context, member: &SimpleMember) -> bool {
+        let result = context.http.get_member(
+            GuildId::new(member.server_id),
+            UserId::new(member.user_id),
+        ).await;
+
+        match result {
+            Ok(fetched) => fetched.roles.iter().all(|role| member.roles.contains(&role.get())),
+            Err(error) => {
+                println!(
+                    "Error fetching member {} in server {}: {}",
+                    member.user_id,
+                    member.server_id,
+                    error,
+                );
+                false
+            },
+        }
+    }
+
+    // The roles a member has right now, from the cache or otherwise Discord.
+    async fn current_roles(&self, context: &Context, member: &SimpleMember) -> Option<HashSet<u64>> {
+        let server_id = GuildId::new(member.server_id);
+        let user_id = UserId::new(member.user_id);
+
+        let cached = context.cache.guild(server_id)
+            .and_then(|guild| guild.members.get(&user_id).map(|cached| {
+                cached.roles.iter().map(|role| role.get()).collect()
+            }));
+        if cached.is_some() {
+            return cached;
+        }
+
+        match context.http.get_member(server_id, user_id).await {
+            Ok(fetched) => Some(fetched.roles.iter().map(|role| role.get()).collect()),
+            Err(error) => {
+                println!(
+                    "Error fetching roles of member {} in server {}: {}",
+                    member.user_id,
+                    member.server_id,
+                    error,
+                );
+                None
+            },
+        }
+    }
+
+    async fn fetch_joined_at(&self, context: &Context, member: &SimpleMember) -> Option<i64> {
+        let result = context.http.get_member(
+            GuildId::new(member.server_id),
+            UserId::new(member.user_id),
+        ).await;
+
+        match result {
+            Ok(fetched) => fetched.joined_at.map(|time| time.unix_timestamp()),
+            Err(error) => {
+                println!(
+                    "Error fetching member {} in server {}: {}",
+                    member.user_id,
+                    member.server_id,
+                    error,
+                );
+                None
+            },
+        }
+    }
+
+    pub async fn save_guild(&self, context: &Context, server_id: GuildId) -> std::result::Result<usize, Error> {
+        self.sync_guild(context, server_id, Origin::Sync).await.map(|(members, _)| members)
+    }
+
+    // Observes every member of a guild as the given origin, returning how
+    // many there were and how long it took, not counting any wait for a 
+    // turn to sync.
+    async fn sync_guild(
+        &self, 
+        context: &Context, 
+        server_id: GuildId, 
+        origin: Origin,
+    ) -> std::result::Result<(usize, Duration), Error> {
+        // Only closed with the handler, which nothing syncs past.
+        let _permit = self.sync_permits.acquire().await;
+        let start = Instant::now();
+        let members = match self.fetch_guild(context, server_id, origin).await {
+            Ok(members) => members,
+            Err(error) => {
+                match retry::classify(&error) {
+                    retry::Failure::Transient => {
+                        println!("Syncing guild {} will be retried in the background", server_id.get());
+                        self.failed_syncs.lock().await.insert(server_id, context.clone());
+                    },
+                    retry::Failure::Forbidden => println!(
+                        "Not allowed to list the members of guild {}, check the Server Members intent \
+                        is enabled and the bot can view the server",
+                        server_id.get(),
+                    ),
+                    retry::Failure::Permanent => (),
+                }
+                return Err(error.into());
+            },
+        };
+        self.record_sync(server_id).await?;
+
+        let elapsed = start.elapsed();
+        println!("Synced {} members of guild {} in {:.1}s", members, server_id.get(), elapsed.as_secs_f64());
+        if elapsed.as_secs() >= self.config.slow_sync_warning {
+            println!("Warning: syncing guild {} took {}s", server_id.get(), elapsed.as_secs());
+        }
+
+        Ok((members, elapsed))
+    }
+
+    // Fetches every member of a guild, returning how many there were.
+    async fn fetch_guild(&self, context: &Context, server_id: GuildId, origin: Origin) -> std::result::Result<usize, serenity::Error> {
+        // Small guilds fit in a page or two, which is simpler than chunking.
+        let member_count = context.cache.guild(server_id).map(|guild| guild.member_count);
+        if member_count.is_some_and(|count| count < self.config.chunk_sync_threshold) {
+            return self.save_guild_rest(context, server_id, origin).await;
+        }
+
+        let nonce = format!("{}", self.chunk_nonce.fetch_add(1, Ordering::Relaxed));
+        let (progress, mut receiver) = mpsc::unbounded_channel();
+
+        self.chunk_syncs.lock().await.insert(server_id, ChunkSync {
+            nonce: nonce.clone(),
+            origin,
+            received: 0,
+            expected: None,
+            members: 0,
+            progress,
+        });
+
+        self.shard_messenger(context, server_id).await.chunk_guild(
+            server_id, 
+            None, 
+            false, 
+            ChunkGuildFilter::None, 
+            Some(nonce),
+        );
+
+        loop {
+            let result = tokio::time::timeout(CHUNK_TIMEOUT, receiver.recv()).await;
+            let mut syncs = self.chunk_syncs.lock().await;
+
+            match result {
+                Ok(Some(())) => {
+                    if syncs.get(&server_id).is_none_or(ChunkSync::is_complete) {
+                        let sync = syncs.remove(&server_id);
+                        return Ok(sync.map_or(0, |sync| sync.members));
+                    }
+                },
+                _ => {
+                    let sync = syncs.remove(&server_id);
+                    std::mem::drop(syncs);
+
+                    println!(
+                        "Timed out waiting for member chunks of guild {} ({} of {:?} received), falling back to REST",
+                        server_id.get(),
+                        sync.as_ref().map_or(0, |sync| sync.received),
+                        sync.as_ref().and_then(|sync| sync.expected),
+                    );
+                    return self.save_guild_rest(context, server_id, origin).await;
+                },
+            }
+        }
+    }
+
+    async fn shard_messenger(&self, context: &Context, server_id: GuildId) -> ShardMessenger {
+        let shard_id = shard_for(server_id, self.shard_count.load(Ordering::Relaxed));
+        self.shards.lock().await.get(&shard_id)
+            .cloned()
+            .unwrap_or_else(|| context.shard.clone())
+    }
+
+    async fn save_guild_rest(&self, context: &Context, server_id: GuildId, origin: Origin) -> std::result::Result<usize, serenity::Error> {
+        let mut after = None;
+        let mut total = 0;
+
+        loop {
+            let members = retry::with_backoff(|| context.http.get_guild_members(
+                server_id, 
+                Some(MEMBER_PAGE_SIZE), 
+                after,
+            )).await?;
+
+            let page_size = members.len();
+            total += page_size;
+            after = members.last().map(|member| member.user.id.get());
+
+            for member in members {
+                self.enqueue_observe(context, member.into(), origin);
+            }
+
+            if page_size < MEMBER_PAGE_SIZE as usize {
+                break;
+            }
+        }
+
+        println!("Fetched {} members of guild {}", total, server_id.get());
+        Ok(total)
+    }
+
+    // What the members in a chunk are observed as, None if no sync asked
+    // for it.
+    async fn chunk_origin(&self, chunk: &GuildMembersChunkEvent) -> Option<Origin> {
+        let syncs = self.chunk_syncs.lock().await;
+        match (syncs.get(&chunk.guild_id), &chunk.nonce) {
+            (Some(sync), Some(nonce)) if sync.nonce == *nonce => Some(sync.origin),
+            _ => None,
+        }
+    }
+
+    async fn record_chunk(&self, chunk: &GuildMembersChunkEvent) {
+        let mut syncs = self.chunk_syncs.lock().await;
+        if let Some(sync) = syncs.get_mut(&chunk.guild_id) {
+            if chunk.nonce.as_ref() == Some(&sync.nonce) {
+                sync.received += 1;
+                sync.expected = Some(chunk.chunk_count);
+                sync.members += chunk.members.len();
+                // The receiver is only gone if the sync has already timed out.
+                let _ = sync.progress.send(());
+            }
+        }
+    }
+
+    async fn last_sync(&self, server_id: GuildId) -> Result<Option<u64>> {
+        let connection = self.data.lock().unwrap();
+        let mut last_sync_query = connection.prepare(
+            "SELECT last_sync FROM guild_settings 
+            WHERE server_id=?1",
+        )?;
+
+        let last_sync: Vec<Option<u64>> = last_sync_query.query_map(
+            [server_id.get()],
+            |row| row.get(0)
+        )?.collect::<Result<_>>()?;
+
+        Ok(last_sync.first().copied().flatten())
+    }
+
+    async fn record_sync(&self, server_id: GuildId) -> Result<()> {
+        let now = self.clock.now();
+        self.writer.write(move |connection| {
+            connection.execute(
+                "INSERT INTO guild_settings (server_id, last_sync) VALUES (?1, ?2)
+                ON CONFLICT(server_id) DO UPDATE SET last_sync=excluded.last_sync",
+                [server_id.get(), now],
+            )?;
+            Ok(())
+        }).await
+    }
+
+    // Sets whether restores in a guild are only logged, None goes back to 
+    // what's configured.
+    pub async fn set_dry_run(&self, server_id: GuildId, dry_run: Option<bool>) -> Result<()> {
+        self.writer.write(move |connection| {
+            connection.execute(
+                "INSERT INTO guild_settings (server_id, dry_run) VALUES (?1, ?2)
+                ON CONFLICT(server_id) DO UPDATE SET dry_run=excluded.dry_run",
+                rusqlite::params![server_id.get(), dry_run],
+            )?;
+            Ok(())
+        }).await
+    }
+
+    async fn stored_dry_run(&self, server_id: u64) -> Result<Option<bool>> {
+        let connection = self.data.lock().unwrap();
+        let mut dry_run_query = connection.prepare(
+            "SELECT dry_run FROM guild_settings 
+            WHERE server_id=?1",
+        )?;
+
+        let dry_run: Vec<Option<bool>> = dry_run_query.query_map(
+            [server_id],
+            |row| row.get(0)
+        )?.collect::<Result<_>>()?;
+
+        Ok(dry_run.first().copied().flatten())
+    }
+
+    // Whether restores in a guild are worked out and logged but not done, as
+    // set by command or otherwise the config.
+    pub async fn is_dry_run(&self, server_id: u64) -> bool {
+        let configured = self.config.guild(server_id).is_some_and(|guild| guild.dry_run);
+        match self.stored_dry_run(server_id).await {
+            Ok(stored) => stored.unwrap_or(configured),
+            Err(error) => {
+                println!("Error reading dry run setting of server {}: {}", server_id, error);
+                configured
+            },
+        }
+    }
+
+    // The roles a guild has marked sticky, if only those are persisted there.
+    fn sticky_roles_in(connection: &Connection, server_id: u64) -> Result<Option<HashSet<u64>>> {
+        let mut mode_query = connection.prepare(
+            "SELECT sticky_only FROM guild_settings 
+            WHERE server_id=?1",
+        )?;
+
+        let sticky_only: Vec<Option<bool>> = mode_query.query_map(
+            [server_id],
+            |row| row.get(0)
+        )?.collect::<Result<_>>()?;
+
+        if !sticky_only.first().copied().flatten().unwrap_or(false) {
+            return Ok(None);
+        }
+
+        Self::sticky_roles(connection, server_id).map(|roles| Some(roles.into_iter().collect()))
+    }
+
+    fn sticky_roles(connection: &Connection, server_id: u64) -> Result<Vec<u64>> {
+        let mut roles_query = connection.prepare(
+            "SELECT role_id FROM sticky_roles 
+            WHERE server_id=?1",
+        )?;
+
+        let roles = roles_query.query_map(
+            [server_id],
+            |row| row.get(0)
+        )?.collect::<Result<_>>();
+        roles
+    }
+
+    // Marks a role as sticky in its guild or not, returning whether that 
+    // changed anything.
+    pub async fn set_sticky(&self, server_id: GuildId, role_id: RoleId, sticky: bool) -> Result<bool> {
+        self.writer.write(move |connection| {
+            let changed = if sticky {
+                connection.execute(
+                    "INSERT OR IGNORE INTO sticky_roles (server_id, role_id) VALUES (?1, ?2)",
+                    [server_id.get(), role_id.get()],
+                )?
+            } else {
+                connection.execute(
+                    "DELETE FROM sticky_roles WHERE server_id=?1 AND role_id=?2",
+                    [server_id.get(), role_id.get()],
+                )?
+            };
+            Ok(changed > 0)
+        }).await
+    }
+
+    // Sets whether only sticky roles are stored and restored in a guild.
+    pub async fn set_sticky_only(&self, server_id: GuildId, sticky_only: bool) -> Result<()> {
+        self.writer.write(move |connection| {
+            connection.execute(
+                "INSERT INTO guild_settings (server_id, sticky_only) VALUES (?1, ?2)
+                ON CONFLICT(server_id) DO UPDATE SET sticky_only=excluded.sticky_only",
+                rusqlite::params![server_id.get(), sticky_only],
+            )?;
+            Ok(())
+        }).await
+    }
+
+    // Whether a guild only persists sticky roles, and which roles are sticky.
+    pub async fn sticky_settings(&self, server_id: GuildId) -> Result<(bool, Vec<u64>)> {
+        let connection = self.data.lock().unwrap();
+        let sticky_only = Self::sticky_roles_in(&connection, server_id.get())?.is_some();
+        Ok((sticky_only, Self::sticky_roles(&connection, server_id.get())?))
+    }
+
+    async fn needs_sync(&self, server_id: GuildId) -> Result<bool> {
+        if self.chunk_syncs.lock().await.contains_key(&server_id) {
+            return Ok(false);
+        }
+
+        Ok(match self.last_sync(server_id).await? {
+            Some(last_sync) => self.clock.now().saturating_sub(last_sync) >= RECENT_SYNC_INTERVAL.as_secs(),
+            None => true,
+        })
+    }
+
+    // Puts off forgetting a guild the bot was removed from, in case it was 
+    // removed by accident and gets added back.
+    async fn schedule_forget(&self, server_id: GuildId, grace: u64) -> Result<()> {
+        let forget_at = self.clock.now() + grace;
+        self.writer.write(move |connection| {
+            connection.execute(
+                "INSERT INTO guild_settings (server_id, forget_at) VALUES (?1, ?2)
+                ON CONFLICT(server_id) DO UPDATE SET forget_at=excluded.forget_at",
+                [server_id.get(), forget_at],
+            )?;
+            Ok(())
+        }).await
+    }
+
+    // When a guild's data is due to be forgotten, if it is.
+    async fn forget_at(&self, server_id: GuildId) -> Result<Option<u64>> {
+        let connection = self.data.lock().unwrap();
+        let mut forget_query = connection.prepare(
+            "SELECT forget_at FROM guild_settings 
+            WHERE server_id=?1",
+        )?;
+
+        let forget_at: Vec<Option<u64>> = forget_query.query_map(
+            [server_id.get()],
+            |row| row.get(0)
+        )?.collect::<Result<_>>()?;
+
+        Ok(forget_at.first().copied().flatten())
+    }
+
+    // Keeps a guild's data after all, returning false if it wasn't going to 
+    // be forgotten.
+    pub async fn cancel_forget(&self, server_id: GuildId) -> Result<bool> {
+        self.writer.write(move |connection| {
+            let cancelled = connection.execute(
+                "UPDATE guild_settings SET forget_at=NULL 
+                WHERE server_id=?1 AND forget_at IS NOT NULL",
+                [server_id.get()],
+            )?;
+            Ok(cancelled > 0)
+        }).await
+    }
+
+    // Forgets guilds whose grace period has run out.
+    async fn forget_due_guilds(&self) -> Result<()> {
+        let due: Vec<u64> = {
+            let connection = self.data.lock().unwrap();
+            let mut due_query = connection.prepare(
+                "SELECT server_id FROM guild_settings 
+                WHERE forget_at<=?1",
+            )?;
+
+            let due = due_query.query_map(
+                [self.clock.now()],
+                |row| row.get(0)
+            )?.collect::<Result<_>>()?;
+            due
+        };
+
+        for server_id in due {
+            if self.guilds.lock().await.contains(&GuildId::new(server_id)) {
+                println!(
+                    "WARNING: grace period for guild {} is over, forgetting its data even though \
+                    the bot was added back to it",
+                    server_id,
+                );
+            } else {
+                println!("Grace period for guild {} is over, forgetting its data", server_id);
+            }
+            self.forget_guild(GuildId::new(server_id)).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn forget_guild(&self, server_id: GuildId) -> Result<()> {
+        let storage = self.storage.clone();
+        let seen_cache = self.seen_cache.clone();
+        self.writer.write(move |connection| {
+            let transaction = connection.transaction()?;
+
+            storage.forget_guild(&transaction, server_id.get())?;
+
+            transaction.execute(
+                "DELETE FROM stats_history WHERE server_id=?",
+                [server_id.get()],
+            )?;
+
+            transaction.execute(
+                "DELETE FROM last_seen WHERE server_id=?",
+                [server_id.get()],
+            )?;
+
+            transaction.execute(
+                "DELETE FROM manual_removals WHERE server_id=?",
+                [server_id.get()],
+            )?;
+
+            transaction.execute(
+                "DELETE FROM capped_roles WHERE server_id=?",
+                [server_id.get()],
+            )?;
+
+            transaction.execute(
+                "DELETE FROM kicks WHERE server_id=?",
+                [server_id.get()],
+            )?;
+
+            transaction.execute(
+                "DELETE FROM holds WHERE server_id=?",
+                [server_id.get()],
+            )?;
+
+            transaction.execute(
+                "DELETE FROM role_grants WHERE server_id=?",
+                [server_id.get()],
+            )?;
+
+            transaction.execute(
+                "DELETE FROM role_removals WHERE server_id=?",
+                [server_id.get()],
+            )?;
+
+            transaction.execute(
+                "DELETE FROM restore_failures WHERE server_id=?",
+                [server_id.get()],
+            )?;
+
+            transaction.execute(
+                "DELETE FROM guild_settings WHERE server_id=?",
+                [server_id.get()],
+            )?;
+
+            transaction.execute(
+                "DELETE FROM sticky_roles WHERE server_id=?",
+                [server_id.get()],
+            )?;
+
+            transaction.execute(
+                "DELETE FROM pending_approvals WHERE server_id=?",
+                [server_id.get()],
+            )?;
+
+            transaction.commit()?;
+            seen_cache.forget_guild(server_id.get());
+            Ok(())
+        }).await
+    }
+
+    async fn stored_servers(&self) -> Result<Vec<GuildId>> {
+        let connection = self.data.lock().unwrap();
+        let mut servers_query = connection.prepare("SELECT DISTINCT server_id FROM last_seen")?;
+
+        let mut servers: HashSet<u64> = servers_query.query_map(
+            [],
+            |row| row.get(0)
+        )?.collect::<Result<_>>()?;
+        servers.extend(self.storage.guilds(&connection)?);
+
+        Ok(servers.into_iter().map(GuildId::new).collect())
+    }
+
+    // Stored guilds which aren't among the given current ones.
+    pub async fn orphaned_guilds(
+        &self,
+        current: &HashSet<GuildId>,
+        filter: impl Fn(&GuildId) -> bool,
+    ) -> Result<Vec<GuildId>> {
+        let orphans = self.stored_servers().await?.into_iter()
+            .filter(filter)
+            .filter(|id| !current.contains(id))
+            .collect();
+        Ok(orphans)
+    }
+
+    // Reports stored guilds the bot was removed from while offline, only 
+    // deleting their data if configured to. A database moved between bot
+    // applications would otherwise be wiped on the first start.
+    pub async fn reconcile_guilds(&self, ready: &Ready) -> Result<()> {
+        let current: HashSet<GuildId> = ready.guilds.iter()
+            .map(|guild| guild.id)
+            .collect();
+
+        // Each shard is only told about its own guilds, so only consider 
+        // stored guilds which would have been sent to this shard.
+        let on_this_shard = |id: &GuildId| match ready.shard {
+            Some(shard) => shard_for(*id, shard.total) == shard.id.0,
+            None => true,
+        };
+
+        for server_id in self.orphaned_guilds(&current, on_this_shard).await? {
+            if self.config.prune_orphans {
+                println!("Pruning data for guild {} which the bot is no longer in", server_id.get());
+                self.forget_guild(server_id).await?;
+            } else {
+                println!(
+                    "Guild {} has stored data but the bot is no longer in it, \
+                    run `rolepersist db orphans --purge` to remove it",
+                    server_id.get(),
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn initial_sync(&self, context: &Context) {
+        // Wait until every shard has connected and the cache has every guild
+        // available, otherwise the guild list (and role data) is incomplete.
+        if !self.shards_ready.load(Ordering::Relaxed) {
+            return;
+        }
+
+        // Without the GUILDS intent guilds never become available, the list
+        // from the ready event is all there will be.
+        let has_guilds = self.config.intents.contains(GatewayIntents::GUILDS);
+        if has_guilds && context.cache.unavailable_guilds().len() != 0 {
+            self.wait_for_unavailable(context);
+            return;
+        }
+
+        self.run_initial_sync(context).await;
+    }
+
+    // Gives guilds which are unavailable at startup unavailable_guild_wait 
+    // seconds to come back, after which the initial sync goes ahead without
+    // any still missing so one outage doesn't hold up every other guild.
+    fn wait_for_unavailable(&self, context: &Context) {
+        if self.initial_sync_waiting.swap(true, Ordering::Relaxed) {
+            return;
+        }
+
+        let handler = match self.this.get().and_then(Weak::upgrade) {
+            Some(handler) => handler,
+            None => return,
+        };
+
+        let context = context.clone();
+        let wait = Duration::from_secs(self.config.unavailable_guild_wait);
+        tokio::spawn(async move {
+            tokio::time::sleep(wait).await;
+            handler.run_initial_sync(&context).await;
+        });
+    }
+
+    async fn run_initial_sync(&self, context: &Context) {
+        // Held until the sync is marked done, so guilds coming back meanwhile
+        // are either synced with the rest or found to be late.
+        let mut late_guilds = self.late_guilds.lock().await;
+        if self.initial_sync_done.swap(true, Ordering::Relaxed) {
+            return;
+        }
+
+        let unavailable: HashSet<GuildId> = context.cache.unavailable_guilds().iter()
+            .map(|guild| *guild.key())
+            .filter(|id| self.filter_allow_server(*id))
+            .collect();
+        if !unavailable.is_empty() {
+            println!(
+                "Starting the initial sync without {} unavailable guilds, they're synced once they're back",
+                unavailable.len(),
+            );
+        }
+        let guilds: Vec<GuildId> = context.cache.guilds().into_iter()
+            .filter(|id| self.filter_allow_server(*id) && !unavailable.contains(id))
+            .collect();
+        *late_guilds = unavailable;
+        std::mem::drop(late_guilds);
+
+        let start = Instant::now();
+        let restored_before = self.stats.restored.load(Ordering::Relaxed);
+        let errors_before = self.stats.errors.load(Ordering::Relaxed);
+        let mut synced = 0;
+        let mut members = 0;
+        let mut errors = 0;
+
+        // Up to sync_concurrency guilds are synced at once, so small guilds
+        // aren't stuck waiting behind a huge one.
+        let mut durations: Vec<(GuildId, Duration)> = vec![];
+        let mut syncs = futures::stream::iter(guilds.iter().copied())
+            .map(|server_id| async move {
+                (server_id, self.sync_guild(context, server_id, Origin::Sync).await)
+            })
+            .buffer_unordered(guilds.len().max(1));
+        while let Some((server_id, result)) = syncs.next().await {
+            match result {
+                Ok((count, elapsed)) => {
+                    synced += 1;
+                    members += count;
+                    durations.push((server_id, elapsed));
+                },
+                Err(error) => {
+                    errors += 1;
+                    println!("Error syncing guild {}: {}", server_id.get(), error);
+                },
+            }
+        }
+        std::mem::drop(syncs);
+
+        // Members are observed by the workers, so wait for them to catch up.
+        self.wait_idle().await;
+        self.initial_sync_settled.store(true, Ordering::Relaxed);
+
+        println!(
+            "Initial sync done in {}s: {} of {} guilds synced, {} members observed, {} roles restored, {} restores queued, {} errors",
+            start.elapsed().as_secs(),
+            synced,
+            guilds.len(),
+            members,
+            self.stats.restored.load(Ordering::Relaxed) - restored_before,
+            self.restore_backlog.lock().await.len(),
+            errors + self.stats.errors.load(Ordering::Relaxed) - errors_before,
+        );
+        log_sync_durations(&mut durations);
+    }
+
+    pub async fn resync_shard(&self, context: &Context) {
+        let shard_count = self.shard_count.load(Ordering::Relaxed);
+        let now = Instant::now();
+
+        let dirty: Vec<GuildId> = {
+            let guilds = self.guilds.lock().await;
+            let mut reconnect_syncs = self.reconnect_syncs.lock().await;
+
+            guilds.iter()
+                .filter(|id| shard_for(**id, shard_count) == context.shard_id.0)
+                .filter(|id| {
+                    let recently_synced = reconnect_syncs.get(id)
+                        .is_some_and(|last| now.duration_since(*last) < RECONNECT_RESYNC_INTERVAL);
+                    if !recently_synced {
+                        reconnect_syncs.insert(**id, now);
+                    }
+                    !recently_synced
+                })
+                .copied()
+                .collect()
+        };
+
+        futures::stream::iter(dirty).for_each_concurrent(None, |server_id| async move {
+            println!("Resyncing guild {} after reconnecting", server_id.get());
+            if let Err(error) = self.save_guild(context, server_id).await {
+                println!("Error syncing guild {}: {}", server_id.get(), error);
+            }
+        }).await;
+    }
+
+    pub fn enqueue(&self, context: &Context, server_id: GuildId, task: Task) {
+        if self.queues_closed.load(Ordering::Relaxed) {
+            return;
+        }
+
+        self.guild_queue(server_id).push(Work { context: context.clone(), task });
+    }
+
+    // Restores a member through their guild's worker, so it doesn't race 
+    // their events.
+    pub fn enqueue_restore(&self, context: &Context, member: SimpleMember) {
+        self.enqueue(context, GuildId::new(member.server_id), Task::Restore(member));
+    }
+
+    pub fn enqueue_observe(&self, context: &Context, member: SimpleMember, origin: Origin) {
+        self.enqueue(context, GuildId::new(member.server_id), Task::Observe { member, origin });
+    }
+
+    // The queue for a guild's work, starting a worker for it if there isn't 
+    // one yet.
+    fn guild_queue(&self, server_id: GuildId) -> Arc<WorkQueue> {
+        if let Some(queue) = self.queues.get(&server_id) {
+            return queue.clone();
+        }
+
+        self.queues.entry(server_id).or_insert_with(|| {
+            let queue = Arc::new(WorkQueue::new(QUEUE_CAPACITY));
+            self.spawn_worker(server_id, queue.clone());
+            queue
+        }).clone()
+    }
+
+    fn spawn_worker(&self, server_id: GuildId, queue: Arc<WorkQueue>) {
+        // Checked holding the lock start_workers takes after setting this, so
+        // the queue is either started here or left where it'll find it.
+        let mut unstarted = self.unstarted_queues.lock().unwrap();
+        let handler = match self.this.get().and_then(Weak::upgrade) {
+            Some(handler) => handler,
+            None => {
+                unstarted.push((server_id, queue));
+                return;
+            },
+        };
+        std::mem::drop(unstarted);
+
+        let worker = tokio::spawn(async move {
+            while let Some(work) = queue.pop().await {
+                // Errors are handled within, this is a last resort so a 
+                // bug with one event doesn't stop the worker entirely.
+                let result = AssertUnwindSafe(handler.work(work)).catch_unwind().await;
+                if result.is_err() {
+                    println!("Worker for guild {} panicked while processing an event", server_id.get());
+                }
+                queue.finish();
+            }
+
+            // A new queue may have been started for the guild since this 
+            // one was closed, which should be left alone.
+            handler.queues.remove_if(&server_id, |_, current| Arc::ptr_eq(current, &queue));
+        });
+
+        self.workers.lock().unwrap().push(worker);
+    }
+
+    // Stops a guild's worker once it has finished what's queued.
+    fn close_queue(&self, server_id: GuildId) {
+        if let Some(queue) = self.queues.get(&server_id) {
+            queue.close();
+        }
+    }
+
+    // Carries out restores held back by syncs at the configured rate, most 
+    // recent joins first. Nothing is taken from the backlog until the initial
+    // sync has finished finding everyone.
+    pub fn start_restore_backlog(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let handler = self.clone();
+        tokio::spawn(async move {
+            let rate = handler.config.sync_restores_per_second.max(1);
+            let mut interval = tokio::time::interval(Duration::from_secs(1) / rate);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                if !handler.initial_sync_settled.load(Ordering::Relaxed) {
+                    continue;
+                }
+
+                let work = {
+                    let mut backlog = handler.restore_backlog.lock().await;
+                    let latest = backlog.iter()
+                        .enumerate()
+                        .max_by_key(|(_, work)| match &work.task {
+                            Task::Observe { member, .. } => member.joined_at,
+                            _ => None,
+                        })
+                        .map(|(index, _)| index);
+                    latest.map(|index| backlog.swap_remove(index))
+                };
+
+                if let Some(Work { context, task: Task::Observe { member, origin } }) = work {
+                    handler.enqueue_observe(&context, member, origin);
+                }
+            }
+        })
+    }
+
+    // Lets guild workers be started as work for each guild arrives, starting
+    // them for any which already has, and starts the workers adding roles.
+    pub fn start_workers(self: &Arc<Self>) -> Vec<tokio::task::JoinHandle<()>> {
+        let _ = self.this.set(Arc::downgrade(self));
+        let unstarted = std::mem::take(&mut *self.unstarted_queues.lock().unwrap());
+        for (server_id, queue) in unstarted {
+            self.spawn_worker(server_id, queue);
+        }
+        self.role_queue.start(ROLE_ADD_WORKERS)
+    }
+
+    pub fn set_shard_manager(&self, shard_manager: Arc<ShardManager>) {
+        *self.shard_manager.write().unwrap() = Some(shard_manager);
+    }
+
+    fn current_shard_manager(&self) -> Option<Arc<ShardManager>> {
+        self.shard_manager.read().unwrap().clone()
+    }
+
+    // Disconnects the shards of whichever client is currently running.
+    pub async fn shutdown_shards(&self) {
+        if let Some(manager) = self.current_shard_manager() {
+            manager.shutdown_all().await;
+        }
+    }
+
+    // Registers the periodic maintenance jobs, which start running once all
+    // shards are ready.
+    pub fn start_jobs(self: &Arc<Self>) -> Vec<tokio::task::JoinHandle<()>> {
+        let handler = self.clone();
+        let compaction = self.scheduler.spawn(
+            "compact_locks",
+            self.config.job_interval("compact_locks", LOCK_COMPACTION_INTERVAL),
+            move || {
+                let handler = handler.clone();
+                async move {
+                    let (size, capacity) = handler.compact_locks().await;
+                    println!("Member lock map: {} entries, capacity {}", size, capacity);
+                    Ok(())
+                }
+            },
+        );
+
+        let handler = self.clone();
+        let status = self.scheduler.spawn(
+            "status",
+            self.config.job_interval("status", SHARD_MONITOR_INTERVAL),
+            move || {
+                let handler = handler.clone();
+                async move {
+                    handler.check_shards().await;
+                    handler.log_jobs();
+                    handler.log_saves();
+                    Ok(())
+                }
+            },
+        );
+
+        let handler = self.clone();
+        let sync_retry = self.scheduler.spawn(
+            "retry_syncs",
+            self.config.job_interval("retry_syncs", SYNC_RETRY_INTERVAL),
+            move || {
+                let handler = handler.clone();
+                async move {
+                    handler.retry_syncs().await;
+                    Ok(())
+                }
+            },
+        );
+
+        let handler = self.clone();
+        let forget = self.scheduler.spawn(
+            "forget_guilds",
+            self.config.job_interval("forget_guilds", FORGET_CHECK_INTERVAL),
+            move || {
+                let handler = handler.clone();
+                async move {
+                    handler.forget_due_guilds().await.map_err(|error| error.to_string())
+                }
+            },
+        );
+
+        let handler = self.clone();
+        let stats = self.scheduler.spawn(
+            "snapshot_stats",
+            self.config.job_interval("snapshot_stats", STATS_SNAPSHOT_INTERVAL),
+            move || {
+                let handler = handler.clone();
+                async move {
+                    handler.snapshot_stats().await.map_err(|error| error.to_string())
+                }
+            },
+        );
+
+        vec![compaction, status, sync_retry, forget, stats]
+    }
+
+    // Stores how many members each guild has tracked and what was done there
+    // since the last snapshot, dropping snapshots older than
+    // stats_history_days.
+    async fn snapshot_stats(&self) -> Result<()> {
+        let now = self.clock.now();
+        let mut counts = self.stats.take_guild_counts();
+        let history_days = self.config.stats_history_days;
+        self.writer.write(move |connection| {
+            let transaction = connection.transaction()?;
+
+            let members: Vec<(u64, u64)> = {
+                let mut members_query = transaction.prepare(
+                    "SELECT server_id, COUNT(*) FROM last_seen GROUP BY server_id",
+                )?;
+                let members = members_query.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                    .collect::<Result<_>>()?;
+                members
+            };
+
+            let mut snapshots: Vec<(u64, u64, GuildCounts)> = members.into_iter()
+                .map(|(server_id, members)| (server_id, members, counts.remove(&server_id).unwrap_or_default()))
+                .collect();
+            snapshots.extend(counts.into_iter().map(|(server_id, counts)| (server_id, 0, counts)));
+
+            for (server_id, members, counts) in snapshots {
+                transaction.execute(
+                    "INSERT INTO stats_history (server_id, time, members, restored, errors) VALUES (?1, ?2, ?3, ?4, ?5)",
+                    [server_id, now, members, counts.restored, counts.errors],
+                )?;
+            }
+
+            transaction.execute(
+                "DELETE FROM stats_history WHERE time<?",
+                [now.saturating_sub(history_days * 24 * 60 * 60)],
+            )?;
+
+            transaction.commit()
+        }).await
+    }
+
+    // A guild's stats snapshots from the last given number of days, counting
+    // today, oldest first.
+    pub async fn stats_history(&self, server_id: u64, days: u64) -> Result<Vec<StatsSnapshot>> {
+        const DAY: u64 = 24 * 60 * 60;
+        let since = (self.clock.now() / DAY + 1).saturating_sub(days) * DAY;
+
+        let connection = self.data.lock().unwrap();
+        let mut history_query = connection.prepare(
+            "SELECT time, members, restored, errors FROM stats_history
+            WHERE server_id=?1 AND time>=?2
+            ORDER BY time",
+        )?;
+
+        let history = history_query.query_map(
+            [server_id, since],
+            |row| Ok(StatsSnapshot {
+                time: row.get(0)?,
+                members: row.get(1)?,
+                restored: row.get(2)?,
+                errors: row.get(3)?,
+            }),
+        )?.collect::<Result<_>>();
+        history
+    }
+
+    // Tries syncing guilds whose last sync failed again, any which fail again
+    // are put back for the next run.
+    async fn retry_syncs(&self) {
+        let failed = std::mem::take(&mut *self.failed_syncs.lock().await);
+
+        futures::stream::iter(failed).for_each_concurrent(None, |(server_id, context)| async move {
+            if !self.guilds.lock().await.contains(&server_id) {
+                return;
+            }
+
+            println!("Retrying sync of guild {}", server_id.get());
+            if let Err(error) = self.save_guild(&context, server_id).await {
+                println!("Error syncing guild {}: {}", server_id.get(), error);
+            }
+        }).await;
+    }
+
+    // Drops locks nobody holds any more and gives back the space they used, 
+    // returning the remaining entry count and capacity.
+    pub async fn compact_locks(&self) -> (usize, usize) {
+        // Anything left behind by work cancelled while holding a lock.
+        self.member_locks.retain(|_, lock| Arc::strong_count(lock) > 1);
+        self.member_locks.shrink_to_fit();
+        (self.member_locks.len(), self.member_locks.capacity())
+    }
+
+    // Logs the status of each shard, warning about any which have been 
+    // disconnected for too long.
+    async fn check_shards(&self) {
+        if let Some(manager) = self.current_shard_manager() {
+            let runners = manager.runners.lock().await;
+            let mut statuses = self.shard_status.lock().await;
+            for (id, runner) in runners.iter() {
+                if let Some(status) = statuses.get_mut(&id.0) {
+                    status.latency = runner.latency;
+                }
+            }
+        }
+
+        let mut statuses = self.shard_status.lock().await;
+        for (id, status) in statuses.iter_mut() {
+            println!(
+                "Shard {}: {} for {}s, {} guilds, latency {:?}",
+                id,
+                status.stage,
+                status.since.elapsed().as_secs(),
+                status.guilds,
+                status.latency,
+            );
+
+            let down = status.stage != ConnectionStage::Connected;
+            if down && !status.warned && status.since.elapsed() > SHARD_DOWN_WARNING {
+                println!(
+                    "Warning: shard {} has not been connected for {}s",
+                    id,
+                    status.since.elapsed().as_secs(),
+                );
+                status.warned = true;
+            }
+        }
+    }
+
+    fn log_saves(&self) {
+        println!(
+            "Saves: {} written, {} skipped with nothing changed",
+            self.stats.saves.load(Ordering::Relaxed),
+            self.stats.unchanged_saves.load(Ordering::Relaxed),
+        );
+    }
+
+    fn log_jobs(&self) {
+        for (name, status) in self.scheduler.statuses() {
+            let last_run = status.last_run
+                .map(|time| format!("{}s ago", time.elapsed().as_secs()))
+                .unwrap_or_else(|| "never".to_string());
+            println!(
+                "Job {}: {} runs, last {}{}{}",
+                name,
+                status.runs,
+                last_run,
+                if status.running { ", running" } else { "" },
+                status.last_error.map(|error| format!(", last error: {}", error)).unwrap_or_default(),
+            );
+        }
+    }
+
+    // Members' work is done holding their lock, so nothing else changing 
+    // their data, such as /rolepersist clear, lands partway through it.
+    async fn work(&self, work: Work) {
+        let context = &work.context;
+        match work.task {
+            Task::Observe { member, origin: Origin::Debounced } => {
+                self.do_locked(member.lock_key(), || self.observe_flushed(&member)).await
+            },
+            Task::Observe { mut member, origin } => self.observe_member(context, &mut member, origin).await,
+            Task::Save(member) if !self.persists(&member) => {},
+            Task::Save(member) => {
+                if let Err(error) = self.do_locked(member.lock_key(), || self.save_observed(context, &member, false)).await {
+                    println!(
+                        "Error saving member {} in server {}: {}",
+                        member.user_id,
+                        member.server_id,
+                        error,
+                    );
+                }
+            },
+            Task::Restore(mut member) => {
+                if let Err(error) = self.restore_member(context, &mut member).await {
+                    println!(
+                        "Error restoring member {} in server {}: {}",
+                        member.user_id,
+                        member.server_id,
+                        error,
+                    );
+                }
+            },
+            Task::ForgetGuild(server_id) => {
+                if let Err(error) = self.forget_guild(server_id).await {
+                    println!("Error forgetting guild {}: {}", server_id.get(), error);
+                }
+            },
+        }
+    }
+
+    // Counts a join towards the guild's join rate, putting it in raid mode if
+    // there are too many.
+    pub async fn track_join(&self, context: &Context, server_id: GuildId) {
+        let config = match &self.config.raid {
+            Some(config) => config,
+            None => return,
+        };
+
+        let now = Instant::now();
+        let mut raids = self.raids.lock().await;
+        let raid = raids.entry(server_id).or_default();
+
+        raid.joins.push_back(now);
+        while raid.joins.front().is_some_and(|join| now.duration_since(*join) > RAID_WINDOW) {
+            raid.joins.pop_front();
+        }
+
+        if raid.joins.len() < config.joins_per_minute {
+            return;
+        }
+
+        let engaged = raid.until.is_none();
+        raid.until = Some(now + Duration::from_secs(config.cooldown));
+        std::mem::drop(raids);
+
+        if engaged {
+            println!("Raid mode engaged in guild {}", server_id.get());
+            self.notify_staff(
+                context,
+                server_id,
+                "Raid mode engaged: a flood of joins was detected, restoring roles is paused until it calms down.",
+            ).await;
+        }
+    }
+
+    // Puts off restoring a member until raid mode ends, if their guild is in
+    // it, returning whether it was. Past max_deferred the restore is left for
+    // the member's next event after the raid.
+    async fn hold_during_raid(&self, context: &Context, member: &SimpleMember) -> bool {
+        let mut raids = self.raids.lock().await;
+        let raid = match raids.get_mut(&GuildId::new(member.server_id)) {
+            Some(raid) if raid.until.is_some() => raid,
+            _ => return false,
+        };
+
+        // A later event has the member's latest roles.
+        raid.deferred.retain(|work| !matches!(
+            &work.task,
+            Task::Observe { member: deferred, .. } if deferred.user_id == member.user_id
+        ));
+
+        let max_deferred = self.config.raid.as_ref().map_or(0, |config| config.max_deferred);
+        if raid.deferred.len() < max_deferred {
+            raid.deferred.push(Work {
+                context: context.clone(),
+                task: Task::Observe { member: member.clone(), origin: Origin::Backlog },
+            });
+        } else {
+            println!(
+                "Too many restores held back in raided server {}, member {} is restored when next seen",
+                member.server_id,
+                member.user_id,
+            );
+        }
+        true
+    }
+
+    // Holds back storing a member's update until no more have come in for
+    // update_debounce_ms, or update_max_delay_ms since the first held back.
+    async fn debounce(&self, context: &Context, member: &SimpleMember, since: Option<Instant>) {
+        let now = Instant::now();
+        self.debounced_updates.lock().await.insert(
+            (UserId::new(member.user_id), GuildId::new(member.server_id)),
+            DebouncedUpdate {
+                context: context.clone(),
+                member: member.clone(),
+                first: since.unwrap_or(now),
+                last: now,
+                flushed: false,
+            },
+        );
+    }
+
+    async fn take_debounced(&self, member: &SimpleMember) -> Option<DebouncedUpdate> {
+        self.debounced_updates.lock().await
+            .remove(&(UserId::new(member.user_id), GuildId::new(member.server_id)))
+    }
+
+    // Checks for held back updates which are due to be stored.
+    pub fn start_update_flusher(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let handler = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(DEBOUNCE_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                handler.flush_updates(false).await;
+            }
+        })
+    }
+
+    // Stores a flushed update, unless something observed for the member 
+    // since has already taken it.
+    async fn observe_flushed(&self, member: &SimpleMember) {
+        if let Some(mut held) = self.take_debounced(member).await {
+            self.observe_member_locked(&held.context, &mut held.member, Origin::Debounced).await;
+        }
+    }
+
+    // Queues held back updates to be stored, all of them or only those which
+    // are due.
+    pub async fn flush_updates(&self, all: bool) {
+        let quiet = Duration::from_millis(self.config.update_debounce_ms);
+        let max_delay = Duration::from_millis(self.config.update_max_delay_ms);
+
+        // Updates stay held until the worker gets to them, so anything 
+        // observed for the member before then stores them first instead.
+        let due: Vec<(Context, SimpleMember)> = {
+            let mut updates = self.debounced_updates.lock().await;
+            updates.values_mut()
+                .filter(|update| !update.flushed)
+                .filter(|update| all || update.last.elapsed() >= quiet || update.first.elapsed() >= max_delay)
+                .map(|update| {
+                    update.flushed = true;
+                    (update.context.clone(), update.member.clone())
+                })
+                .collect()
+        };
+
+        for (context, member) in due {
+            self.enqueue_observe(&context, member, Origin::Debounced);
+        }
+    }
+
+    pub fn start_raid_monitor(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let handler = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(RAID_CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                handler.end_raids().await;
+            }
+        })
+    }
+
+    // Takes guilds whose raid cooldown has passed out of raid mode, carrying
+    // out all the restores which were held back in one go.
+    async fn end_raids(&self) {
+        let now = Instant::now();
+        let mut ended = vec![];
+
+        {
+            let mut raids = self.raids.lock().await;
+            for (server_id, raid) in raids.iter_mut() {
+                if raid.until.is_some_and(|until| until <= now) {
+                    raid.until = None;
+                    ended.push((*server_id, std::mem::take(&mut raid.deferred)));
+                }
+            }
+        }
+
+        for (server_id, deferred) in ended {
+            println!(
+                "Raid mode ended in guild {}, processing {} held back restores",
+                server_id.get(),
+                deferred.len(),
+            );
+
+            if let Some(work) = deferred.first() {
+                self.notify_staff(
+                    &work.context,
+                    server_id,
+                    "Raid mode ended: restoring roles for members who joined meanwhile.",
+                ).await;
+            }
+
+            for work in deferred {
+                if let Task::Observe { member, origin } = work.task {
+                    self.enqueue_observe(&work.context, member, origin);
+                }
+            }
+        }
+    }
+
+    // Deals with the bot being added back to a guild whose data is waiting 
+    // to be forgotten.
+    async fn check_readded(&self, context: &Context, server_id: GuildId) {
+        let forget_at = match self.forget_at(server_id).await {
+            Ok(Some(forget_at)) => forget_at,
+            Ok(None) => return,
+            Err(error) => {
+                println!("Error checking whether guild {} is being forgotten: {}", server_id.get(), error);
+                return;
+            },
+        };
+
+        if self.config.keep_on_readd {
+            match self.cancel_forget(server_id).await {
+                Ok(_) => println!("Added back to guild {}, keeping its data", server_id.get()),
+                Err(error) => println!("Error keeping data for guild {}: {}", server_id.get(), error),
+            }
+        } else {
+            // Nothing else stops the data being deleted while the bot is back
+            // in the guild, so make sure someone hears about it.
+            println!(
+                "WARNING: added back to guild {} which is still due to be forgotten at {}, \
+                its data will be deleted unless /rolepersist undo-forget is used",
+                server_id.get(),
+                forget_at,
+            );
+            self.alert_staff(context, server_id, &format!(
+                "Roles stored for this server from before the bot was removed will be deleted <t:{}:R>, \
+                use `/rolepersist undo-forget` to keep them.",
+                forget_at,
+            )).await;
+        }
+    }
+
+    // Tells a guild's staff about a problem, through its owner if there's no
+    // staff channel to post in.
+    async fn alert_staff(&self, context: &Context, server_id: GuildId, message: &str) {
+        if self.config.guild(server_id.get()).is_some_and(|guild| guild.staff_channel.is_some()) {
+            return self.notify_staff(context, server_id, message).await;
+        }
+
+        let owner = context.cache.guild(server_id).map(|guild| guild.owner_id);
+        if let Some(owner) = owner {
+            let message = CreateMessage::new().content(message);
+            if let Err(error) = owner.direct_message(&context.http, message).await {
+                println!("Error alerting owner of guild {}: {}", server_id.get(), error);
+            }
+        }
+    }
+
+    pub async fn notify_staff(&self, context: &Context, server_id: GuildId, message: &str) {
+        let channel = self.config.guild(server_id.get()).and_then(|guild| guild.staff_channel);
+        if let Some(channel) = channel {
+            if let Err(error) = ChannelId::new(channel).say(&context.http, message).await {
+                println!("Error notifying staff of guild {}: {}", server_id.get(), error);
+            }
+        }
+    }
+
+    // Copies the database to a timestamped file next to it with SQLite's 
+    // online backup, returning the path and size of the copy. This uses its
+    // own connection, so member events carry on being saved meanwhile.
+    pub async fn backup(&self) -> Result<(String, u64)> {
+        let path = self.database_path
+            .with_file_name(format!("data-backup-{}.db", self.clock.now()))
+            .to_string_lossy()
+            .into_owned();
+
+        // spawn_blocking rather than block_in_place, which panics on a
+        // current_thread runtime.
+        let source_path = self.database_path.clone();
+        let destination_path = path.clone();
+        tokio::task::spawn_blocking(move || {
+            let source = Connection::open(&source_path)?;
+            let mut destination = Connection::open(&destination_path)?;
+            let backup = rusqlite::backup::Backup::new(&source, &mut destination)?;
+            backup.run_to_completion(BACKUP_PAGES_PER_STEP, BACKUP_STEP_PAUSE, None)
+        }).await.unwrap_or_else(|_| Err(rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_ERROR),
+            Some("the backup panicked".to_string()),
+        )))?;
+
+        let size = fs::metadata(&path).map(|metadata| metadata.len()).unwrap_or(0);
+        println!("Backed up the database to {} ({} bytes)", path, size);
+        Ok((path, size))
+    }
+
+    pub fn close(self) -> Result<()> {
+        self.writer.close()?;
+        let connection = self.data.into_inner().unwrap_or_else(|poisoned| poisoned.into_inner());
+        connection.close().map_err(|(_, error)| error)
+    }
+
+    // Syncs a guild on request, only recording what members have, and waits
+    // for everyone in it to be observed. Returns how many members there were
+    // and how long it took, or None if the guild is already being resynced.
+    pub async fn resync_guild(&self, context: &Context, server_id: GuildId) -> std::result::Result<Option<(usize, Duration)>, Error> {
+        if !self.resyncs.lock().await.insert(server_id) {
+            return Ok(None);
+        }
+
+        let start = Instant::now();
+        let result = self.sync_guild(context, server_id, Origin::Resync).await;
+        if result.is_ok() {
+            let queue = self.guild_queue(server_id);
+            while !queue.is_idle() {
+                tokio::time::sleep(IDLE_POLL_INTERVAL).await;
+            }
+        }
+
+        self.resyncs.lock().await.remove(&server_id);
+        Ok(Some((result?.0, start.elapsed())))
+    }
+
+    // Waits for all queued work to be finished.
+    pub async fn wait_idle(&self) {
+        while !self.queues.iter().all(|queue| queue.is_idle()) {
+            tokio::time::sleep(IDLE_POLL_INTERVAL).await;
+        }
+    }
+
+    // Stops accepting work, returning the workers, which exit once they've 
+    // finished what's queued.
+    pub fn close_queues(&self) -> Vec<tokio::task::JoinHandle<()>> {
+        self.queues_closed.store(true, Ordering::Relaxed);
+        for queue in self.queues.iter() {
+            queue.close();
+        }
+        std::mem::take(&mut *self.workers.lock().unwrap())
+    }
+
+    pub fn filter_allow_server(&self, id: GuildId) -> bool {
+        if let Some(restrict) = &*self.restrict.read().unwrap() {
+            restrict.is_restricted(id.get())
+        } else {
+            true
+        }
+    } 
+
+    // Whether members' roles are restored in a guild, rather than only saved.
+    pub fn restores_allowed(&self, server_id: u64) -> bool {
+        match &*self.restrict_restores.read().unwrap() {
+            Some(restrict) => restrict.is_restricted(server_id),
+            None => true,
+        }
+    }
+
+    // Swaps in the restriction lists and the roles left out of restores from
+    // a freshly read config, logging how they changed. Guilds no longer 
+    // allowed stop being tracked straight away, newly allowed ones are picked
+    // up as their events arrive. Everything else needs a restart.
+    pub async fn reload_config(&self, mut config: Config) {
+        // Roles are checked against these as they're saved and restored, 
+        // stored roles newly excluded are left for the next save to drop.
+        let exclusions = Exclusions::take(&mut config);
+        if *self.exclusions.read().unwrap() != exclusions {
+            println!("Reloaded config, excluded roles changed");
+            *self.exclusions.write().unwrap() = exclusions;
+        }
+
+        let restrict = config.restrict;
+        let describe = |restrict: &Option<Restriction>| match restrict {
+            Some(restrict) => format!("{} {:?}", restrict.mode, restrict.servers),
+            None => "none".to_string(),
+        };
+
+        // Restores are checked for as members are observed, so nothing else
+        // needs doing when these change.
+        let previous_restores = {
+            let mut current = self.restrict_restores.write().unwrap();
+            std::mem::replace(&mut *current, config.restrict_restores)
+        };
+        let (before, after) = (describe(&previous_restores), describe(&self.restrict_restores.read().unwrap()));
+        if before != after {
+            println!("Reloaded config, restore restrictions changed from {} to {}", before, after);
+        }
+
+        let previous = {
+            let mut current = self.restrict.write().unwrap();
+            std::mem::replace(&mut *current, restrict)
+        };
+
+        let (before, after) = (describe(&previous), describe(&self.restrict.read().unwrap()));
+        if before == after {
+            println!("Reloaded config, restrictions unchanged");
+            return;
+        }
+        println!("Reloaded config, restrictions changed from {} to {}", before, after);
+
+        let mut guilds = self.guilds.lock().await;
+        let denied: Vec<_> = guilds.iter()
+            .filter(|id| !self.filter_allow_server(**id))
+            .copied()
+            .collect();
+        for id in denied {
+            println!("No longer tracking guild {}", id.get());
+            guilds.remove(&id);
+        }
+    }
+
+    // Member events are kept in order by their guild's worker, this is for 
+    // anything else which changes a member's data, such as manual commands.
+    // Returns whatever the function does.
+    pub async fn do_locked<T, F: Future<Output = T>>(
+        &self, 
+        key: (UserId, GuildId),
+        function: impl FnOnce() -> F,
+    ) -> T {
+        // Taking a reference while the entry's shard is locked means the lock
+        // can't be removed, and another made in its place, before it's held.
+        let user_lock = self.member_locks.entry(key).or_default().clone();
+        let guard = user_lock.clone().lock_owned().await;
+        let result = function().await;
+        std::mem::drop(guard);
+        std::mem::drop(user_lock);
+
+        // Only the map has it when nobody else holds or is waiting for it,
+        // and nobody else can pick it up while the shard is locked to check.
+        self.member_locks.remove_if(&key, |_, lock| Arc::strong_count(lock) == 1);
+        result
+    }
+}
+
+#[async_trait]
+impl EventHandler for Handler {
+    async fn ready(&self, context: Context, ready: Ready) {
+        if let Err(error) = self.reconcile_guilds(&ready).await {
+            println!("Error checking for orphaned guilds: {}", error);
+        }
+
+        if let Some(shard) = ready.shard {
+            self.shard_count.store(shard.total, Ordering::Relaxed);
+        }
+
+        // Commands are global, registering them once is enough.
+        if context.shard_id.0 == 0 {
+            if let Err(error) = commands::register(&context.http).await {
+                println!("Error registering commands: {}", error);
+            }
+        }
+
+        self.shards.lock().await.insert(context.shard_id.0, context.shard.clone());
+
+        println!("Shard {} ready with {} guilds", context.shard_id, ready.guilds.len());
+        self.shard_status.lock().await.insert(context.shard_id.0, ShardStatus {
+            stage: ConnectionStage::Connected,
+            since: Instant::now(),
+            latency: None,
+            guilds: ready.guilds.len(),
+            warned: false,
+        });
+
+        let guilds = ready.guilds.into_iter()
+            .map(|guild| guild.id)
+            .filter(|id| self.filter_allow_server(*id));
+
+        self.guilds.lock().await.extend(guilds);
+    }
+
+    async fn interaction_create(&self, context: Context, interaction: Interaction) {
+        match interaction {
+            Interaction::Command(command) => commands::handle(self, &context, &command).await,
+            Interaction::Component(component) => commands::handle_component(self, &context, &component).await,
+            _ => (),
+        }
+    }
+
+    async fn shards_ready(&self, context: Context, total_shards: u32) {
+        println!("All {} shards ready", total_shards);
+        self.shards_ready.store(true, Ordering::Relaxed);
+        self.scheduler.start();
+        self.initial_sync(&context).await;
+    }
+
+    async fn cache_ready(&self, context: Context, _guilds: Vec<GuildId>) {
+        self.initial_sync(&context).await;
+    }
+
+    async fn guild_create(&self, context: Context, guild: Guild, is_new: Option<bool>) {
+        if self.filter_allow_server(guild.id) {
+            self.guilds.lock().await.insert(guild.id);
+            self.mark_available(guild.id).await;
+            self.check_readded(&context, guild.id).await;
+
+            // Guilds present at startup are covered by the initial sync.
+            if !self.initial_sync_done.load(Ordering::Relaxed) {
+                return;
+            }
+
+            // Guilds becoming available again after an outage also arrive 
+            // here, member events should have kept those mostly up to date.
+            // Those the initial sync went ahead without weren't seen at all.
+            let late = self.late_guilds.lock().await.remove(&guild.id);
+            if is_new != Some(true) && !late {
+                match self.needs_sync(guild.id).await {
+                    Ok(false) => return,
+                    Ok(true) => {},
+                    Err(error) => println!("Error checking last sync of guild {}: {}", guild.id.get(), error),
+                }
+            }
+
+            if let Err(error) = self.save_guild(&context, guild.id).await {
+                println!("Error syncing guild {}: {}", guild.id.get(), error);
+            }
+        }
+    }
+
+    async fn guild_members_chunk(&self, context: Context, chunk: GuildMembersChunkEvent) {
+        let origin = match self.chunk_origin(&chunk).await {
+            Some(origin) => origin,
+            None => return,
+        };
+
+        for member in chunk.members.values() {
+            self.enqueue_observe(&context, member.into(), origin);
+        }
+
+        self.record_chunk(&chunk).await;
+    }
+
+    async fn guild_delete(&self, context: Context, guild: UnavailableGuild, full: Option<Guild>) {
+        if guild.unavailable {
+            println!("Guild {} is unavailable, holding back restores there until it's back", guild.id.get());
+            self.mark_unavailable(guild.id).await;
+        } else {
+            self.guilds.lock().await.remove(&guild.id);
+
+            if self.config.keep_removed_guilds {
+                // The cache still has the guild's members, this is the last 
+                // chance to store what they had when the bot was removed.
+                let members: Vec<SimpleMember> = match full {
+                    Some(full) => full.members.values().map(SimpleMember::from).collect(),
+                    None => context.cache.guild(guild.id)
+                        .map(|cached| cached.members.values().map(SimpleMember::from).collect())
+                        .unwrap_or_default(),
+                };
+
+                println!("Removed from guild {}, keeping data and saving {} cached members", guild.id.get(), members.len());
+                for member in members {
+                    self.enqueue(&context, guild.id, Task::Save(member));
+                }
+            } else if self.config.forget_grace_secs > 0 {
+                println!(
+                    "Removed from guild {}, forgetting its data in {}s unless the bot is added back",
+                    guild.id.get(),
+                    self.config.forget_grace_secs,
+                );
+                if let Err(error) = self.schedule_forget(guild.id, self.config.forget_grace_secs).await {
+                    println!("Error scheduling forgetting guild {}: {}", guild.id.get(), error);
+                }
+            } else {
+                self.enqueue(&context, guild.id, Task::ForgetGuild(guild.id));
+            }
+
+            self.close_queue(guild.id);
+            self.seen_cache.forget_guild(guild.id.get());
+        }
+    }
+
+    async fn resume(&self, context: Context, _event: ResumedEvent) {
+        self.resync_shard(&context).await;
+    }
+
+    async fn shard_stage_update(&self, context: Context, event: ShardStageUpdateEvent) {
+        println!("Shard {} went from {} to {}", event.shard_id, event.old, event.new);
+        let mut statuses = self.shard_status.lock().await;
+        let status = statuses.entry(event.shard_id.0).or_insert_with(|| ShardStatus {
+            stage: event.new,
+            since: Instant::now(),
+            latency: None,
+            guilds: 0,
+            warned: false,
+        });
+        status.stage = event.new;
+        status.since = Instant::now();
+        status.warned = false;
+        std::mem::drop(statuses);
+
+        // A fresh session gets a ready event which syncs everything already, 
+        // so only resumed sessions need to catch up here.
+        if event.old == ConnectionStage::Resuming && event.new == ConnectionStage::Connected {
+            self.resync_shard(&context).await;
+        }
+    }
+        
+    async fn guild_audit_log_entry_create(&self, context: Context, entry: AuditLogEntry, guild_id: GuildId) {
+        if !self.filter_allow_server(guild_id) {
+            return;
+        }
+
+        let user_id = match entry.target_id {
+            Some(target) => UserId::new(target.get()),
+            None => return,
+        };
+
+        match entry.action {
+            Action::Member(MemberAction::Kick) => {
+                if let Err(error) = self.record_kick(user_id, guild_id).await {
+                    println!("Error recording kick of member {} in server {}: {}", user_id.get(), guild_id.get(), error);
+                }
+            },
+            Action::Member(MemberAction::RoleUpdate) => {
+                // Our own restores and removals aren't moderator decisions.
+                if entry.user_id == context.cache.current_user().id {
+                    return;
+                }
+
+                let removed = removed_roles(entry.changes.unwrap_or_default());
+                if removed.is_empty() {
+                    return;
+                }
+
+                if let Err(error) = self.record_manual_removals(user_id, guild_id, &removed).await {
+                    println!("Error recording role removals for member {} in server {}: {}", user_id.get(), guild_id.get(), error);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    async fn guild_member_addition(&self, context: Context, member: Member) {
+        if self.filter_allow_server(member.guild_id) {
+            self.track_join(&context, member.guild_id).await;
+            self.enqueue_observe(&context, member.into(), Origin::Join);
+        }
+    }
+    
+    async fn guild_member_update(
+        &self, 
+        context: Context, 
+        _old: Option<Member>,
+        _new: Option<Member>,
+        update: GuildMemberUpdateEvent
+    ) {
+        if self.filter_allow_server(update.guild_id) {
+            self.enqueue_observe(&context, update.into(), Origin::Update);
+        }
+    }
+}
+
+enum RestrictionMode {
+    Allow,
+    Deny,
+}
+
+impl fmt::Display for RestrictionMode {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RestrictionMode::Allow => formatter.write_str("allow"),
+            RestrictionMode::Deny => formatter.write_str("deny"),
+        }
+    }
+}
+
+struct RestrictionVisitor;
+
+impl<'de> Visitor<'de> for RestrictionVisitor {
+    type Value = RestrictionMode;
+    
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("the string 'allow' or the string 'deny'")
+    }
+
+    fn visit_str<E>(self, value: &str) -> Result<Self::Value, E> 
+    where E: serde::de::Error {
+        match value {
+            "allow" => Ok(RestrictionMode::Allow),
+            "deny" => Ok(RestrictionMode::Deny),
+            _ => Err(E::custom(format!("{} is not a restriction mode", value))),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for RestrictionMode {
+    fn deserialize<D>(deserializer: D) -> Result<RestrictionMode, D::Error>
+
+    where D: Deserializer<'de> {
+        deserializer.deserialize_str(RestrictionVisitor)
+    }
+}
+
+#[derive(Deserialize)]
+struct Restriction {
+    mode: RestrictionMode,
+    servers: Vec<u64>,
+}
+
+impl Restriction {
+    pub fn is_restricted(&self, server_id: u64) -> bool {
+        let is_listed = self.servers.iter()
+            .find(|id| **id == server_id);
+
+        match self.mode {
+            RestrictionMode::Allow => is_listed.is_some(),
+            RestrictionMode::Deny => is_listed.is_none(),
+        }
+    }
+}
+
+// Roles in a guild which can't be given out by the bot.
+#[derive(Default)]
+struct SpecialRoles {
+    // Roles belonging to an integration (another bot or a subscription).
+    managed: HashSet<u64>,
+    // The role Discord gives members boosting the server.
+    booster: Option<u64>,
+    // Linked roles, which need a verified connection, and roles bought 
+    // through a subscription. Only Discord can give these out.
+    linked: HashSet<u64>,
+    // Roles at or above the bot's highest role, which Discord won't let it 
+    // give out.
+    above_bot: HashSet<u64>,
+    // Every role in the guild, if they're known.
+    existing: Option<HashSet<u64>>,
+    // Roles with permissions too dangerous to hand out automatically.
+    sensitive: HashSet<u64>,
+    // Roles granting administrator, which is everything.
+    privileged: HashSet<u64>,
+}
+
+impl SpecialRoles {
+    fn from_roles<'a>(roles: impl Iterator<Item = &'a Role>, bot_position: Option<u16>) -> Self {
+        let mut special = Self::default();
+        let mut existing = HashSet::new();
+        for role in roles {
+            existing.insert(role.id.get());
+
+            if role.permissions.intersects(SENSITIVE_PERMISSIONS) {
+                special.sensitive.insert(role.id.get());
+            }
+
+            if is_privileged(role) {
+                special.privileged.insert(role.id.get());
+            }
+
+            // The booster role is usually flagged as managed too, but not 
+            // always, the tag is what marks it.
+            if role.tags.premium_subscriber {
+                special.booster = Some(role.id.get());
+            } else if role.tags.guild_connections
+                || role.tags.available_for_purchase
+                || role.tags.subscription_listing_id.is_some() {
+                special.linked.insert(role.id.get());
+            } else if role.managed {
+                special.managed.insert(role.id.get());
+            } else if bot_position.is_some_and(|position| role.position >= position) {
+                special.above_bot.insert(role.id.get());
+            }
+        }
+        special.existing = Some(existing);
+        special
+    }
+
+    fn cached(context: &Context, server_id: u64) -> Self {
+        let guild = match context.cache.guild(server_id) {
+            Some(guild) => guild,
+            None => return Self::default(),
+        };
+
+        // If the bot's own member isn't cached, leave it to Discord to refuse.
+        let bot_position = guild.members.get(&context.cache.current_user().id)
+            .map(|bot| bot.roles.iter()
+                .filter_map(|role| guild.roles.get(role))
+                .map(|role| role.position)
+                .max()
+                .unwrap_or(0));
+
+        Self::from_roles(guild.roles.values(), bot_position)
+    }
+}
+
+// Whether a role grants administrator, and so is never restored unless its
+// guild allows it.
+fn is_privileged(role: &Role) -> bool {
+    role.permissions.contains(Permissions::ADMINISTRATOR)
+}
+
+// The unix time (in seconds) a Discord ID was created at.
+// The roles a role update audit log entry took away.
+fn removed_roles(changes: Vec<Change>) -> Vec<RoleId> {
+    changes.into_iter()
+        .filter_map(|change| match change {
+            Change::RolesRemove { old, new } => new.or(old),
+            _ => None,
+        })
+        .flatten()
+        .map(|role| role.id)
+        .collect()
+}
+
+fn snowflake_time(id: u64) -> u64 {
+    const DISCORD_EPOCH: u64 = 1420070400000;
+    ((id >> 22) + DISCORD_EPOCH) / 1000
+}
+
+fn has_column(connection: &Connection, table: &str, column: &str) -> Result<bool> {
+    let count = connection.query_row(
+        "SELECT COUNT(*) FROM pragma_table_info(?1) WHERE name=?2",
+        [table, column],
+        |row| row.get::<_, i64>(0),
+    )?;
+    Ok(count > 0)
+}
+
+fn shard_for(server_id: GuildId, shard_count: u32) -> u32 {
+    ((server_id.get() >> 22) % shard_count as u64) as u32
+}
+
+#[derive(Deserialize)]
+struct RoleReference {
+    server: u64,
+    role: u64,
+}
+
+#[derive(Deserialize)]
+struct RoleMapping {
+    from: RoleReference,
+    to: RoleReference,
+}
+
+#[derive(Deserialize)]
+struct WipeGuard {
+    // Members with more stored roles than this suddenly having none are 
+    // double-checked with Discord before saving.
+    min_roles: usize,
+    // Members losing more than this percentage of their stored roles at once 
+    // are double-checked too.
+    max_loss_percent: Option<u8>,
+}
+
+#[derive(Deserialize)]
+struct RaidConfig {
+    // Joins within a minute which put a guild into raid mode.
+    joins_per_minute: usize,
+    // Seconds after the join rate drops that raid mode ends.
+    cooldown: u64,
+    // Restores held back at most while in raid mode, any more are carried
+    // out when the member is next seen after it.
+    #[serde(default = "default_raid_max_deferred")]
+    max_deferred: usize,
+}
+
+#[derive(Deserialize)]
+struct VerificationRoles {
+    // Given to members when they join, until they verify.
+    unverified: u64,
+    // Given in place of the unverified role once they have.
+    verified: u64,
+}
+
+// What happens to restores of more roles than max_auto_restore_roles.
+#[derive(Deserialize, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+enum OverCap {
+    // Staff are asked to approve the whole restore, the first roles up to 
+    // the cap are restored if there's no staff channel.
+    #[default]
+    Ask,
+    // The first roles up to the cap are restored and staff told.
+    Top,
+}
+
+// How a rejoining member's stored roles are given back.
+#[derive(Deserialize, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+enum RestoreStrategy {
+    // Stored roles are added to whatever the member already has.
+    #[default]
+    Merge,
+    // The member ends up with exactly their stored roles, losing any others
+    // they were given since rejoining, such as a welcome bot's auto-roles.
+    Replace,
+}
+
+// Which events members are saved on.
+#[derive(Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum ObserveOn {
+    Join,
+    Update,
+}
+
+#[derive(Deserialize)]
+pub struct GuildConfig {
+    // Another server whose stored roles are given (by name) to members the 
+    // first time they're seen in this one.
+    profile_source: Option<u64>,
+    // Where to send notices meant for the server's staff.
+    staff_channel: Option<u64>,
+    // Whether members who were kicked get their roles back when they rejoin.
+    #[serde(default = "default_true")]
+    restore_after_kick: bool,
+    // Whether roles a moderator took away stay away when the member rejoins.
+    #[serde(default)]
+    skip_manual_removals: bool,
+    // Seconds to wait after a rejoin before restoring, so other bots 
+    // onboarding the member can finish first.
+    #[serde(default)]
+    restore_delay_secs: u64,
+    // Seconds since a member's roles were stored within which any they've 
+    // lost without leaving are added back by syncs. Off unless set, since it
+    // undoes removals made while the bot wasn't watching.
+    repair_drift_within_secs: Option<u64>,
+    // Roles restored first in this server, in order, instead of the global 
+    // priority_roles. Only the order of requests changes, so with 
+    // restore_concurrency above 1 it makes little difference.
+    priority_roles: Option<Vec<u64>>,
+    // Roles a verification bot uses, for servers which verify members in
+    // two stages.
+    verification: Option<VerificationRoles>,
+    // Overrides max_auto_restore_roles for this server.
+    max_auto_restore_roles: Option<usize>,
+    // Whether restores wait for staff to approve them in the staff channel.
+    #[serde(default)]
+    require_approval: bool,
+    // Whether restores are only worked out and logged, to try the bot out. 
+    // Can be changed with /rolepersist dryrun.
+    #[serde(default)]
+    dry_run: bool,
+    // Roles with the administrator permission which may be restored, no 
+    // others are, whatever else is configured.
+    #[serde(default)]
+    allow_privileged_roles: Vec<u64>,
+    // Whether rejoining members get their stored roles added ("merge") or 
+    // have their roles replaced with them ("replace"). Replacing never takes
+    // away managed roles, roles above the bot or roles which aren't persisted.
+    #[serde(default)]
+    restore_strategy: RestoreStrategy,
+}
+
+// Why roles are being added to a member, for the audit log.
+fn restore_reason(last_seen: Option<i64>) -> String {
+    let date = last_seen.and_then(|time| serenity::model::Timestamp::from_unix_timestamp(time).ok());
+    let reason = match date {
+        Some(date) => format!("Restoring roles held when last seen on {} (rolepersist)", date.date()),
+        None => "Granting previously assigned roles (rolepersist)".to_string(),
+    };
+    truncate_reason(reason)
+}
+
+// Why a member's roles are being replaced, for the audit log.
+fn replace_reason(last_seen: Option<i64>) -> String {
+    let date = last_seen.and_then(|time| serenity::model::Timestamp::from_unix_timestamp(time).ok());
+    let reason = match date {
+        Some(date) => format!("Replacing roles with those held when last seen on {} (rolepersist replace)", date.date()),
+        None => "Replacing roles with those previously held (rolepersist replace)".to_string(),
+    };
+    truncate_reason(reason)
+}
+
+// Cuts an audit log reason down to the length Discord accepts.
+fn truncate_reason(mut reason: String) -> String {
+    if reason.len() > MAX_AUDIT_REASON {
+        let end = (0..=MAX_AUDIT_REASON).rev()
+            .find(|end| reason.is_char_boundary(*end))
+            .unwrap_or(0);
+        reason.truncate(end);
+    }
+    reason
+}
+
+// Leaves roles past the first cap to be restored out of the plan.
+fn apply_restore_cap(plan: &mut [(u64, Verdict)], cap: usize) {
+    plan.iter_mut()
+        .filter(|(_, verdict)| *verdict == Verdict::Restore)
+        .skip(cap)
+        .for_each(|(_, verdict)| *verdict = Verdict::OverCap);
+}
+
+// Puts priority roles first in the order they are listed, the sort is stable 
+// so everything else keeps its order.
+fn sort_by_priority(roles: &mut [u64], priority: &[u64]) {
+    roles.sort_by_key(|role| {
+        priority.iter()
+            .position(|priority| priority == role)
+            .unwrap_or(usize::MAX)
+    });
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub token: String,
+    restrict: Option<Restriction>,
+    // Guilds members' roles are restored in, members of any others are still
+    // saved. Reloaded along with restrict.
+    restrict_restores: Option<Restriction>,
+    #[serde(default)]
+    role_mapping: Vec<RoleMapping>,
+    // Whether data for guilds the bot was removed from while offline is 
+    // deleted on startup rather than just reported.
+    #[serde(default)]
+    prune_orphans: bool,
+    // Whether data for guilds the bot is removed from is kept (with a final 
+    // snapshot of their members) rather than deleted.
+    #[serde(default)]
+    keep_removed_guilds: bool,
+    // Seconds to wait before forgetting a guild the bot was removed from, so
+    // an accidental removal can be undone. 0 forgets it straight away.
+    #[serde(default)]
+    forget_grace_secs: u64,
+    // Milliseconds without another update before a member's update is
+    // stored, so roles toggled in quick succession are stored once. 0 stores
+    // each update as it comes. Rejoins and restores are never held back.
+    #[serde(default = "default_update_debounce_ms")]
+    update_debounce_ms: u64,
+    // The longest a member's updates are held back for while more keep
+    // coming, in milliseconds.
+    #[serde(default = "default_update_max_delay_ms")]
+    update_max_delay_ms: u64,
+    // Whether members' nicknames and server avatars are stored, shown by
+    // /rolepersist history.
+    #[serde(default)]
+    store_profiles: bool,
+    // Whether members rejoining are given back the nickname stored for them,
+    // which needs store_profiles and Manage Nicknames.
+    #[serde(default)]
+    restore_nicknames: bool,
+    // Whether the audit log is searched for who gave members their roles and
+    // why when they're stored, shown by /rolepersist history. Needs View
+    // Audit Log, and costs a request for each member whose roles grow.
+    #[serde(default)]
+    record_grant_reasons: bool,
+    // Days of hourly stats snapshots kept for /rolepersist trends.
+    #[serde(default = "default_stats_history_days")]
+    stats_history_days: u64,
+    // Whether being added back to a guild within forget_grace_secs keeps its
+    // data, rather than leaving that to /rolepersist undo-forget.
+    #[serde(default)]
+    keep_on_readd: bool,
+    #[serde(default)]
+    priority_roles: Vec<u64>,
+    // Seconds after a role is taken from a member during which it won't be
+    // restored to them, 0 to always restore.
+    #[serde(default)]
+    restore_cooldown: u64,
+    #[serde(default)]
+    guilds: HashMap<u64, GuildConfig>,
+    wipe_guard: Option<WipeGuard>,
+    // Guilds with at least this many members are synced by requesting member 
+    // chunks over the gateway, smaller ones through REST.
+    #[serde(default = "default_chunk_sync_threshold")]
+    chunk_sync_threshold: u64,
+    // How many guilds are synced at once, however many syncs are waiting.
+    #[serde(default = "default_sync_concurrency")]
+    sync_concurrency: usize,
+    // Seconds a single guild's sync can take before a warning is logged.
+    #[serde(default = "default_slow_sync_warning")]
+    slow_sync_warning: u64,
+    // Seconds the initial sync waits for guilds unavailable at startup before
+    // going ahead without them.
+    #[serde(default = "default_unavailable_guild_wait")]
+    unavailable_guild_wait: u64,
+    // Roles members give themselves (through reaction or button role bots).
+    // These are still stored but never restored, so the member can pick them
+    // up again themselves and the other bot's state stays consistent. This 
+    // and the role lists after it, up to sensitive_roles_allowed, are 
+    // reloaded along with restrict.
+    #[serde(default)]
+    self_assignable_roles: Vec<u64>,
+    // Roles Discord's own automation gives out, such as those picked in
+    // onboarding prompts, which restoring would fight with. These are stored
+    // but never restored, and replacing roles leaves them alone. Roles from
+    // bots and integrations, the booster role, and linked or purchasable
+    // roles are told apart by their metadata and never need listing, but
+    // nothing marks onboarding roles, so those have to be listed here.
+    #[serde(default)]
+    system_managed_roles: Vec<u64>,
+    // Roles which are never stored or restored.
+    #[serde(default)]
+    exclude_roles: Vec<u64>,
+    // If set, the only roles which are stored and restored. Roles in 
+    // exclude_roles are still left out.
+    include_only_roles: Option<Vec<u64>>,
+    // Roles with sensitive permissions (administrator, banning, managing the
+    // server and the like) which may still be restored.
+    #[serde(default)]
+    sensitive_roles_allowed: Vec<u64>,
+    // Whether roles managed by integrations are left out of the database, 
+    // they're never restored either way.
+    #[serde(default)]
+    skip_managed_roles: bool,
+    // Whether stored roles found to have been deleted from a guild are 
+    // removed from the database.
+    #[serde(default)]
+    prune_deleted_roles: bool,
+    // Accounts younger than this don't get roles restored, to make using 
+    // fresh alt accounts to regain roles harder.
+    min_account_age_days: Option<u64>,
+    raid: Option<RaidConfig>,
+    // Whether members without any roles are left out of the database until 
+    // they get one, which saves a lot of space on large open servers.
+    #[serde(default)]
+    skip_roleless_members: bool,
+    // The most roles restored to a member without anyone looking, more than
+    // this is dealt with according to over_restore_cap. Roles which aren't 
+    // restored anyway don't count.
+    max_auto_restore_roles: Option<usize>,
+    #[serde(default)]
+    over_restore_cap: OverCap,
+    // How members' roles are laid out in the database, "rows" for a row per
+    // role or "json" for a row per member, which also keeps their nickname 
+    // and timeout. Roles stored the other way are moved over on startup.
+    #[serde(default)]
+    storage: storage::Layout,
+    // Whether bots have their roles stored and restored like anyone else.
+    #[serde(default)]
+    persist_bots: bool,
+    // How many of a member's roles are added at once, with more than one the
+    // order from priority_roles is no longer kept.
+    #[serde(default = "default_restore_concurrency")]
+    restore_concurrency: usize,
+    // How many roles are added each second at most, across all guilds.
+    #[serde(default = "default_role_adds_per_second")]
+    role_adds_per_second: u32,
+    // How many restores found by syncs are carried out each second.
+    #[serde(default = "default_sync_restores_per_second")]
+    sync_restores_per_second: u32,
+    // Seconds between runs of periodic jobs, by job name.
+    #[serde(default)]
+    job_intervals: HashMap<String, u64>,
+    // Which member events update their stored roles, syncs and restores 
+    // always do. Without "update" role changes are only picked up when 
+    // members join or guilds are synced, so a member leaving soon after 
+    // gaining or losing a role gets back what they had before. Updates are 
+    // still checked for rejoins.
+    #[serde(default = "default_observe_on")]
+    observe_on: Vec<ObserveOn>,
+    // Gateway intents to connect with, by name.
+    #[serde(default = "default_intents", deserialize_with = "deserialize_intents")]
+    pub intents: GatewayIntents,
+    // How many times in a row reconnecting to the gateway is tried after it
+    // fails before giving up and shutting down. Invalid tokens and intents 
+    // are never retried.
+    #[serde(default = "default_gateway_retries")]
+    pub gateway_retries: u32,
+}
+
+fn default_update_debounce_ms() -> u64 {
+    2000
+}
+
+fn default_update_max_delay_ms() -> u64 {
+    10_000
+}
+
+fn default_stats_history_days() -> u64 {
+    90
+}
+
+fn default_gateway_retries() -> u32 {
+    10
+}
+
+fn default_slow_sync_warning() -> u64 {
+    5 * 60
+}
+
+fn default_unavailable_guild_wait() -> u64 {
+    60
+}
+
+fn default_raid_max_deferred() -> usize {
+    1000
+}
+
+fn default_chunk_sync_threshold() -> u64 {
+    MEMBER_PAGE_SIZE
+}
+
+fn default_sync_restores_per_second() -> u32 {
+    5
+}
+
+fn default_sync_concurrency() -> usize {
+    3
+}
+
+fn default_restore_concurrency() -> usize {
+    1
+}
+
+fn default_role_adds_per_second() -> u32 {
+    10
+}
+
+fn default_observe_on() -> Vec<ObserveOn> {
+    vec![ObserveOn::Join, ObserveOn::Update]
+}
+
+fn default_intents() -> GatewayIntents {
+    GatewayIntents::GUILDS 
+        | GatewayIntents::GUILD_MEMBERS 
+        | GatewayIntents::GUILD_MODERATION
+}
+
+fn deserialize_intents<'de, D>(deserializer: D) -> Result<GatewayIntents, D::Error>
+where D: Deserializer<'de> {
+    let names = Vec::<String>::deserialize(deserializer)?;
+    names.iter().try_fold(GatewayIntents::empty(), |intents, name| {
+        GatewayIntents::from_name(name)
+            .map(|intent| intents | intent)
+            .ok_or_else(|| serde::de::Error::custom(format!("{} is not a gateway intent", name)))
+    })
+}
+
+// Logs the intents being used and what doesn't work without the usual ones.
+pub fn describe_intents(intents: GatewayIntents) {
+    let names: Vec<&str> = intents.iter_names().map(|(name, _)| name).collect();
+    println!("Connecting with intents: {}", names.join(", "));
+
+    if !intents.contains(GatewayIntents::GUILD_MEMBERS) {
+        println!("Warning: without GUILD_MEMBERS no member events arrive and guilds can't be synced, nothing will be restored");
+    }
+
+    // Ready still lists the guilds, so the initial sync and member events 
+    // carry on. Guilds joined later aren't synced until the next restart, and 
+    // anything needing the guild's roles cached (profiles) finds nothing.
+    if !intents.contains(GatewayIntents::GUILDS) {
+        println!("Without GUILDS: guilds joined while running aren't synced, removals aren't noticed and profile roles can't be matched");
+    }
+
+    if !intents.contains(GatewayIntents::GUILD_MODERATION) {
+        println!("Without GUILD_MODERATION: kicks and moderator role removals aren't recorded");
+    }
+}
+
+impl Config {
+    // A config with nothing but the token set, everything else as it is when
+    // left out of a config file.
+    pub fn new(token: impl Into<String>) -> Self {
+        serde_json::from_value(serde_json::json!({ "token": token.into() }))
+            .expect("A config with only a token is valid")
+    }
+
+    pub fn from_json(contents: &str) -> std::result::Result<Self, String> {
+        serde_json::from_str(contents).map_err(|error| error.to_string())
+    }
+
+    pub fn from_toml(contents: &str) -> std::result::Result<Self, String> {
+        // TOML keys are always strings, going through JSON values lets them
+        // be read as the IDs used to key guild settings.
+        toml::from_str::<serde_json::Value>(contents)
+            .map_err(|error| error.to_string())
+            .and_then(|value| serde_json::from_value(value).map_err(|error| error.to_string()))
+    }
+
+    pub fn guild(&self, server_id: u64) -> Option<&GuildConfig> {
+        self.guilds.get(&server_id)
+    }
+
+    pub fn job_interval(&self, name: &str, default: Duration) -> Duration {
+        self.job_intervals.get(name)
+            .map(|seconds| Duration::from_secs(*seconds))
+            .filter(|interval| !interval.is_zero())
+            .unwrap_or(default)
+    }
+}
+
+// The roles config leaves out of storing or restoring, kept apart from the 
+// rest of it so they can be reloaded.
+#[derive(PartialEq)]
+struct Exclusions {
+    self_assignable_roles: Vec<u64>,
+    system_managed_roles: Vec<u64>,
+    exclude_roles: Vec<u64>,
+    include_only_roles: Option<Vec<u64>>,
+    sensitive_roles_allowed: Vec<u64>,
+}
+
+impl Exclusions {
+    fn take(config: &mut Config) -> Self {
+        Self {
+            self_assignable_roles: std::mem::take(&mut config.self_assignable_roles),
+            system_managed_roles: std::mem::take(&mut config.system_managed_roles),
+            exclude_roles: std::mem::take(&mut config.exclude_roles),
+            include_only_roles: config.include_only_roles.take(),
+            sensitive_roles_allowed: std::mem::take(&mut config.sensitive_roles_allowed),
+        }
+    }
+
+    // Whether a role is stored and restored at all.
+    fn persists_role(&self, role: u64) -> bool {
+        !self.exclude_roles.contains(&role)
+            && self.include_only_roles.as_ref().is_none_or(|roles| roles.contains(&role))
+    }
+}
+
+// Logs how long the guilds of a sync took, slowest first.
+fn log_sync_durations(durations: &mut [(GuildId, Duration)]) {
+    if durations.is_empty() {
+        return;
+    }
+
+    durations.sort_by_key(|(_, elapsed)| std::cmp::Reverse(*elapsed));
+    let total: Duration = durations.iter().map(|(_, elapsed)| *elapsed).sum();
+    let slowest: Vec<String> = durations.iter()
+        .take(SLOWEST_SYNCS_LOGGED)
+        .map(|(server_id, elapsed)| format!("{} ({:.1}s)", server_id.get(), elapsed.as_secs_f64()))
+        .collect();
+    println!(
+        "Guild syncs took {:.1}s between them, slowest: {}",
+        total.as_secs_f64(),
+        slowest.join(", "),
+    );
+}
+
+pub fn vacuum(database_path: impl AsRef<Path>) -> Result<()> {
+    let database_path = database_path.as_ref();
+    let size = || fs::metadata(database_path).map(|metadata| metadata.len()).unwrap_or(0);
+
+    let before = size();
+    let connection = Connection::open(database_path)?;
+    connection.execute_batch("VACUUM; PRAGMA optimize;")?;
+    std::mem::drop(connection);
+    let after = size();
+
+    println!("Database size: {} bytes before, {} bytes after", before, after);
+    Ok(())
+}
+
+pub fn load_config() -> std::result::Result<Config, String> {
+    let path = CONFIG_PATHS.iter()
+        .find(|path| std::path::Path::new(path).exists())
+        .unwrap_or(&"config.json");
+
+    let config_contents = fs::read_to_string(path)
+        .map_err(|error| format!("Unable to read config file {}: {}", path, error))?;
+
+    let config = if path.ends_with(".toml") {
+        Config::from_toml(&config_contents)
+    } else {
+        Config::from_json(&config_contents)
+    };
+
+    config.map_err(|error| format!("Unable to parse config file {}: {}", path, error))
+}
+
+pub fn read_config() -> Config {
+    load_config().unwrap_or_else(|error| panic!("{}", error))
+}
@@ -1,381 +1,288 @@
-use std::fs;
-use std::fmt;
-use std::sync::{Arc, Weak};
-
-use rusqlite::{Connection, Result};
-use serenity::all::UnavailableGuild;
-
-use std::future::Future;
-
-use serenity::{async_trait, prelude::*};
-use serenity::model::gateway::Ready;
-use serenity::model::id::{UserId, GuildId, RoleId};
-use serenity::model::guild::{Member, Guild};
-use serenity::model::event::GuildMemberUpdateEvent;
-
-use serde::Deserialize;
-use serde::de::{Deserializer, Visitor};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use serenity::prelude::*;
+use serenity::http::{GuildPagination, Http};
+use serenity::model::id::{GuildId, UserId};
+
+use discord_rolepersist::{describe_intents, is_fatal_gateway_error, load_config, read_config, vacuum, Handler, DATABASE_PATH};
+use discord_rolepersist::clock::SystemClock;
+
+// How long shutting down waits for queued work to be finished.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+// The most guilds Discord will return from a single guild list request.
+const GUILD_PAGE_SIZE: u64 = 200;
+
+// The wait before reconnecting to the gateway the first time, doubled for
+// each failure in a row after.
+const RECONNECT_BASE_DELAY: Duration = Duration::from_secs(5);
+
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+
+// A client which stayed connected at least this long before failing had
+// recovered, so its failure starts a fresh run of retries.
+const RECONNECT_STABLE_AFTER: Duration = Duration::from_secs(10 * 60);
+
+async fn explain(server_id: Option<String>, user_id: Option<String>) {
+    let parse_id = |id: Option<String>| id.and_then(|id| id.parse::<u64>().ok()).filter(|id| *id != 0);
+    let (server_id, user_id) = match (parse_id(server_id), parse_id(user_id)) {
+        (Some(server_id), Some(user_id)) => (GuildId::new(server_id), UserId::new(user_id)),
+        _ => {
+            println!("Usage: rolepersist explain <server id> <user id>");
+            std::process::exit(1);
+        },
+    };
+
+    let config = read_config();
+    let http = Http::new(&config.token);
+    let handler = Handler::new(config, DATABASE_PATH, Arc::new(SystemClock)).unwrap();
+
+    if let Err(error) = handler.explain_restore(&http, server_id, user_id).await {
+        println!("Error explaining restore: {}", error);
+        std::process::exit(1);
+    }
+}
 
-use weak_table::WeakValueHashMap;
+// Lists guilds with stored data which the bot is no longer in, deleting their
+// data if asked to.
+async fn db_orphans(purge: bool) {
+    let config = read_config();
+    let http = Http::new(&config.token);
+    let handler = Handler::new(config, DATABASE_PATH, Arc::new(SystemClock)).unwrap();
+
+    let mut current = HashSet::new();
+    let mut after = None;
+    loop {
+        let page = match http.get_guilds(after.map(GuildPagination::After), Some(GUILD_PAGE_SIZE)).await {
+            Ok(page) => page,
+            Err(error) => {
+                println!("Error fetching guilds: {}", error);
+                std::process::exit(1);
+            },
+        };
 
-struct SimpleMember {
-    joined_at: i64,
-    user_id: u64,
-    server_id: u64,
-    roles: Vec<u64>,
-}
+        after = page.last().map(|guild| guild.id);
+        let done = (page.len() as u64) < GUILD_PAGE_SIZE;
+        current.extend(page.into_iter().map(|guild| guild.id));
 
-impl From<&Member> for SimpleMember {
-    fn from(member: &Member) -> Self {
-        SimpleMember {
-            joined_at: member.joined_at.unwrap_or_default().unix_timestamp(),
-            user_id: member.user.id.get(),
-            server_id: member.guild_id.get(),
-            roles: member.roles.iter().cloned().map(|r| r.get()).collect(),
+        if done {
+            break;
         }
     }
-}
 
-impl From<Member> for SimpleMember {
-    fn from(member: Member) -> Self {
-        Self::from(&member)
+    let orphans = match handler.orphaned_guilds(&current, |_| true).await {
+        Ok(orphans) => orphans,
+        Err(error) => {
+            println!("Error reading stored guilds: {}", error);
+            std::process::exit(1);
+        },
+    };
+
+    if orphans.is_empty() {
+        println!("No orphaned guilds, the bot is in all {} guilds with stored data", current.len());
+        return;
     }
-}
 
-impl From<&GuildMemberUpdateEvent> for SimpleMember {
-    fn from(member: &GuildMemberUpdateEvent) -> Self {
-        SimpleMember {
-            joined_at: member.joined_at.unix_timestamp(),
-            user_id: member.user.id.get(),
-            server_id: member.guild_id.get(),
-            roles: member.roles.iter().cloned().map(|r| r.get()).collect(),
+    for server_id in &orphans {
+        if purge {
+            if let Err(error) = handler.forget_guild(*server_id).await {
+                println!("Error removing data for guild {}: {}", server_id.get(), error);
+                std::process::exit(1);
+            }
+            println!("Removed data for guild {}", server_id.get());
+        } else {
+            println!("Guild {} has stored data but the bot is no longer in it", server_id.get());
         }
     }
-}
 
-impl From<GuildMemberUpdateEvent> for SimpleMember {
-    fn from(member: GuildMemberUpdateEvent) -> Self {
-        Self::from(&member)
+    if !purge {
+        println!("{} orphaned guilds, run with --purge to remove their data", orphans.len());
     }
 }
 
-struct Handler {
-    data: Mutex<Connection>,
-    config: Config,
-    member_locks: Mutex<WeakValueHashMap<(UserId, GuildId), Weak<Mutex<()>>>>,
-}
-
-impl Handler {
-    pub fn new(config: Config) -> Result<Self> {
-        let connection = Connection::open("data.db")?;
-
-        connection.execute(
-            "CREATE TABLE IF NOT EXISTS roles(
-                user_id NUMBER,
-                server_id NUMBER,
-                role_id NUMBER
-            )", 
-            []
-        )?;
-
-        connection.execute(
-            "CREATE TABLE IF NOT EXISTS last_seen(
-                user_id NUMBER,
-                server_id NUMBER,
-                time INTEGER,
-                PRIMARY KEY(user_id, server_id)
-            )", 
-            []
-        )?;
-
-        Ok(Self {
-            data: Mutex::new(connection),
-            config,
-            member_locks: Mutex::new(WeakValueHashMap::new()),
-        })
-    }
-
-    pub async fn save_member(&self, member: &SimpleMember) {
-        let now = std::time::SystemTime::now();
-        let since_epoch = now.duration_since(std::time::UNIX_EPOCH).unwrap();
-
-        let mut connection = self.data.lock().await;
-        let transaction = connection.transaction().unwrap();
-
-        transaction.execute(
-            "REPLACE INTO last_seen (user_id, server_id, time) VALUES (?1, ?2, ?3)",
-            [member.user_id, member.server_id, since_epoch.as_secs()],
-        ).unwrap();
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
 
-        transaction.execute(
-            "DELETE FROM roles WHERE user_id=?1 AND server_id=?2",
-            [member.user_id, member.server_id],
-        ).unwrap();
+        let mut terminate = signal(SignalKind::terminate())
+            .expect("Unable to listen for SIGTERM");
 
-        for role_id in &member.roles {
-            transaction.execute(
-                "INSERT INTO roles (user_id, server_id, role_id) VALUES (?1, ?2, ?3)",
-                [member.user_id, member.server_id, *role_id],
-            ).unwrap();
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {},
+            _ = terminate.recv() => {},
         }
-
-        transaction.commit().unwrap();
     }
 
-    pub async fn restore_member(
-        &self, 
-        context: &Context, 
-        member: &mut SimpleMember
-    ) {
-        let connection = self.data.lock().await;
-        let roles: Vec<RoleId> = {
-            let mut roles_query = connection.prepare(
-                "SELECT role_id FROM roles 
-                WHERE user_id=?1 AND server_id=?2",
-            ).unwrap();
-
-            roles_query.query_map(
-                [member.user_id, member.server_id],
-                |row| Ok(RoleId::new(row.get(0)?))
-            ).unwrap().collect::<Result<_>>().unwrap()
-        };
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.expect("Unable to listen for interrupts");
+}
 
-        for role in roles {
-            if !member.roles.contains(&role.get()) {
-                let role_add_attempt = context.http.add_member_role(
-                    GuildId::new(member.server_id), 
-                    UserId::new(member.user_id), 
-                    role,
-                    Some("Granting previously assigned roles"),
-                ).await;
-
-                if let Err(error) = role_add_attempt {
-                    println!(
-                        "error restoring role {} for member {} in server {}: {:?}", 
-                        role.get(), 
-                        member.user_id, 
-                        member.server_id,
-                        error,
-                    );
-                } else {
-                    member.roles.push(role.get());
-                }
-           }
-        }
+// Resolves once a shutdown has been asked for, checking now and then since
+// the signal task may have already finished.
+async fn wait_for_shutdown(shutting_down: &AtomicBool) {
+    while !shutting_down.load(Ordering::Relaxed) {
+        tokio::time::sleep(Duration::from_millis(250)).await;
     }
+}
 
-    async fn last_seen(&self, member: &SimpleMember) -> Option<i64> {
-        let connection = self.data.lock().await;
-        let mut last_seen_query = connection.prepare(
-            "SELECT time FROM last_seen 
-            WHERE user_id=?1 AND server_id=?2",
-        ).unwrap();
+// Reloads the parts of the config which can change at runtime whenever the
+// process is sent SIGHUP.
+async fn reload_on_hangup(handler: Arc<Handler>) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
 
-        let last_seen: Vec<i64> = last_seen_query.query_map(
-            [member.user_id, member.server_id],
-            |row| row.get::<usize, i64>(0)
-        ).unwrap().collect::<Result<_>>().unwrap();
+        let mut hangup = signal(SignalKind::hangup())
+            .expect("Unable to listen for SIGHUP");
 
-        assert!(last_seen.len() <= 1);
-        last_seen.first().copied()
-    }
-
-    pub async fn observe_member(&self, context: &Context, member: &mut SimpleMember) {
-        let key: (UserId, GuildId) = (member.user_id.into(), member.server_id.into());
-        self.do_locked(key, || async {
-            if let Some(last_seen) = self.last_seen(member).await {
-                if last_seen < member.joined_at {
-                    // Member has left and rejoined since we last observed at them.
-                    self.restore_member(context, member).await;
-                }
+        while hangup.recv().await.is_some() {
+            match load_config() {
+                Ok(config) => handler.reload_config(config).await,
+                Err(error) => println!("Not reloading config: {}", error),
             }
-            
-            self.save_member(member).await;
-        }).await;
-    }
-
-    pub async fn save_guild(&self, context: &Context, server_id: GuildId) -> std::result::Result<(), serenity::Error> {
-        let result = context.http.get_guild_members(server_id, None, None).await;
-
-        match result {
-            Ok(members) => {
-                for member in members {
-                    self.observe_member(context, &mut member.into()).await
-                }
-                Ok(())
-            },
-            Err(e) => Err(e),
         }
     }
 
-    pub async fn forget_guild(&self, server_id: GuildId) {
-        let mut connection = self.data.lock().await;
-        let transaction = connection.transaction().unwrap();
-
-        transaction.execute(
-            "DELETE FROM roles WHERE server_id=?",
-            [server_id.get()],
-        ).unwrap();
+    #[cfg(not(unix))]
+    std::mem::drop(handler);
+}
 
-        transaction.execute(
-            "DELETE FROM last_seen WHERE server_id=?",
-            [server_id.get()],
-        ).unwrap();
+async fn run() {
+    let config = read_config();
 
-        transaction.commit().unwrap();
+    let token = config.token.clone();
+    let intents = config.intents;
+    let gateway_retries = config.gateway_retries;
+    describe_intents(intents);
+    let handler = Arc::new(Handler::new(config, DATABASE_PATH, Arc::new(SystemClock)).unwrap());
+    let role_workers = handler.start_workers();
+
+    let jobs = handler.start_jobs();
+    let raid_monitor = handler.start_raid_monitor();
+    let restore_backlog = handler.start_restore_backlog();
+    let update_flusher = handler.start_update_flusher();
+
+    let reloader = tokio::spawn(reload_on_hangup(handler.clone()));
+
+    let shutting_down = Arc::new(AtomicBool::new(false));
+    {
+        let handler = handler.clone();
+        let shutting_down = shutting_down.clone();
+        tokio::spawn(async move {
+            shutdown_signal().await;
+            println!("Shutting down: disconnecting shards");
+            shutting_down.store(true, Ordering::Relaxed);
+            handler.shutdown_shards().await;
+        });
     }
 
-    pub fn filter_allow_server(&self, id: GuildId) -> bool {
-        if let Some(restrict) = &self.config.restrict {
-            restrict.is_restricted(id.get())
-        } else {
-            true
-        }
-    } 
-
-    pub async fn do_locked<
-        F: Future<Output = ()>,
-        FN: FnOnce() -> F,
-    >(
-        &self, 
-        key: (UserId, GuildId),
-        function: FN,
-    ) {
-        let mut locks = self.member_locks.lock().await;
-
-        if let Some(user_lock) = locks.get(&key) {
-            std::mem::drop(locks);
-            let _lock = user_lock.lock().await;
-            function().await;
-        } else {
-            let user_lock = Arc::new(Mutex::new(()));
-            locks.insert(key, user_lock.clone());
-            let _lock = user_lock.lock().await;
-            std::mem::drop(locks);
-            function().await;
+    let mut failures = 0;
+    let mut fatal = false;
+    loop {
+        let mut client = Client::builder(&token, intents)
+            .event_handler_arc(handler.clone()).await
+            .unwrap();
+
+        handler.set_shard_manager(client.shard_manager.clone());
+        // The signal may have come while the client was being built, before
+        // it had any shards to shut down.
+        if shutting_down.load(Ordering::Relaxed) {
+            break;
         }
-    }
-}
 
-#[async_trait]
-impl EventHandler for Handler {
-    async fn ready(&self, context: Context, ready: Ready) {
-        let guilds: Vec<_> = ready.guilds.into_iter()
-            .filter(|guild| self.filter_allow_server(guild.id))
-            .collect();
-        
-        for guild in guilds {
-            if let Err(error) = self.save_guild(&context, guild.id).await {
-                println!("Error fetching members of guild {}: {}", guild.id, error);
-            }
-        }
-    }
+        let started = Instant::now();
+        let cause = match client.start_autosharded().await {
+            Ok(()) => break,
+            Err(_) if shutting_down.load(Ordering::Relaxed) => break,
+            Err(cause) => cause,
+        };
 
-    async fn guild_create(&self, context: Context, guild: Guild, _is_new: Option<bool>) {
-        if self.filter_allow_server(guild.id) {
-            if let Err(error) = self.save_guild(&context, guild.id).await {
-                println!("Error fetching members of guild {}: {}", guild.id.get(), error);
-            }
+        if is_fatal_gateway_error(&cause) {
+            println!("Discord refused the connection, check the token and intents in the config: {:?}", cause);
+            fatal = true;
+            break;
         }
-    }
 
-    async fn guild_delete(&self, _context: Context, guild: UnavailableGuild, _full: Option<Guild>) {
-        if !guild.unavailable {
-            self.forget_guild(guild.id).await;
+        if started.elapsed() >= RECONNECT_STABLE_AFTER {
+            failures = 0;
         }
-    }
-        
-    async fn guild_member_addition(&self, context: Context, member: Member) {
-        if self.filter_allow_server(member.guild_id) {
-            self.observe_member(&context, &mut member.into()).await
+        failures += 1;
+        if failures > gateway_retries {
+            println!("Client error: {:?}, giving up after {} attempts to reconnect", cause, gateway_retries);
+            fatal = true;
+            break;
         }
-    }
-    
-    async fn guild_member_update(
-        &self, 
-        context: Context, 
-        _old: Option<Member>,
-        _new: Option<Member>,
-        update: GuildMemberUpdateEvent
-    ) {
-        if self.filter_allow_server(update.guild_id) {
-            self.observe_member(&context, &mut update.into()).await
+
+        let delay = RECONNECT_BASE_DELAY
+            .saturating_mul(1 << (failures - 1).min(16))
+            .min(RECONNECT_MAX_DELAY);
+        println!(
+            "Client error: {:?}, reconnecting in {}s (attempt {} of {})",
+            cause,
+            delay.as_secs(),
+            failures,
+            gateway_retries,
+        );
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {},
+            _ = wait_for_shutdown(&shutting_down) => break,
         }
     }
-}
-
-enum RestrictionMode {
-    Allow,
-    Deny,
-}
 
-struct RestrictionVisitor;
-
-impl<'de> Visitor<'de> for RestrictionVisitor {
-    type Value = RestrictionMode;
-    
-    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        formatter.write_str("the string 'allow' or the string 'deny'")
+    println!("Shutting down: finishing queued work");
+    handler.flush_updates(true).await;
+    let workers = handler.close_queues();
+    let drained = tokio::time::timeout(SHUTDOWN_TIMEOUT, futures::future::join_all(workers)).await;
+    if drained.is_err() {
+        println!("Shutting down: gave up waiting for queued work after {}s", SHUTDOWN_TIMEOUT.as_secs());
     }
 
-    fn visit_str<E>(self, value: &str) -> Result<Self::Value, E> 
-    where E: serde::de::Error {
-        match value {
-            "allow" => Ok(RestrictionMode::Allow),
-            "deny" => Ok(RestrictionMode::Deny),
-            _ => Err(E::custom(format!("{} is not a restriction mode", value))),
-        }
+    // Guild workers may wait on role additions, so these go once they're done.
+    for task in jobs.into_iter().chain(role_workers).chain([raid_monitor, restore_backlog, update_flusher, reloader]) {
+        task.abort();
+        let _ = task.await;
     }
-}
-
-impl<'de> Deserialize<'de> for RestrictionMode {
-    fn deserialize<D>(deserializer: D) -> Result<RestrictionMode, D::Error>
-
-    where D: Deserializer<'de> {
-        deserializer.deserialize_str(RestrictionVisitor)
+    println!("Shutting down: closing database");
+    match Arc::try_unwrap(handler) {
+        Ok(handler) => {
+            if let Err(error) = handler.close() {
+                println!("Error closing database: {}", error);
+            }
+        },
+        Err(_) => println!("Database still in use, leaving it to close on exit"),
     }
-}
-
-#[derive(Deserialize)]
-struct Restriction {
-    mode: RestrictionMode,
-    servers: Vec<u64>,
-}
-
-impl Restriction {
-    pub fn is_restricted(&self, server_id: u64) -> bool {
-        let is_listed = self.servers.iter()
-            .find(|id| **id == server_id);
 
-        match self.mode {
-            RestrictionMode::Allow => is_listed.is_some(),
-            RestrictionMode::Deny => is_listed.is_none(),
-        }
+    println!("Shut down");
+    if fatal {
+        std::process::exit(1);
     }
 }
 
-#[derive(Deserialize)]
-struct Config {
-    token: String,
-    restrict: Option<Restriction>
-}
-
 #[tokio::main]
 async fn main() {
-    let config_contents = fs::read_to_string("config.json")
-        .expect("Unable to read config file");
-    let config: Config = serde_json::from_str(&config_contents)
-        .expect("Unable to parse config file");
-
-    let token = config.token.clone();
-    let intents = GatewayIntents::GUILDS | GatewayIntents::GUILD_MEMBERS;
-    let handler = Handler::new(config).unwrap();
-
-    let mut client = Client::builder(&token, intents)
-        .event_handler(handler).await
-        .unwrap();
-    
-    if let Err(cause) = client.start_autosharded().await {
-        println!("Client error: {:?}", cause);
+    let mut args = std::env::args().skip(1);
+    let command = args.next();
+
+    match command.as_deref() {
+        None => run().await,
+        Some("vacuum") => vacuum(DATABASE_PATH).expect("Unable to vacuum database"),
+        Some("explain") => explain(args.next(), args.next()).await,
+        Some("db") => match (args.next().as_deref(), args.next().as_deref()) {
+            (Some("orphans"), None) => db_orphans(false).await,
+            (Some("orphans"), Some("--purge")) => db_orphans(true).await,
+            _ => {
+                println!("Usage: rolepersist db orphans [--purge]");
+                std::process::exit(1);
+            },
+        },
+        Some(command) => {
+            println!("Unknown command: {}", command);
+            println!("Usage: rolepersist [vacuum | explain <server id> <user id> | db orphans [--purge]]");
+            std::process::exit(1);
+        },
     }
 }
@@ -0,0 +1,161 @@
+use std::collections::{HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use serenity::prelude::Context;
+use serenity::model::id::GuildId;
+
+use tokio::sync::Notify;
+
+use crate::SimpleMember;
+
+// Why a member is being observed.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Origin {
+    Join,
+    Update,
+    // Listed by a guild sync, restores found this way are held back so a sync
+    // turning up many rejoins doesn't set off a burst of role changes.
+    Sync,
+    // Listed by a sync staff asked for, which only records what members have
+    // and never restores.
+    Resync,
+    // A restore held back by a sync, an outage or a raid, now being carried
+    // out.
+    Backlog,
+    // A restore put off by the guild's restore delay, now due.
+    Delayed,
+    // A restore held for staff approval, now decided.
+    Approval,
+    // An update held back while more came in for the member, now due to be
+    // stored.
+    Debounced,
+}
+
+pub enum Task {
+    // A member to observe, joins may need their roles restored.
+    Observe {
+        member: SimpleMember,
+        origin: Origin,
+    },
+    // A member to store as they are, without restoring anything.
+    Save(SimpleMember),
+    // A member to give back all their stored roles, asked for by staff.
+    Restore(SimpleMember),
+    ForgetGuild(GuildId),
+}
+
+pub struct Work {
+    pub context: Context,
+    pub task: Task,
+}
+
+impl Work {
+    // Updates and syncs carry the member's whole state, so a later 
+    // observation of them supersedes one. Restores and deletions have no such
+    // replacement.
+    fn is_droppable(&self) -> bool {
+        matches!(self.task, Task::Observe { origin: Origin::Update | Origin::Sync | Origin::Resync, .. })
+    }
+
+    // The member observed, if this is an observation.
+    fn observed(&self) -> Option<(u64, u64)> {
+        match &self.task {
+            Task::Observe { member, .. } => Some((member.user_id, member.server_id)),
+            _ => None,
+        }
+    }
+}
+
+// The oldest work which can be dropped for a later observation of the same 
+// member queued behind it.
+fn superseded(items: &VecDeque<Work>) -> Option<usize> {
+    let mut later = HashSet::new();
+    let mut oldest = None;
+    for (index, work) in items.iter().enumerate().rev() {
+        if let Some(member) = work.observed() {
+            if !later.insert(member) && work.is_droppable() {
+                oldest = Some(index);
+            }
+        }
+    }
+    oldest
+}
+
+pub struct WorkQueue {
+    items: std::sync::Mutex<VecDeque<Work>>,
+    capacity: usize,
+    available: Notify,
+    closed: AtomicBool,
+    // Work queued or still being processed.
+    outstanding: AtomicUsize,
+}
+
+impl WorkQueue {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            items: std::sync::Mutex::new(VecDeque::new()),
+            capacity,
+            available: Notify::new(),
+            closed: AtomicBool::new(false),
+            outstanding: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn push(&self, work: Work) {
+        if self.closed.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let mut items = self.items.lock().unwrap();
+        items.push_back(work);
+        self.outstanding.fetch_add(1, Ordering::Relaxed);
+
+        if items.len() > self.capacity {
+            // If nothing queued has been superseded, the new work included, 
+            // the queue is allowed to grow past capacity rather than lose any
+            // of it.
+            if let Some(index) = superseded(&items) {
+                items.remove(index);
+                self.outstanding.fetch_sub(1, Ordering::Relaxed);
+                println!("Work queue full, dropped the oldest member update with a newer one queued");
+            }
+        }
+        std::mem::drop(items);
+        self.available.notify_one();
+    }
+
+    // Waits for the next piece of work, returning None only once the queue
+    // is both closed and empty.
+    pub async fn pop(&self) -> Option<Work> {
+        loop {
+            let notified = self.available.notified();
+
+            {
+                let mut items = self.items.lock().unwrap();
+                if let Some(work) = items.pop_front() {
+                    return Some(work);
+                }
+
+                if self.closed.load(Ordering::Relaxed) {
+                    return None;
+                }
+            }
+
+            notified.await;
+        }
+    }
+
+    // Marks a piece of work taken with pop as finished.
+    pub fn finish(&self) {
+        self.outstanding.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.outstanding.load(Ordering::Relaxed) == 0
+    }
+
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.available.notify_waiters();
+    }
+}
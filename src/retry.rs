@@ -0,0 +1,131 @@
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::BuildHasher;
+use std::time::{Duration, Instant};
+
+use serenity::gateway::GatewayError;
+use serenity::http::HttpError;
+
+// How many times a request is tried before giving up.
+const MAX_ATTEMPTS: u32 = 5;
+
+// The delay before the first retry, doubled for each one after.
+const BASE_DELAY: Duration = Duration::from_secs(1);
+
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(PartialEq)]
+pub enum Failure {
+    // Discord or the network is having trouble, trying again later may work.
+    Transient,
+    // The bot isn't allowed to do this, retrying won't help.
+    Forbidden,
+    Permanent,
+}
+
+pub fn classify(error: &serenity::Error) -> Failure {
+    match error {
+        serenity::Error::Http(HttpError::UnsuccessfulRequest(response)) => {
+            let status = response.status_code;
+            // Serenity already waits out the Retry-After of rate limited
+            // requests, any 429 which still gets here is treated like an
+            // outage.
+            if status.is_server_error() || status.as_u16() == 429 {
+                Failure::Transient
+            } else if status.as_u16() == 403 {
+                Failure::Forbidden
+            } else {
+                Failure::Permanent
+            }
+        },
+        serenity::Error::Http(HttpError::Request(_)) => Failure::Transient,
+        _ => Failure::Permanent,
+    }
+}
+
+// Whether Discord refused a request because the bot lacks permission, which
+// for role changes includes the role being above the bot's highest role.
+pub fn is_missing_permissions(error: &serenity::Error) -> bool {
+    const MISSING_PERMISSIONS: isize = 50013;
+
+    match error {
+        serenity::Error::Http(HttpError::UnsuccessfulRequest(response)) => {
+            response.error.code == MISSING_PERMISSIONS
+        },
+        _ => false,
+    }
+}
+
+// Whether Discord doesn't know the member (or user) a request was about, 
+// which for role changes means they've left the server.
+pub fn is_unknown_member(error: &serenity::Error) -> bool {
+    const UNKNOWN_MEMBER: isize = 10007;
+    const UNKNOWN_USER: isize = 10013;
+
+    match error {
+        serenity::Error::Http(HttpError::UnsuccessfulRequest(response)) => {
+            matches!(response.error.code, UNKNOWN_MEMBER | UNKNOWN_USER)
+        },
+        _ => false,
+    }
+}
+
+// Whether connecting to the gateway failed in a way reconnecting won't fix,
+// such as the token being invalid or the bot not being allowed its intents.
+pub fn is_fatal_gateway_error(error: &serenity::Error) -> bool {
+    match error {
+        serenity::Error::Gateway(error) => matches!(
+            error,
+            GatewayError::InvalidAuthentication
+                | GatewayError::InvalidGatewayIntents
+                | GatewayError::DisallowedGatewayIntents
+        ),
+        serenity::Error::Http(HttpError::UnsuccessfulRequest(response)) => {
+            response.status_code.as_u16() == 401
+        },
+        _ => false,
+    }
+}
+
+// Makes a request, retrying transient failures with jittered exponential
+// backoff.
+pub async fn with_backoff<T, F, Fut>(request: F) -> Result<T, serenity::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, serenity::Error>>,
+{
+    with_limits(MAX_ATTEMPTS, None, request).await
+}
+
+// Like with_backoff, but with a given number of attempts and optionally a 
+// deadline past which no retry will be waited for. A transient error coming 
+// back means the retries ran out.
+pub async fn with_limits<T, F, Fut>(
+    max_attempts: u32,
+    deadline: Option<Instant>,
+    mut request: F,
+) -> Result<T, serenity::Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, serenity::Error>>,
+{
+    let jitter = RandomState::new();
+    let mut delay = BASE_DELAY;
+    let mut attempt = 1;
+
+    loop {
+        match request().await {
+            Err(error) if attempt < max_attempts && classify(&error) == Failure::Transient => {
+                let wait = delay + Duration::from_millis(jitter.hash_one(attempt) % (delay.as_millis() as u64 / 2 + 1));
+                if deadline.is_some_and(|deadline| Instant::now() + wait > deadline) {
+                    return Err(error);
+                }
+                println!("Request failed ({}), retrying in {:.1}s", error, wait.as_secs_f64());
+                tokio::time::sleep(wait).await;
+                delay = (delay * 2).min(MAX_DELAY);
+                attempt += 1;
+            },
+            result => return result,
+        }
+    }
+}
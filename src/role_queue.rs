@@ -0,0 +1,99 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use serenity::http::Http;
+use serenity::model::id::{GuildId, RoleId, UserId};
+
+use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::time::{Interval, MissedTickBehavior};
+
+struct RoleAdd {
+    http: Arc<Http>,
+    server_id: GuildId,
+    user_id: UserId,
+    role: RoleId,
+    reason: String,
+    done: oneshot::Sender<Result<(), serenity::Error>>,
+}
+
+struct Shared {
+    receiver: mpsc::UnboundedReceiver<RoleAdd>,
+    // Paces requests across all workers, on top of the per route limits
+    // serenity already waits out.
+    pace: Interval,
+}
+
+// Every role the bot adds goes through here, so bursts of restores across 
+// all guilds are smoothed out instead of firing at once.
+pub struct RoleQueue {
+    sender: mpsc::UnboundedSender<RoleAdd>,
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl RoleQueue {
+    pub fn new(per_second: u32) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let mut pace = tokio::time::interval(Duration::from_secs(1) / per_second.max(1));
+        pace.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        Self {
+            sender,
+            shared: Arc::new(Mutex::new(Shared { receiver, pace })),
+        }
+    }
+
+    // Starts the workers which make the requests, at most this many are in
+    // flight at once.
+    pub fn start(&self, workers: usize) -> Vec<tokio::task::JoinHandle<()>> {
+        (0..workers).map(|_| {
+            let shared = self.shared.clone();
+            tokio::spawn(async move {
+                loop {
+                    let add = {
+                        let mut shared = shared.lock().await;
+                        let add = match shared.receiver.recv().await {
+                            Some(add) => add,
+                            None => return,
+                        };
+                        // Nobody wants the role any more, such as when the
+                        // member left partway through being restored.
+                        if add.done.is_closed() {
+                            continue;
+                        }
+                        shared.pace.tick().await;
+                        add
+                    };
+
+                    let result = add.http.add_member_role(
+                        add.server_id,
+                        add.user_id,
+                        add.role,
+                        Some(&add.reason),
+                    ).await;
+
+                    // Whoever queued this may have stopped waiting.
+                    let _ = add.done.send(result);
+                }
+            })
+        }).collect()
+    }
+
+    // Queues adding a role to a member, waiting until it's been done.
+    pub async fn add_role(
+        &self,
+        http: Arc<Http>,
+        server_id: GuildId,
+        user_id: UserId,
+        role: RoleId,
+        reason: &str,
+    ) -> Result<(), serenity::Error> {
+        let (done, result) = oneshot::channel();
+        let add = RoleAdd { http, server_id, user_id, role, reason: reason.to_string(), done };
+
+        if self.sender.send(add).is_err() {
+            return Err(serenity::Error::Other("role queue is closed"));
+        }
+
+        result.await.unwrap_or(Err(serenity::Error::Other("role queue stopped before adding the role")))
+    }
+}
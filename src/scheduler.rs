@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::BuildHasher;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+
+#[derive(Clone, Default)]
+pub struct JobStatus {
+    pub runs: u64,
+    pub running: bool,
+    pub last_run: Option<Instant>,
+    pub last_duration: Option<Duration>,
+    pub last_error: Option<String>,
+}
+
+// Runs named jobs periodically once started.
+pub struct Scheduler {
+    statuses: std::sync::Mutex<HashMap<&'static str, JobStatus>>,
+    started: AtomicBool,
+    start: Notify,
+    jitter: RandomState,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self {
+            statuses: std::sync::Mutex::new(HashMap::new()),
+            started: AtomicBool::new(false),
+            start: Notify::new(),
+            jitter: RandomState::new(),
+        }
+    }
+
+    // Lets jobs begin running, they wait for this so they don't fire before
+    // the bot has connected.
+    pub fn start(&self) {
+        self.started.store(true, Ordering::Relaxed);
+        self.start.notify_waiters();
+    }
+
+    async fn wait_start(&self) {
+        loop {
+            let notified = self.start.notified();
+            if self.started.load(Ordering::Relaxed) {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    // Spawns a job which runs every interval after a random delay, so jobs
+    // with the same interval don't all fire together. A run which takes
+    // longer than the interval delays the next rather than overlapping it.
+    pub fn spawn<F, Fut>(
+        self: &Arc<Self>,
+        name: &'static str,
+        interval: Duration,
+        job: F,
+    ) -> JoinHandle<()>
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        self.statuses.lock().unwrap().insert(name, JobStatus::default());
+
+        let scheduler = self.clone();
+        tokio::spawn(async move {
+            scheduler.wait_start().await;
+
+            let max_jitter = interval.as_millis() as u64 / 10 + 1;
+            let jitter = Duration::from_millis(scheduler.jitter.hash_one(name) % max_jitter);
+            tokio::time::sleep(jitter).await;
+
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+            loop {
+                ticker.tick().await;
+                scheduler.update(name, |status| status.running = true);
+
+                let start = Instant::now();
+                let result = job().await;
+                let duration = start.elapsed();
+
+                match &result {
+                    Ok(()) => println!("Job {} ran in {}ms", name, duration.as_millis()),
+                    Err(error) => println!("Job {} failed after {}ms: {}", name, duration.as_millis(), error),
+                }
+
+                scheduler.update(name, |status| {
+                    status.runs += 1;
+                    status.running = false;
+                    status.last_run = Some(start);
+                    status.last_duration = Some(duration);
+                    status.last_error = result.err();
+                });
+            }
+        })
+    }
+
+    fn update(&self, name: &'static str, change: impl FnOnce(&mut JobStatus)) {
+        if let Some(status) = self.statuses.lock().unwrap().get_mut(name) {
+            change(status);
+        }
+    }
+
+    pub fn statuses(&self) -> Vec<(&'static str, JobStatus)> {
+        let mut statuses: Vec<_> = self.statuses.lock().unwrap().iter()
+            .map(|(name, status)| (*name, status.clone()))
+            .collect();
+        statuses.sort_by_key(|(name, _)| *name);
+        statuses
+    }
+}
@@ -0,0 +1,394 @@
+use std::collections::HashSet;
+
+use rusqlite::{Connection, OptionalExtension, Result};
+
+use serde::{Deserialize, Serialize};
+
+// Which layout members' stored roles are kept in.
+#[derive(Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    // A row for each role each member has, the original layout.
+    #[default]
+    Rows,
+    // A single row for each member holding a JSON snapshot.
+    Json,
+}
+
+impl Layout {
+    fn other(self) -> Self {
+        match self {
+            Layout::Rows => Layout::Json,
+            Layout::Json => Layout::Rows,
+        }
+    }
+}
+
+// What a snapshot keeps of a member besides their roles. The rows layout 
+// leaves nicknames to last_seen and doesn't keep timeouts.
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq)]
+pub struct Extras {
+    #[serde(default)]
+    pub nick: Option<String>,
+    // When their timeout ends, as a unix timestamp.
+    #[serde(default)]
+    pub timeout: Option<i64>,
+}
+
+// Where members' roles are kept, everything else about them is stored the
+// same whichever layout is used.
+pub trait Storage: Send + Sync {
+    // The table members' roles are kept in.
+    fn table(&self) -> &'static str;
+
+    fn create(&self, connection: &Connection) -> Result<()>;
+
+    fn roles(&self, connection: &Connection, user_id: u64, server_id: u64) -> Result<Vec<u64>>;
+
+    // Replaces the roles stored for a member, returning which roles weren't
+    // stored before.
+    fn set_roles(&self, connection: &Connection, user_id: u64, server_id: u64, roles: &HashSet<u64>) -> Result<Vec<u64>>;
+
+    // Every role stored for any member of a guild.
+    fn guild_roles(&self, connection: &Connection, server_id: u64) -> Result<Vec<u64>>;
+
+    // Removes roles from every member of a guild.
+    fn forget_roles(&self, connection: &Connection, server_id: u64, roles: &[u64]) -> Result<()>;
+
+    // Removes a member's roles, returning how many rows went.
+    fn forget_member(&self, connection: &Connection, user_id: u64, server_id: u64) -> Result<usize>;
+
+    fn forget_guild(&self, connection: &Connection, server_id: u64) -> Result<()>;
+
+    // Guilds with roles stored.
+    fn guilds(&self, connection: &Connection) -> Result<Vec<u64>>;
+
+    // Every member with roles stored, as (user, server, roles).
+    fn members(&self, connection: &Connection) -> Result<Vec<(u64, u64, Vec<u64>)>>;
+
+    // The rest of a member's snapshot, None for layouts which don't keep one.
+    fn extras(&self, _connection: &Connection, _user_id: u64, _server_id: u64) -> Result<Option<Extras>> {
+        Ok(None)
+    }
+
+    fn set_extras(&self, _connection: &Connection, _user_id: u64, _server_id: u64, _extras: &Extras) -> Result<()> {
+        Ok(())
+    }
+}
+
+pub fn open(layout: Layout) -> Box<dyn Storage> {
+    match layout {
+        Layout::Rows => Box::new(RowStorage),
+        Layout::Json => Box::new(JsonStorage),
+    }
+}
+
+// Moves members' roles stored in the other layout's table into this one's,
+// so switching layouts doesn't leave them behind. Returns how many members
+// were moved.
+pub fn migrate(connection: &mut Connection, layout: Layout) -> Result<usize> {
+    let (from, to) = (open(layout.other()), open(layout));
+    let exists = connection.query_row(
+        "SELECT 1 FROM sqlite_master WHERE type='table' AND name=?1",
+        [from.table()],
+        |_| Ok(()),
+    ).optional()?.is_some();
+    if !exists {
+        return Ok(0);
+    }
+
+    let transaction = connection.transaction()?;
+    let members = from.members(&transaction)?;
+    for (user_id, server_id, roles) in &members {
+        to.set_roles(&transaction, *user_id, *server_id, &roles.iter().copied().collect())?;
+    }
+    transaction.execute(&format!("DROP TABLE {}", from.table()), [])?;
+    transaction.commit()?;
+    Ok(members.len())
+}
+
+// Roles as a JSON array, for statements taking any number of them at once.
+fn json_array(roles: &[u64]) -> Result<String> {
+    serde_json::to_string(roles)
+        .map_err(|error| rusqlite::Error::ToSqlConversionFailure(Box::new(error)))
+}
+
+pub struct RowStorage;
+
+impl Storage for RowStorage {
+    fn table(&self) -> &'static str {
+        "roles"
+    }
+
+    fn create(&self, connection: &Connection) -> Result<()> {
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS roles(
+                user_id NUMBER,
+                server_id NUMBER,
+                role_id NUMBER
+            )",
+            []
+        )?;
+        Ok(())
+    }
+
+    fn roles(&self, connection: &Connection, user_id: u64, server_id: u64) -> Result<Vec<u64>> {
+        let mut roles_query = connection.prepare_cached(
+            "SELECT role_id FROM roles
+            WHERE user_id=?1 AND server_id=?2",
+        )?;
+
+        let roles = roles_query.query_map(
+            [user_id, server_id],
+            |row| row.get(0)
+        )?.collect::<Result<_>>();
+        roles
+    }
+
+    // Only the roles which changed are written, since most updates change
+    // few if any.
+    fn set_roles(&self, connection: &Connection, user_id: u64, server_id: u64, roles: &HashSet<u64>) -> Result<Vec<u64>> {
+        let stored: HashSet<u64> = self.roles(connection, user_id, server_id)?
+            .into_iter()
+            .collect();
+
+        // The roles removed and added are each passed as a JSON array, so a 
+        // member takes one statement for each however many roles changed, 
+        // with no parameter limit to chunk under and only two statements to 
+        // cache.
+        let removed: Vec<u64> = stored.difference(roles).copied().collect();
+        if !removed.is_empty() {
+            connection.prepare_cached(
+                "DELETE FROM roles WHERE user_id=?1 AND server_id=?2 
+                AND role_id IN (SELECT value FROM json_each(?3))",
+            )?.execute(rusqlite::params![user_id, server_id, json_array(&removed)?])?;
+        }
+
+        let added: Vec<u64> = roles.difference(&stored).copied().collect();
+        if !added.is_empty() {
+            connection.prepare_cached(
+                "INSERT INTO roles (user_id, server_id, role_id) 
+                SELECT ?1, ?2, value FROM json_each(?3)",
+            )?.execute(rusqlite::params![user_id, server_id, json_array(&added)?])?;
+        }
+
+        Ok(added)
+    }
+
+    fn guild_roles(&self, connection: &Connection, server_id: u64) -> Result<Vec<u64>> {
+        let mut roles_query = connection.prepare(
+            "SELECT DISTINCT role_id FROM roles
+            WHERE server_id=?",
+        )?;
+
+        let roles = roles_query.query_map(
+            [server_id],
+            |row| row.get(0)
+        )?.collect::<Result<_>>();
+        roles
+    }
+
+    fn forget_roles(&self, connection: &Connection, server_id: u64, roles: &[u64]) -> Result<()> {
+        for role_id in roles {
+            connection.execute(
+                "DELETE FROM roles WHERE server_id=?1 AND role_id=?2",
+                [server_id, *role_id],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn forget_member(&self, connection: &Connection, user_id: u64, server_id: u64) -> Result<usize> {
+        connection.execute(
+            "DELETE FROM roles WHERE user_id=?1 AND server_id=?2",
+            [user_id, server_id],
+        )
+    }
+
+    fn forget_guild(&self, connection: &Connection, server_id: u64) -> Result<()> {
+        connection.execute(
+            "DELETE FROM roles WHERE server_id=?",
+            [server_id],
+        )?;
+        Ok(())
+    }
+
+    fn guilds(&self, connection: &Connection) -> Result<Vec<u64>> {
+        let mut servers_query = connection.prepare("SELECT DISTINCT server_id FROM roles")?;
+        let servers = servers_query.query_map([], |row| row.get(0))?.collect::<Result<_>>();
+        servers
+    }
+
+    fn members(&self, connection: &Connection) -> Result<Vec<(u64, u64, Vec<u64>)>> {
+        let mut roles_query = connection.prepare(
+            "SELECT user_id, server_id, role_id FROM roles ORDER BY user_id, server_id",
+        )?;
+        let rows: Vec<(u64, u64, u64)> = roles_query.query_map(
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?.collect::<Result<_>>()?;
+
+        let mut members: Vec<(u64, u64, Vec<u64>)> = vec![];
+        for (user_id, server_id, role_id) in rows {
+            match members.last_mut() {
+                Some((user, server, roles)) if *user == user_id && *server == server_id => roles.push(role_id),
+                _ => members.push((user_id, server_id, vec![role_id])),
+            }
+        }
+        Ok(members)
+    }
+}
+
+// What's kept of a member in the JSON layout.
+#[derive(Serialize, Deserialize, Default)]
+struct Snapshot {
+    roles: Vec<u64>,
+    #[serde(flatten)]
+    extras: Extras,
+}
+
+pub struct JsonStorage;
+
+impl JsonStorage {
+    fn snapshot(connection: &Connection, user_id: u64, server_id: u64) -> Result<Option<Snapshot>> {
+        let snapshot: Option<String> = connection.query_row(
+            "SELECT snapshot FROM member_snapshots
+            WHERE user_id=?1 AND server_id=?2",
+            [user_id, server_id],
+            |row| row.get(0),
+        ).optional()?;
+
+        // A snapshot which can't be read is as good as none.
+        Ok(snapshot.and_then(|snapshot| serde_json::from_str(&snapshot).ok()))
+    }
+
+    fn guild_snapshots(connection: &Connection, server_id: u64) -> Result<Vec<(u64, Snapshot)>> {
+        let mut snapshots_query = connection.prepare(
+            "SELECT user_id, snapshot FROM member_snapshots
+            WHERE server_id=?",
+        )?;
+
+        let snapshots: Vec<(u64, String)> = snapshots_query.query_map(
+            [server_id],
+            |row| Ok((row.get(0)?, row.get(1)?))
+        )?.collect::<Result<_>>()?;
+
+        Ok(snapshots.into_iter()
+            .filter_map(|(user_id, snapshot)| Some((user_id, serde_json::from_str(&snapshot).ok()?)))
+            .collect())
+    }
+
+    fn write(connection: &Connection, user_id: u64, server_id: u64, snapshot: &Snapshot) -> Result<()> {
+        let snapshot = serde_json::to_string(snapshot)
+            .map_err(|error| rusqlite::Error::ToSqlConversionFailure(Box::new(error)))?;
+        connection.execute(
+            "REPLACE INTO member_snapshots (user_id, server_id, snapshot) VALUES (?1, ?2, ?3)",
+            rusqlite::params![user_id, server_id, snapshot],
+        )?;
+        Ok(())
+    }
+}
+
+impl Storage for JsonStorage {
+    fn table(&self) -> &'static str {
+        "member_snapshots"
+    }
+
+    fn create(&self, connection: &Connection) -> Result<()> {
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS member_snapshots(
+                user_id NUMBER,
+                server_id NUMBER,
+                snapshot TEXT,
+                PRIMARY KEY(user_id, server_id)
+            )",
+            []
+        )?;
+        Ok(())
+    }
+
+    fn roles(&self, connection: &Connection, user_id: u64, server_id: u64) -> Result<Vec<u64>> {
+        Ok(Self::snapshot(connection, user_id, server_id)?.unwrap_or_default().roles)
+    }
+
+    fn set_roles(&self, connection: &Connection, user_id: u64, server_id: u64, roles: &HashSet<u64>) -> Result<Vec<u64>> {
+        let mut snapshot = Self::snapshot(connection, user_id, server_id)?.unwrap_or_default();
+        let stored: HashSet<u64> = snapshot.roles.iter().copied().collect();
+        if stored == *roles {
+            return Ok(vec![]);
+        }
+
+        let added = roles.difference(&stored).copied().collect();
+        snapshot.roles = roles.iter().copied().collect();
+        Self::write(connection, user_id, server_id, &snapshot)?;
+        Ok(added)
+    }
+
+    fn guild_roles(&self, connection: &Connection, server_id: u64) -> Result<Vec<u64>> {
+        let roles: HashSet<u64> = Self::guild_snapshots(connection, server_id)?.into_iter()
+            .flat_map(|(_, snapshot)| snapshot.roles)
+            .collect();
+        Ok(roles.into_iter().collect())
+    }
+
+    fn forget_roles(&self, connection: &Connection, server_id: u64, roles: &[u64]) -> Result<()> {
+        for (user_id, mut snapshot) in Self::guild_snapshots(connection, server_id)? {
+            let before = snapshot.roles.len();
+            snapshot.roles.retain(|role| !roles.contains(role));
+            if snapshot.roles.len() != before {
+                Self::write(connection, user_id, server_id, &snapshot)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn forget_member(&self, connection: &Connection, user_id: u64, server_id: u64) -> Result<usize> {
+        connection.execute(
+            "DELETE FROM member_snapshots WHERE user_id=?1 AND server_id=?2",
+            [user_id, server_id],
+        )
+    }
+
+    fn forget_guild(&self, connection: &Connection, server_id: u64) -> Result<()> {
+        connection.execute(
+            "DELETE FROM member_snapshots WHERE server_id=?",
+            [server_id],
+        )?;
+        Ok(())
+    }
+
+    fn guilds(&self, connection: &Connection) -> Result<Vec<u64>> {
+        let mut servers_query = connection.prepare("SELECT DISTINCT server_id FROM member_snapshots")?;
+        let servers = servers_query.query_map([], |row| row.get(0))?.collect::<Result<_>>();
+        servers
+    }
+
+    fn members(&self, connection: &Connection) -> Result<Vec<(u64, u64, Vec<u64>)>> {
+        let mut snapshots_query = connection.prepare("SELECT user_id, server_id, snapshot FROM member_snapshots")?;
+        let snapshots: Vec<(u64, u64, String)> = snapshots_query.query_map(
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?.collect::<Result<_>>()?;
+
+        Ok(snapshots.into_iter()
+            .filter_map(|(user_id, server_id, snapshot)| {
+                let snapshot: Snapshot = serde_json::from_str(&snapshot).ok()?;
+                Some((user_id, server_id, snapshot.roles))
+            })
+            .collect())
+    }
+
+    fn extras(&self, connection: &Connection, user_id: u64, server_id: u64) -> Result<Option<Extras>> {
+        Ok(Some(Self::snapshot(connection, user_id, server_id)?.unwrap_or_default().extras))
+    }
+
+    fn set_extras(&self, connection: &Connection, user_id: u64, server_id: u64, extras: &Extras) -> Result<()> {
+        let mut snapshot = Self::snapshot(connection, user_id, server_id)?.unwrap_or_default();
+        if snapshot.extras == *extras {
+            return Ok(());
+        }
+
+        snapshot.extras = extras.clone();
+        Self::write(connection, user_id, server_id, &snapshot)
+    }
+}
@@ -0,0 +1,263 @@
+// A stand-in for Discord, an HTTP server answering API requests however a
+// test asks and a gateway which accepts connections but never says anything.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::StreamExt;
+
+use serenity::all::{Cache, Http, HttpBuilder, ShardId, ShardInfo, ShardManager, ShardManagerOptions, ShardRunner, ShardRunnerOptions};
+use serenity::gateway::{Shard, ShardMessenger};
+use serenity::model::event::GuildCreateEvent;
+use serenity::prelude::*;
+
+use serde_json::{json, Value};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+#[derive(Clone)]
+pub struct Request {
+    pub method: String,
+    // The path and query, without the /api/v10 prefix.
+    pub path: String,
+}
+
+pub struct Reply {
+    status: u16,
+    body: String,
+    delay: Duration,
+}
+
+impl Reply {
+    pub fn json(body: Value) -> Self {
+        Self { status: 200, body: body.to_string(), delay: Duration::ZERO }
+    }
+
+    pub fn empty() -> Self {
+        Self { status: 204, body: String::new(), delay: Duration::ZERO }
+    }
+
+    // An error response with one of Discord's JSON error codes.
+    pub fn error(status: u16, code: u64) -> Self {
+        let body = json!({ "code": code, "message": "Mocked error" }).to_string();
+        Self { status, body, delay: Duration::ZERO }
+    }
+
+    pub fn after(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+}
+
+type Responder = dyn Fn(&Request) -> Reply + Send + Sync;
+
+pub struct Discord {
+    pub context: Context,
+    requests: Arc<std::sync::Mutex<Vec<Request>>>,
+}
+
+impl Discord {
+    pub async fn start(respond: impl Fn(&Request) -> Reply + Send + Sync + 'static) -> Self {
+        let respond: Arc<Responder> = Arc::new(respond);
+        let requests = Arc::new(std::sync::Mutex::new(vec![]));
+
+        let api = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let api_url = format!("http://{}", api.local_addr().unwrap());
+        {
+            let requests = requests.clone();
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = api.accept().await {
+                    tokio::spawn(answer(stream, respond.clone(), requests.clone()));
+                }
+            });
+        }
+
+        let gateway = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let gateway_url = format!("ws://{}", gateway.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = gateway.accept().await {
+                if let Ok(mut socket) = tokio_tungstenite::accept_async(stream).await {
+                    tokio::spawn(async move {
+                        while socket.next().await.is_some() {}
+                    });
+                }
+            }
+        });
+
+        let http = Arc::new(HttpBuilder::new("token").proxy(api_url).ratelimiter_disabled(true).build());
+        let context = context(http, gateway_url).await;
+        Self { context, requests }
+    }
+
+    // Every request made so far, in the order they came in.
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    pub fn count(&self, method: &str, path: &str) -> usize {
+        self.requests().iter()
+            .filter(|request| request.method == method && request.path == path)
+            .count()
+    }
+
+    // Puts a guild in the cache with the given roles, as (id, position,
+    // permissions), and the bot holding the role with the given ID.
+    pub fn cache_guild(&self, server_id: u64, roles: &[(u64, u16, u64)], bot_role: Option<u64>, member_count: u64) {
+        let bot_id = self.context.cache.current_user().id.get();
+        let roles: Vec<Value> = roles.iter()
+            .map(|(id, position, permissions)| role(*id, *position, *permissions))
+            .collect();
+        let members: Vec<Value> = bot_role.iter()
+            .map(|role| member(bot_id, server_id, &[*role], Some(0)))
+            .collect();
+
+        let mut event: GuildCreateEvent = serde_json::from_value(json!({
+            "id": server_id.to_string(),
+            "name": "Guild",
+            "icon": null,
+            "icon_hash": null,
+            "splash": null,
+            "discovery_splash": null,
+            "owner_id": "1",
+            "verification_level": 0,
+            "default_message_notifications": 0,
+            "explicit_content_filter": 0,
+            "roles": roles,
+            "emojis": [],
+            "features": [],
+            "mfa_level": 0,
+            "system_channel_flags": 0,
+            "preferred_locale": "en-US",
+            "premium_tier": 0,
+            "nsfw_level": 0,
+            "stickers": [],
+            "premium_progress_bar_enabled": false,
+            "joined_at": "2020-01-01T00:00:00Z",
+            "large": false,
+            "member_count": member_count,
+            "voice_states": [],
+            "members": members,
+            "channels": [],
+            "threads": [],
+            "presences": [],
+            "stage_instances": [],
+            "guild_scheduled_events": [],
+        })).unwrap();
+        self.context.cache.update(&mut event);
+    }
+}
+
+pub fn role(id: u64, position: u16, permissions: u64) -> Value {
+    json!({
+        "id": id.to_string(),
+        "color": 0,
+        "colors": { "primary_color": 0, "secondary_color": null, "tertiary_color": null },
+        "hoist": false,
+        "managed": false,
+        "name": format!("Role {}", id),
+        "permissions": permissions.to_string(),
+        "position": position,
+    })
+}
+
+// A member as Discord sends them, joined_at is unix seconds.
+pub fn member(user_id: u64, server_id: u64, roles: &[u64], joined_at: Option<i64>) -> Value {
+    let joined_at = joined_at.map(|joined_at| serenity::model::Timestamp::from_unix_timestamp(joined_at).unwrap());
+    json!({
+        "user": { "id": user_id.to_string(), "username": format!("user{}", user_id) },
+        "roles": roles.iter().map(|role| role.to_string()).collect::<Vec<_>>(),
+        "joined_at": joined_at,
+        "deaf": false,
+        "mute": false,
+        "flags": 0,
+        "guild_id": server_id.to_string(),
+    })
+}
+
+async fn context(http: Arc<Http>, gateway_url: String) -> Context {
+    let data = Arc::new(RwLock::new(TypeMap::new()));
+    let cache = Arc::new(Cache::new());
+    let ws_url = Arc::new(Mutex::new(gateway_url));
+
+    let (manager, _) = ShardManager::new(ShardManagerOptions {
+        data: data.clone(),
+        event_handlers: vec![],
+        raw_event_handlers: vec![],
+        shard_index: 0,
+        shard_init: 0,
+        shard_total: 1,
+        ws_url: ws_url.clone(),
+        cache: cache.clone(),
+        http: http.clone(),
+        intents: GatewayIntents::empty(),
+        presence: None,
+    });
+
+    let info = ShardInfo { id: ShardId(0), total: 1 };
+    let shard = Shard::new(ws_url, "token", info, GatewayIntents::empty(), None).await.unwrap();
+    let runner = ShardRunner::new(ShardRunnerOptions {
+        data: data.clone(),
+        event_handlers: vec![],
+        raw_event_handlers: vec![],
+        manager,
+        shard,
+        cache: cache.clone(),
+        http: http.clone(),
+    });
+
+    Context {
+        data,
+        shard: ShardMessenger::new(&runner),
+        shard_id: ShardId(0),
+        http,
+        cache,
+    }
+}
+
+// Answers a single request, closing the connection after.
+async fn answer(mut stream: TcpStream, respond: Arc<Responder>, requests: Arc<std::sync::Mutex<Vec<Request>>>) {
+    let mut received = vec![];
+    let mut buffer = [0; 4096];
+    let header_end = loop {
+        let read = match stream.read(&mut buffer).await {
+            Ok(0) | Err(_) => return,
+            Ok(read) => read,
+        };
+        received.extend_from_slice(&buffer[..read]);
+        if let Some(end) = received.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end + 4;
+        }
+    };
+
+    let head = String::from_utf8_lossy(&received[..header_end]).into_owned();
+    let content_length = head.lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse().ok())
+        .unwrap_or(0);
+    while received.len() < header_end + content_length {
+        match stream.read(&mut buffer).await {
+            Ok(0) | Err(_) => return,
+            Ok(read) => received.extend_from_slice(&buffer[..read]),
+        }
+    }
+
+    let mut request_line = head.lines().next().unwrap_or_default().split(' ');
+    let request = Request {
+        method: request_line.next().unwrap_or_default().to_string(),
+        path: request_line.next().unwrap_or_default().trim_start_matches("/api/v10").to_string(),
+    };
+    requests.lock().unwrap().push(request.clone());
+
+    let reply = respond(&request);
+    tokio::time::sleep(reply.delay).await;
+    let response = format!(
+        "HTTP/1.1 {} Mocked\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        reply.status,
+        reply.body.len(),
+        reply.body,
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
@@ -0,0 +1,88 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use rusqlite::{Connection, Result};
+
+use serde_json::json;
+
+use crate::storage::{RowStorage, Storage};
+use crate::Origin;
+
+use super::{member, server, Harness, NOW, SERVER, USER};
+
+// A member whose roles can't be written.
+const FAILING: u64 = 3_000;
+
+// A member whose roles panic when they're written.
+const PANICKING: u64 = 3_001;
+
+// Stores roles in rows, except for members it's been set to break on.
+struct BrokenStorage(RowStorage);
+
+impl Storage for BrokenStorage {
+    fn table(&self) -> &'static str {
+        self.0.table()
+    }
+
+    fn create(&self, connection: &Connection) -> Result<()> {
+        self.0.create(connection)
+    }
+
+    fn roles(&self, connection: &Connection, user_id: u64, server_id: u64) -> Result<Vec<u64>> {
+        self.0.roles(connection, user_id, server_id)
+    }
+
+    fn set_roles(&self, connection: &Connection, user_id: u64, server_id: u64, roles: &HashSet<u64>) -> Result<Vec<u64>> {
+        match user_id {
+            FAILING => Err(rusqlite::Error::InvalidQuery),
+            PANICKING => panic!("Storage broke for member {}", user_id),
+            _ => self.0.set_roles(connection, user_id, server_id, roles),
+        }
+    }
+
+    fn guild_roles(&self, connection: &Connection, server_id: u64) -> Result<Vec<u64>> {
+        self.0.guild_roles(connection, server_id)
+    }
+
+    fn forget_roles(&self, connection: &Connection, server_id: u64, roles: &[u64]) -> Result<()> {
+        self.0.forget_roles(connection, server_id, roles)
+    }
+
+    fn forget_member(&self, connection: &Connection, user_id: u64, server_id: u64) -> Result<usize> {
+        self.0.forget_member(connection, user_id, server_id)
+    }
+
+    fn forget_guild(&self, connection: &Connection, server_id: u64) -> Result<()> {
+        self.0.forget_guild(connection, server_id)
+    }
+
+    fn guilds(&self, connection: &Connection) -> Result<Vec<u64>> {
+        self.0.guilds(connection)
+    }
+
+    fn members(&self, connection: &Connection) -> Result<Vec<(u64, u64, Vec<u64>)>> {
+        self.0.members(connection)
+    }
+}
+
+#[tokio::test]
+async fn storage_errors_only_fail_their_own_event() {
+    let harness = Harness::start_with(json!({}), server(&[10]), |handler| {
+        handler.storage = Arc::new(BrokenStorage(RowStorage));
+    }).await;
+    let context = &harness.discord.context;
+
+    assert!(harness.handler.save_member(&member(FAILING, &[10], Some(NOW))).await.is_err());
+    assert!(harness.handler.save_member(&member(PANICKING, &[10], Some(NOW))).await.is_err());
+
+    for user_id in [FAILING, PANICKING, USER] {
+        harness.handler.enqueue_observe(context, member(user_id, &[10], Some(NOW)), Origin::Join);
+    }
+    harness.handler.wait_idle().await;
+
+    assert!(harness.stored_roles(FAILING).is_empty());
+    assert!(harness.stored_roles(PANICKING).is_empty());
+    assert_eq!(harness.stored_roles(USER), [10]);
+    let counts = harness.handler.stats.take_guild_counts();
+    assert_eq!(counts.get(&SERVER).map(|counts| counts.errors), Some(2));
+}
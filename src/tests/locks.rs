@@ -0,0 +1,130 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use serde_json::json;
+
+use serenity::model::id::{GuildId, UserId};
+
+use crate::Origin;
+
+use super::discord::{self, Reply};
+use super::{member, server, Harness, NOW, SERVER, USER};
+
+fn key() -> (UserId, GuildId) {
+    (UserId::new(USER), GuildId::new(SERVER))
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn do_locked_never_runs_twice_at_once_for_a_member() {
+    let harness = Harness::start(json!({}), |_| unreachable!()).await;
+    let inside = Arc::new(AtomicBool::new(false));
+    let count = Arc::new(AtomicUsize::new(0));
+
+    let tasks: Vec<_> = (0..200).map(|_| {
+        let handler = harness.handler.clone();
+        let (inside, count) = (inside.clone(), count.clone());
+        tokio::spawn(async move {
+            handler.do_locked(key(), || async {
+                assert!(!inside.swap(true, Ordering::SeqCst), "Two holders of one member's lock");
+                // Counting without an atomic increment loses counts unless
+                // nobody else is in here.
+                let seen = count.load(Ordering::SeqCst);
+                tokio::task::yield_now().await;
+                count.store(seen + 1, Ordering::SeqCst);
+                inside.store(false, Ordering::SeqCst);
+            }).await
+        })
+    }).collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+
+    assert_eq!(count.load(Ordering::SeqCst), 200);
+    assert!(harness.handler.member_locks.is_empty());
+}
+
+#[tokio::test]
+async fn clearing_a_member_waits_for_their_queued_work() {
+    let harness = Harness::start(json!({}), |request| {
+        if request.path == format!("/guilds/{}/roles", SERVER) {
+            Reply::json(json!([discord::role(10, 1, 0)]))
+        } else if request.method == "PUT" {
+            Reply::empty().after(Duration::from_millis(300))
+        } else {
+            Reply::error(404, 10004)
+        }
+    }).await;
+    let context = &harness.discord.context;
+    harness.handler.save_member(&member(USER, &[10], Some(NOW - 60))).await.unwrap();
+
+    harness.clock.advance(600);
+    harness.handler.enqueue_observe(context, member(USER, &[], Some(NOW + 300)), Origin::Join);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    harness.handler.clear_member(UserId::new(USER), GuildId::new(SERVER)).await.unwrap();
+    harness.handler.wait_idle().await;
+
+    assert!(harness.stored_roles(USER).is_empty());
+    assert_eq!(harness.handler.last_seen(&member(USER, &[], None)).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn do_locked_returns_what_the_function_does() {
+    let harness = Harness::start(json!({}), |_| unreachable!()).await;
+
+    let value = harness.handler.do_locked(key(), || async { 42 }).await;
+
+    assert_eq!(value, 42);
+    assert!(harness.handler.member_locks.is_empty());
+}
+
+#[tokio::test]
+async fn do_locked_passes_errors_on_and_lets_go() {
+    let harness = Harness::start(json!({}), |_| unreachable!()).await;
+
+    let result: Result<(), &str> = harness.handler.do_locked(key(), || async { Err("failed") }).await;
+    assert_eq!(result, Err("failed"));
+    assert!(harness.handler.member_locks.is_empty());
+
+    // Nothing is left holding the lock after the error.
+    let next = tokio::time::timeout(
+        Duration::from_secs(1),
+        harness.handler.do_locked(key(), || async { "next" }),
+    ).await;
+    assert_eq!(next, Ok("next"));
+}
+
+#[tokio::test]
+async fn saves_from_outside_the_worker_wait_for_queued_work() {
+    let harness = Harness::start(json!({}), |request| {
+        if request.path == format!("/guilds/{}/roles", SERVER) {
+            Reply::json(json!([discord::role(10, 1, 0), discord::role(11, 2, 0)]))
+        } else if request.method == "PUT" {
+            Reply::empty().after(Duration::from_millis(300))
+        } else {
+            Reply::error(404, 10004)
+        }
+    }).await;
+    let context = &harness.discord.context;
+    harness.handler.save_member(&member(USER, &[10], Some(NOW - 60))).await.unwrap();
+
+    harness.clock.advance(600);
+    harness.handler.enqueue_observe(context, member(USER, &[], Some(NOW + 300)), Origin::Join);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    harness.handler.save_member(&member(USER, &[11], Some(NOW + 300))).await.unwrap();
+    harness.handler.wait_idle().await;
+
+    // The restore's own save came first, so it didn't undo this one.
+    assert_eq!(harness.stored_roles(USER), vec![11]);
+}
+
+#[tokio::test]
+async fn work_queued_before_the_workers_start_is_done_once_they_are() {
+    let harness = Harness::unstarted(json!({}), server(&[10])).await;
+
+    harness.handler.enqueue_observe(&harness.discord.context, member(USER, &[10], Some(NOW - 60)), Origin::Sync);
+    harness.handler.start_workers();
+    tokio::time::timeout(Duration::from_secs(5), harness.handler.wait_idle()).await.unwrap();
+
+    assert_eq!(harness.stored_roles(USER), [10]);
+}
@@ -0,0 +1,149 @@
+// Tests of the handler as a whole, run against a fake Discord and a clock
+// which only moves when told to.
+
+mod discord;
+mod failures;
+mod locks;
+mod observe;
+mod planning;
+mod queue;
+mod raid;
+mod restore;
+mod stats;
+mod storage;
+mod sync;
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serenity::model::guild::Member;
+
+use serde_json::{json, Value};
+
+use crate::clock::MockClock;
+use crate::{Config, Handler, SimpleMember};
+
+use discord::{Discord, Reply, Request};
+
+// When tests start, well after any account in them was made.
+const NOW: u64 = 1_700_000_000;
+
+const SERVER: u64 = 1_000;
+
+const USER: u64 = 2_000;
+
+// A database file of its own for each test, removed once it's done.
+struct Database(PathBuf);
+
+impl Database {
+    fn new() -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let name = format!("rolepersist-test-{}-{}.db", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed));
+        Self(std::env::temp_dir().join(name))
+    }
+}
+
+impl Drop for Database {
+    fn drop(&mut self) {
+        for suffix in ["", "-wal", "-shm"] {
+            let mut path = self.0.clone().into_os_string();
+            path.push(suffix);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+struct Harness {
+    handler: Arc<Handler>,
+    clock: Arc<MockClock>,
+    discord: Discord,
+    _database: Database,
+}
+
+impl Harness {
+    // A handler with the given config, on top of only a token, and Discord
+    // answering requests with the given function.
+    async fn start(config: Value, respond: impl Fn(&Request) -> Reply + Send + Sync + 'static) -> Self {
+        Self::start_with(config, respond, |_| ()).await
+    }
+
+    // Like start, letting the test change the handler before it's started.
+    async fn start_with(
+        config: Value,
+        respond: impl Fn(&Request) -> Reply + Send + Sync + 'static,
+        setup: impl FnOnce(&mut Handler),
+    ) -> Self {
+        let harness = Self::prepare(config, respond, setup).await;
+        harness.handler.start_workers();
+        harness
+    }
+
+    // Like start, leaving the handler's workers for the test to start.
+    async fn unstarted(config: Value, respond: impl Fn(&Request) -> Reply + Send + Sync + 'static) -> Self {
+        Self::prepare(config, respond, |_| ()).await
+    }
+
+    async fn prepare(
+        config: Value,
+        respond: impl Fn(&Request) -> Reply + Send + Sync + 'static,
+        setup: impl FnOnce(&mut Handler),
+    ) -> Self {
+        let mut full = json!({ "token": "token" });
+        full.as_object_mut().unwrap().extend(config.as_object().cloned().unwrap_or_default());
+        let config = Config::from_json(&full.to_string()).unwrap();
+
+        let database = Database::new();
+        let clock = Arc::new(MockClock::new(NOW));
+        let mut handler = Handler::new(config, &database.0, clock.clone()).unwrap();
+        setup(&mut handler);
+
+        Self {
+            handler: Arc::new(handler),
+            clock,
+            discord: Discord::start(respond).await,
+            _database: database,
+        }
+    }
+
+    fn stored_roles(&self, user_id: u64) -> Vec<u64> {
+        let connection = self.handler.data.lock().unwrap();
+        let mut roles = self.handler.stored_roles(&connection, user_id, SERVER).unwrap();
+        roles.sort_unstable();
+        roles
+    }
+
+    // Roles the bot has been asked to add to a member, in the order asked.
+    fn added_roles(&self, user_id: u64) -> Vec<u64> {
+        let prefix = format!("/guilds/{}/members/{}/roles/", SERVER, user_id);
+        self.discord.requests().iter()
+            .filter(|request| request.method == "PUT")
+            .filter_map(|request| request.path.strip_prefix(&prefix)?.parse().ok())
+            .collect()
+    }
+}
+
+// A member of the test server as serenity would hand them over.
+fn member(user_id: u64, roles: &[u64], joined_at: Option<u64>) -> SimpleMember {
+    let member: Member = serde_json::from_value(discord::member(
+        user_id,
+        SERVER,
+        roles,
+        joined_at.map(|time| time as i64),
+    )).unwrap();
+    member.into()
+}
+
+// Answers everything the handler looks up about the test server the way
+// Discord would for a server with the given roles and nothing else going on.
+fn server(roles: &'static [u64]) -> impl Fn(&Request) -> Reply + Send + Sync + 'static {
+    move |request| {
+        if request.path == format!("/guilds/{}/roles", SERVER) {
+            Reply::json(roles.iter().enumerate().map(|(position, role)| discord::role(*role, position as u16 + 1, 0)).collect())
+        } else if request.method == "PUT" {
+            Reply::empty()
+        } else {
+            Reply::error(404, 10004)
+        }
+    }
+}
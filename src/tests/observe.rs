@@ -0,0 +1,482 @@
+use serde_json::json;
+
+use serenity::model::event::GuildMemberUpdateEvent;
+use serenity::model::guild::Member;
+use serenity::model::id::{GuildId, RoleId, UserId};
+
+use crate::{Origin, SimpleMember};
+
+use super::discord::{self, Reply, Request};
+use super::{member, server, Harness, NOW, SERVER, USER};
+
+// Discord for a server replacing members' roles, where a welcome bot has 
+// given the test member role 30 since they rejoined.
+fn replacing(request: &Request) -> Reply {
+    if request.path == format!("/guilds/{}/roles", SERVER) {
+        Reply::json(json!([discord::role(10, 1, 0), discord::role(11, 2, 0), discord::role(30, 3, 0)]))
+    } else if request.path == format!("/guilds/{}/members/{}", SERVER, USER) {
+        Reply::json(discord::member(USER, SERVER, &[30], Some(NOW as i64 + 300)))
+    } else if request.method == "PUT" {
+        Reply::empty()
+    } else {
+        Reply::error(404, 10004)
+    }
+}
+
+fn replace_config() -> serde_json::Value {
+    json!({
+        "update_debounce_ms": 0,
+        "guilds": { SERVER.to_string(): { "restore_strategy": "replace" } },
+    })
+}
+
+#[tokio::test]
+async fn rejoin_after_last_seen_restores_roles() {
+    let harness = Harness::start(json!({ "update_debounce_ms": 0 }), server(&[10, 11])).await;
+    let context = &harness.discord.context;
+
+    let mut joined = member(USER, &[10, 11], Some(NOW - 60));
+    harness.handler.observe(context, &mut joined, Origin::Join).await.unwrap();
+    assert_eq!(harness.stored_roles(USER), [10, 11]);
+
+    harness.clock.advance(600);
+    let mut rejoined = member(USER, &[], Some(NOW + 300));
+    harness.handler.observe(context, &mut rejoined, Origin::Join).await.unwrap();
+
+    let mut added = harness.added_roles(USER);
+    added.sort_unstable();
+    assert_eq!(added, [10, 11]);
+    assert_eq!(harness.stored_roles(USER), [10, 11]);
+}
+
+#[tokio::test]
+async fn losing_roles_without_leaving_is_not_a_rejoin() {
+    let harness = Harness::start(json!({ "update_debounce_ms": 0 }), server(&[10, 11])).await;
+    let context = &harness.discord.context;
+
+    let mut joined = member(USER, &[10, 11], Some(NOW - 60));
+    harness.handler.observe(context, &mut joined, Origin::Join).await.unwrap();
+
+    harness.clock.advance(600);
+    let mut updated = member(USER, &[10], Some(NOW - 60));
+    harness.handler.observe(context, &mut updated, Origin::Update).await.unwrap();
+
+    assert!(harness.added_roles(USER).is_empty());
+    assert_eq!(harness.stored_roles(USER), [10]);
+}
+
+#[tokio::test]
+async fn replacing_with_nothing_stored_keeps_live_roles() {
+    let harness = Harness::start(replace_config(), replacing).await;
+    let context = &harness.discord.context;
+    harness.handler.save_member(&member(USER, &[], Some(NOW - 60))).await.unwrap();
+
+    harness.clock.advance(600);
+    let mut rejoined = member(USER, &[30], Some(NOW + 300));
+    harness.handler.observe(context, &mut rejoined, Origin::Join).await.unwrap();
+
+    assert_eq!(harness.discord.count("PATCH", &format!("/guilds/{}/members/{}", SERVER, USER)), 0);
+    assert_eq!(harness.stored_roles(USER), [30]);
+}
+
+#[tokio::test]
+async fn replacing_with_an_empty_plan_does_nothing() {
+    let harness = Harness::start(replace_config(), replacing).await;
+    let context = &harness.discord.context;
+
+    let mut rejoined = member(USER, &[30], Some(NOW + 300));
+    let summary = harness.handler.replace_roles(context, &mut rejoined, vec![], "test").await;
+
+    assert!(summary.removed.is_empty());
+    assert!(harness.discord.requests().is_empty());
+    assert_eq!(rejoined.roles, [30].into());
+}
+
+#[tokio::test]
+async fn accounts_too_young_get_nothing_back() {
+    const DISCORD_EPOCH: u64 = 1_420_070_400_000;
+    let young = ((NOW - 60 * 60) * 1000 - DISCORD_EPOCH) << 22;
+
+    let mut config = replace_config();
+    config["min_account_age_days"] = json!(7);
+    config["store_profiles"] = json!(true);
+    config["restore_nicknames"] = json!(true);
+    let harness = Harness::start(config, replacing).await;
+    let context = &harness.discord.context;
+
+    let mut joined = member(young, &[10], Some(NOW - 60));
+    joined.nick = Some("Nickname".to_string());
+    harness.handler.save_member(&joined).await.unwrap();
+
+    harness.clock.advance(600);
+    let mut rejoined = member(young, &[30], Some(NOW + 300));
+    harness.handler.observe(context, &mut rejoined, Origin::Join).await.unwrap();
+
+    assert!(harness.discord.requests().iter().all(|request| request.method == "GET"));
+    assert_eq!(harness.stored_roles(young), [30]);
+}
+
+#[tokio::test]
+async fn guilds_restores_are_restricted_from_only_save() {
+    let mut config = replace_config();
+    config["restrict_restores"] = json!({ "mode": "deny", "servers": [SERVER] });
+    config["store_profiles"] = json!(true);
+    config["restore_nicknames"] = json!(true);
+    let harness = Harness::start(config, replacing).await;
+    let context = &harness.discord.context;
+
+    let mut joined = member(USER, &[10], Some(NOW - 60));
+    joined.nick = Some("Nickname".to_string());
+    harness.handler.save_member(&joined).await.unwrap();
+
+    harness.clock.advance(600);
+    let mut rejoined = member(USER, &[30], Some(NOW + 300));
+    harness.handler.observe(context, &mut rejoined, Origin::Join).await.unwrap();
+
+    assert!(harness.discord.requests().iter().all(|request| request.method == "GET"));
+    assert_eq!(harness.stored_roles(USER), [30]);
+}
+
+#[tokio::test]
+async fn held_members_keep_their_stored_roles() {
+    let mut config = replace_config();
+    config["store_profiles"] = json!(true);
+    config["restore_nicknames"] = json!(true);
+    let harness = Harness::start(config, replacing).await;
+    let context = &harness.discord.context;
+
+    let mut joined = member(USER, &[10], Some(NOW - 60));
+    joined.nick = Some("Nickname".to_string());
+    harness.handler.save_member(&joined).await.unwrap();
+    harness.handler.set_hold(UserId::new(USER), GuildId::new(SERVER), UserId::new(1), true).await.unwrap();
+
+    harness.clock.advance(600);
+    let mut rejoined = member(USER, &[30], Some(NOW + 300));
+    harness.handler.observe(context, &mut rejoined, Origin::Join).await.unwrap();
+
+    assert!(harness.discord.requests().iter().all(|request| request.method == "GET"));
+    assert_eq!(harness.stored_roles(USER), [10]);
+}
+
+#[tokio::test]
+async fn held_members_are_restored_once_let_go() {
+    let harness = Harness::start(json!({ "update_debounce_ms": 0 }), server(&[10, 11])).await;
+    let context = &harness.discord.context;
+    let (user_id, server_id) = (UserId::new(USER), GuildId::new(SERVER));
+
+    harness.handler.save_member(&member(USER, &[10, 11], Some(NOW - 60))).await.unwrap();
+    harness.handler.set_hold(user_id, server_id, UserId::new(1), true).await.unwrap();
+
+    harness.clock.advance(600);
+    let mut rejoined = member(USER, &[], Some(NOW + 300));
+    harness.handler.observe(context, &mut rejoined, Origin::Join).await.unwrap();
+    assert!(harness.added_roles(USER).is_empty());
+
+    harness.handler.set_hold(user_id, server_id, UserId::new(1), false).await.unwrap();
+    let mut updated = member(USER, &[], Some(NOW + 300));
+    harness.handler.observe(context, &mut updated, Origin::Update).await.unwrap();
+
+    let mut added = harness.added_roles(USER);
+    added.sort_unstable();
+    assert_eq!(added, [10, 11]);
+    assert_eq!(harness.stored_roles(USER), [10, 11]);
+}
+
+#[tokio::test]
+async fn everyone_is_never_stored_or_restored() {
+    let harness = Harness::start(json!({}), server(&[10])).await;
+    let context = &harness.discord.context;
+
+    harness.handler.save_member(&member(USER, &[SERVER, 10], Some(NOW - 60))).await.unwrap();
+    assert_eq!(harness.stored_roles(USER), [10]);
+
+    // As if it had been stored before it was left out.
+    {
+        let connection = harness.handler.data.lock().unwrap();
+        harness.handler.storage.set_roles(&connection, USER, SERVER, &[SERVER, 10].into()).unwrap();
+    }
+
+    harness.clock.advance(600);
+    let mut rejoined = member(USER, &[], Some(NOW + 300));
+    harness.handler.observe(context, &mut rejoined, Origin::Join).await.unwrap();
+
+    assert_eq!(harness.added_roles(USER), [10]);
+    assert_eq!(harness.stored_roles(USER), [10]);
+}
+
+#[test]
+fn members_without_a_join_time_keep_it_missing() {
+    assert_eq!(member(USER, &[10], None).joined_at, None);
+    assert_eq!(member(USER, &[10], Some(NOW)).joined_at, Some(NOW as i64));
+}
+
+#[test]
+fn roles_from_either_member_event_are_a_set() {
+    let value = discord::member(USER, SERVER, &[10, 11, 10], Some(NOW as i64));
+    let joined: Member = serde_json::from_value(value.clone()).unwrap();
+    let updated: GuildMemberUpdateEvent = serde_json::from_value(value).unwrap();
+
+    for member in [SimpleMember::from(&joined), SimpleMember::from(joined), SimpleMember::from(&updated), SimpleMember::from(updated)] {
+        assert_eq!(member.roles, [10, 11].into());
+    }
+}
+
+#[tokio::test]
+async fn restored_roles_join_the_members_own() {
+    let harness = Harness::start(json!({}), server(&[10, 11, 12])).await;
+    harness.handler.save_member(&member(USER, &[10, 11], Some(NOW - 60))).await.unwrap();
+
+    let mut restored = member(USER, &[10, 12], Some(NOW - 60));
+    harness.handler.restore_member(&harness.discord.context, &mut restored).await.unwrap();
+
+    assert_eq!(harness.added_roles(USER), [11]);
+    assert_eq!(restored.roles, [10, 11, 12].into());
+}
+
+#[tokio::test]
+async fn missing_join_times_are_fetched() {
+    let harness = Harness::start(json!({}), |request: &Request| {
+        if request.path == format!("/guilds/{}/members/{}", SERVER, USER) {
+            Reply::json(discord::member(USER, SERVER, &[], Some(NOW as i64 + 300)))
+        } else {
+            server(&[10, 11])(request)
+        }
+    }).await;
+    let context = &harness.discord.context;
+    harness.handler.save_member(&member(USER, &[10, 11], Some(NOW - 60))).await.unwrap();
+
+    harness.clock.advance(600);
+    let mut rejoined = member(USER, &[], None);
+    harness.handler.observe(context, &mut rejoined, Origin::Join).await.unwrap();
+
+    assert_eq!(rejoined.joined_at, Some(NOW as i64 + 300));
+    let mut added = harness.added_roles(USER);
+    added.sort_unstable();
+    assert_eq!(added, [10, 11]);
+}
+
+#[tokio::test]
+async fn members_whose_join_time_cant_be_found_are_restored() {
+    let harness = Harness::start(json!({}), server(&[10, 11])).await;
+    let context = &harness.discord.context;
+    harness.handler.save_member(&member(USER, &[10, 11], Some(NOW - 60))).await.unwrap();
+
+    harness.clock.advance(600);
+    let mut rejoined = member(USER, &[], None);
+    harness.handler.observe(context, &mut rejoined, Origin::Join).await.unwrap();
+
+    let mut added = harness.added_roles(USER);
+    added.sort_unstable();
+    assert_eq!(added, [10, 11]);
+}
+
+#[tokio::test]
+async fn nicknames_are_only_given_back_when_asked_for() {
+    for restore_nicknames in [false, true] {
+        let config = json!({ "store_profiles": true, "restore_nicknames": restore_nicknames });
+        let harness = Harness::start(config, server(&[10])).await;
+        let context = &harness.discord.context;
+
+        let mut joined = member(USER, &[10], Some(NOW - 60));
+        joined.nick = Some("Nickname".to_string());
+        harness.handler.save_member(&joined).await.unwrap();
+
+        harness.clock.advance(600);
+        let mut rejoined = member(USER, &[], Some(NOW + 300));
+        harness.handler.observe(context, &mut rejoined, Origin::Join).await.unwrap();
+
+        assert_eq!(harness.added_roles(USER), [10]);
+        let edits = harness.discord.count("PATCH", &format!("/guilds/{}/members/{}", SERVER, USER));
+        assert_eq!(edits, restore_nicknames as usize);
+    }
+}
+
+#[tokio::test]
+async fn grants_are_only_looked_up_for_roles_given_since_the_last_save() {
+    let config = json!({ "record_grant_reasons": true, "update_debounce_ms": 0 });
+    let harness = Harness::start(config, server(&[10, 11, 12])).await;
+    let context = &harness.discord.context;
+    let lookups = || harness.discord.requests().iter()
+        .filter(|request| request.path.starts_with(&format!("/guilds/{}/audit-logs", SERVER)))
+        .count();
+
+    let mut synced = member(USER, &[10], Some(NOW - 60));
+    harness.handler.observe(context, &mut synced, Origin::Update).await.unwrap();
+    assert_eq!(lookups(), 0, "first save");
+
+    synced.roles.insert(11);
+    harness.handler.observe(context, &mut synced, Origin::Sync).await.unwrap();
+    assert_eq!(lookups(), 0, "sync");
+
+    synced.roles.insert(12);
+    harness.handler.observe(context, &mut synced, Origin::Update).await.unwrap();
+    assert_eq!(lookups(), 1, "update");
+}
+
+#[tokio::test]
+async fn bots_are_only_persisted_when_asked_for() {
+    for persist_bots in [false, true] {
+        let config = json!({ "persist_bots": persist_bots });
+        let harness = Harness::start(config, server(&[10])).await;
+        let context = &harness.discord.context;
+
+        let mut joined = member(USER, &[10], Some(NOW - 60));
+        joined.bot = true;
+        harness.handler.observe(context, &mut joined, Origin::Join).await.unwrap();
+
+        harness.clock.advance(600);
+        let mut rejoined = member(USER, &[], Some(NOW + 300));
+        rejoined.bot = true;
+        harness.handler.observe(context, &mut rejoined, Origin::Join).await.unwrap();
+
+        let expected: &[u64] = if persist_bots { &[10] } else { &[] };
+        assert_eq!(harness.stored_roles(USER), expected, "persist_bots: {}", persist_bots);
+        assert_eq!(harness.added_roles(USER), expected, "persist_bots: {}", persist_bots);
+    }
+}
+
+#[tokio::test]
+async fn a_join_and_update_for_one_rejoin_restore_once_in_either_order() {
+    for (order, origins) in [("join first", [Origin::Join, Origin::Update]), ("update first", [Origin::Update, Origin::Join])] {
+        let harness = Harness::start(json!({ "update_debounce_ms": 0 }), server(&[10, 11, 30])).await;
+        let context = &harness.discord.context;
+        harness.handler.save_member(&member(USER, &[10, 11], Some(NOW - 60))).await.unwrap();
+
+        // With the clock short of the join time, saving after the first 
+        // restore leaves the second event still looking like a rejoin, as
+        // when it was sent before that save. A welcome bot has given them 
+        // role 30 by the second.
+        for (origin, roles) in origins.iter().copied().zip([&[][..], &[30]].iter()) {
+            let mut rejoined = member(USER, roles, Some(NOW + 300));
+            harness.handler.observe(context, &mut rejoined, origin).await.unwrap();
+        }
+
+        let mut added = harness.added_roles(USER);
+        added.sort_unstable();
+        assert_eq!(added, [10, 11], "{}", order);
+        assert_eq!(harness.stored_roles(USER), [10, 11, 30], "{}", order);
+
+        // Past the window it's taken as another rejoin.
+        harness.clock.advance(60);
+        let mut rejoined = member(USER, &[], Some(NOW + 300));
+        harness.handler.observe(context, &mut rejoined, Origin::Update).await.unwrap();
+        assert!(harness.added_roles(USER).len() > added.len(), "{}", order);
+    }
+}
+
+#[tokio::test]
+async fn rejoins_are_found_with_nothing_cached() {
+    let harness = Harness::start(json!({}), server(&[10, 11])).await;
+    let context = &harness.discord.context;
+
+    // As stored by an earlier run, which this one has cached nothing of.
+    {
+        let connection = harness.handler.data.lock().unwrap();
+        connection.execute(
+            "INSERT INTO last_seen (user_id, server_id, time, first_seen) VALUES (?1, ?2, ?3, ?3)",
+            [USER, SERVER, NOW - 60],
+        ).unwrap();
+        harness.handler.storage.set_roles(&connection, USER, SERVER, &[10, 11].into()).unwrap();
+    }
+    assert!(harness.handler.seen_cache.get(&member(USER, &[], None)).is_none());
+
+    let mut rejoined = member(USER, &[], Some(NOW + 300));
+    harness.handler.observe(context, &mut rejoined, Origin::Join).await.unwrap();
+
+    let mut added = harness.added_roles(USER);
+    added.sort_unstable();
+    assert_eq!(added, [10, 11]);
+}
+
+fn manual_removal_count(harness: &Harness) -> u64 {
+    let connection = harness.handler.data.lock().unwrap();
+    connection.query_row("SELECT COUNT(*) FROM manual_removals", [], |row| row.get(0)).unwrap()
+}
+
+#[tokio::test]
+async fn roles_moderators_removed_are_skipped_for_one_rejoin() {
+    let config = json!({ "guilds": { SERVER.to_string(): { "skip_manual_removals": true } } });
+    let harness = Harness::start(config, server(&[10, 11])).await;
+    let context = &harness.discord.context;
+    let (user_id, server_id) = (UserId::new(USER), GuildId::new(SERVER));
+
+    harness.handler.save_member(&member(USER, &[10, 11], Some(NOW - 600))).await.unwrap();
+    harness.handler.record_manual_removals(user_id, server_id, &[RoleId::new(11)]).await.unwrap();
+
+    harness.clock.advance(600);
+    let mut rejoined = member(USER, &[], Some(NOW + 300));
+    harness.handler.observe(context, &mut rejoined, Origin::Join).await.unwrap();
+    assert_eq!(harness.added_roles(USER), [10]);
+    assert_eq!(manual_removal_count(&harness), 1);
+
+    // Leaving again, the removal was from before the stay they just left.
+    harness.clock.advance(600);
+    let mut rejoined = member(USER, &[], Some(NOW + 900));
+    harness.handler.observe(context, &mut rejoined, Origin::Join).await.unwrap();
+    assert_eq!(manual_removal_count(&harness), 0);
+}
+
+#[tokio::test]
+async fn getting_a_removed_role_back_clears_its_removal() {
+    let config = json!({
+        "update_debounce_ms": 0,
+        "guilds": { SERVER.to_string(): { "skip_manual_removals": true } },
+    });
+    let harness = Harness::start(config, server(&[10, 11])).await;
+    let context = &harness.discord.context;
+
+    harness.handler.save_member(&member(USER, &[10], Some(NOW - 600))).await.unwrap();
+    harness.handler.record_manual_removals(UserId::new(USER), GuildId::new(SERVER), &[RoleId::new(11)]).await.unwrap();
+
+    harness.clock.advance(60);
+    let mut regranted = member(USER, &[10, 11], Some(NOW - 600));
+    harness.handler.observe(context, &mut regranted, Origin::Update).await.unwrap();
+    assert_eq!(manual_removal_count(&harness), 0);
+
+    harness.clock.advance(600);
+    let mut rejoined = member(USER, &[], Some(NOW + 300));
+    harness.handler.observe(context, &mut rejoined, Origin::Join).await.unwrap();
+    let mut added = harness.added_roles(USER);
+    added.sort_unstable();
+    assert_eq!(added, [10, 11]);
+}
+
+#[tokio::test]
+async fn held_back_updates_never_overwrite_newer_events() {
+    let harness = Harness::unstarted(json!({ "update_debounce_ms": 1000 }), server(&[10, 11, 12])).await;
+    let context = &harness.discord.context;
+    harness.handler.save_member(&member(USER, &[10], Some(NOW - 60))).await.unwrap();
+
+    let mut updated = member(USER, &[10, 11], Some(NOW - 60));
+    harness.handler.observe(context, &mut updated, Origin::Update).await.unwrap();
+    assert_eq!(harness.stored_roles(USER), [10]);
+
+    // The sync is queued ahead of the flushed update, and stores it first.
+    harness.handler.enqueue_observe(context, member(USER, &[10, 11, 12], Some(NOW - 60)), Origin::Sync);
+    harness.handler.flush_updates(true).await;
+    harness.handler.start_workers();
+    harness.handler.wait_idle().await;
+
+    assert_eq!(harness.stored_roles(USER), [10, 11, 12]);
+}
+
+#[tokio::test]
+async fn approvals_with_no_staff_channel_are_denied_once() {
+    let config = json!({
+        "update_debounce_ms": 0,
+        "guilds": { SERVER.to_string(): { "require_approval": true } },
+    });
+    let harness = Harness::start(config, server(&[10, 30])).await;
+    let context = &harness.discord.context;
+    harness.handler.save_member(&member(USER, &[10], Some(NOW - 600))).await.unwrap();
+
+    harness.clock.advance(600);
+    let mut rejoined = member(USER, &[30], Some(NOW + 300));
+    harness.handler.observe(context, &mut rejoined, Origin::Join).await.unwrap();
+
+    // Saved as they are, so later events don't find the rejoin again.
+    assert_eq!(harness.stored_roles(USER), [30]);
+    let mut updated = member(USER, &[30], Some(NOW + 300));
+    harness.handler.observe(context, &mut updated, Origin::Update).await.unwrap();
+    assert!(harness.added_roles(USER).is_empty());
+}
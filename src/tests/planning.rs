@@ -0,0 +1,119 @@
+use serde_json::json;
+
+use serenity::model::guild::Role;
+use serenity::model::guild::audit_log::Change;
+use serenity::model::id::RoleId;
+use serenity::model::permissions::Permissions;
+
+use crate::{is_privileged, removed_roles, sort_by_priority, Config, SpecialRoles, Verdict};
+
+use super::discord;
+use super::{member, Harness, SERVER, USER};
+
+fn role(id: u64, permissions: Permissions) -> Role {
+    serde_json::from_value(discord::role(id, 1, permissions.bits())).unwrap()
+}
+
+#[test]
+fn priority_roles_come_first_in_listed_order() {
+    let mut roles = vec![1, 2, 3, 4, 5];
+    sort_by_priority(&mut roles, &[4, 9, 2]);
+    assert_eq!(roles, [4, 2, 1, 3, 5]);
+}
+
+#[test]
+fn without_priority_roles_the_order_is_kept() {
+    let mut roles = vec![3, 1, 2];
+    sort_by_priority(&mut roles, &[]);
+    assert_eq!(roles, [3, 1, 2]);
+
+    sort_by_priority(&mut roles, &[7, 8]);
+    assert_eq!(roles, [3, 1, 2]);
+}
+
+#[test]
+fn only_administrator_is_privileged() {
+    assert!(is_privileged(&role(10, Permissions::ADMINISTRATOR)));
+    assert!(is_privileged(&role(11, Permissions::ADMINISTRATOR | Permissions::SEND_MESSAGES)));
+    assert!(!is_privileged(&role(12, Permissions::MANAGE_ROLES | Permissions::BAN_MEMBERS)));
+    assert!(!is_privileged(&role(13, Permissions::empty())));
+}
+
+#[test]
+fn only_roles_taken_away_count_as_removed() {
+    let changes: Vec<Change> = serde_json::from_value(json!([
+        { "key": "$remove", "new_value": [{ "id": "11", "name": "Helper" }, { "id": "12", "name": "Artist" }] },
+        { "key": "$add", "new_value": [{ "id": "13", "name": "Muted" }] },
+        { "key": "nick", "old_value": "Before", "new_value": "After" },
+        { "key": "$remove", "old_value": [{ "id": "14", "name": "Old" }] },
+    ])).unwrap();
+
+    assert_eq!(removed_roles(changes), [RoleId::new(11), RoleId::new(12), RoleId::new(14)]);
+    assert!(removed_roles(vec![]).is_empty());
+}
+
+#[tokio::test]
+async fn privileged_roles_in_the_cache_are_blocked_unless_allowed() {
+    let harness = Harness::start(json!({
+        "sensitive_roles_allowed": [11],
+        "guilds": { SERVER.to_string(): { "allow_privileged_roles": [11] } },
+    }), |_| unreachable!()).await;
+
+    let administrator = Permissions::ADMINISTRATOR.bits();
+    harness.discord.cache_guild(SERVER, &[
+        (10, 1, administrator),
+        (11, 2, administrator),
+        (12, 3, 0),
+        (13, 4, Permissions::MANAGE_ROLES.bits()),
+        (20, 5, 0),
+        (30, 6, 0),
+    ], Some(20), 2);
+
+    let special = SpecialRoles::cached(&harness.discord.context, SERVER);
+    assert_eq!(special.privileged, [10, 11].into());
+
+    let connection = harness.handler.data.lock().unwrap();
+    let plan = harness.handler.plan_restore(&connection, &member(USER, &[], None), vec![10, 11, 12, 13, 30], &special).unwrap();
+    assert!(plan == [
+        (10, Verdict::Privileged),
+        (11, Verdict::Restore),
+        (12, Verdict::Restore),
+        (13, Verdict::Sensitive),
+        (30, Verdict::AboveBot),
+    ]);
+}
+
+#[tokio::test]
+async fn excluded_roles_are_reloaded_with_the_config() {
+    let harness = Harness::start(json!({ "exclude_roles": [10] }), |_| unreachable!()).await;
+    harness.discord.cache_guild(SERVER, &[(10, 1, 0), (11, 2, 0), (20, 3, 0)], Some(20), 2);
+    let special = SpecialRoles::cached(&harness.discord.context, SERVER);
+    let plan = || {
+        let connection = harness.handler.data.lock().unwrap();
+        harness.handler.plan_restore(&connection, &member(USER, &[], None), vec![10, 11], &special).unwrap()
+    };
+    assert!(plan() == [(10, Verdict::Excluded), (11, Verdict::Restore)]);
+
+    let reloaded = Config::from_json(r#"{ "token": "token", "self_assignable_roles": [11] }"#).unwrap();
+    harness.handler.reload_config(reloaded).await;
+
+    assert!(plan() == [(10, Verdict::Restore), (11, Verdict::SelfAssignable)]);
+}
+
+#[tokio::test]
+async fn excluded_roles_win_over_included_ones() {
+    let harness = Harness::start(json!({
+        "exclude_roles": [10],
+        "include_only_roles": [10, 11],
+    }), |_| unreachable!()).await;
+    harness.discord.cache_guild(SERVER, &[(10, 1, 0), (11, 2, 0), (12, 3, 0), (20, 4, 0)], Some(20), 2);
+    let special = SpecialRoles::cached(&harness.discord.context, SERVER);
+
+    let connection = harness.handler.data.lock().unwrap();
+    let plan = harness.handler.plan_restore(&connection, &member(USER, &[], None), vec![10, 11, 12], &special).unwrap();
+    assert!(plan == [(10, Verdict::Excluded), (11, Verdict::Restore), (12, Verdict::Excluded)]);
+
+    // Only what's left of both lists is stored either.
+    let stored = harness.handler.storable(&harness.discord.context, &member(USER, &[10, 11, 12], None));
+    assert_eq!(stored.roles, [11].into());
+}
@@ -0,0 +1,38 @@
+use serde_json::json;
+
+use crate::queue::{Work, WorkQueue};
+use crate::{Origin, Task};
+
+use super::{member, Harness, NOW};
+
+// The members observed by what's left in a queue, after closing it.
+async fn drain(queue: &WorkQueue) -> Vec<(u64, bool)> {
+    queue.close();
+    let mut left = vec![];
+    while let Some(work) = queue.pop().await {
+        if let Task::Observe { member, origin } = work.task {
+            left.push((member.user_id, origin == Origin::Update));
+        }
+    }
+    left
+}
+
+#[tokio::test]
+async fn full_queues_only_drop_updates_with_a_newer_one_behind() {
+    let harness = Harness::start(json!({}), |_| unreachable!()).await;
+    let observe = |user_id, origin| Work {
+        context: harness.discord.context.clone(),
+        task: Task::Observe { member: member(user_id, &[], Some(NOW)), origin },
+    };
+
+    let queue = WorkQueue::new(2);
+    queue.push(observe(1, Origin::Update));
+    queue.push(observe(2, Origin::Sync));
+    // Nothing here has been superseded, so nothing goes.
+    queue.push(observe(3, Origin::Join));
+    queue.push(observe(3, Origin::Join));
+    // Which makes the first update out of date.
+    queue.push(observe(1, Origin::Update));
+
+    assert_eq!(drain(&queue).await, [(2, false), (3, false), (3, false), (1, true)]);
+}
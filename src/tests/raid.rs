@@ -0,0 +1,75 @@
+use std::time::Instant;
+
+use serde_json::json;
+
+use serenity::client::EventHandler;
+use serenity::model::guild::Member;
+use serenity::model::id::GuildId;
+
+use super::discord;
+use super::{member, server, Harness, NOW, SERVER, USER};
+
+// Three joins in a minute set off raid mode.
+fn raid_config(max_deferred: usize) -> serde_json::Value {
+    json!({ "raid": { "joins_per_minute": 3, "cooldown": 600, "max_deferred": max_deferred } })
+}
+
+async fn join(harness: &Harness, user_id: u64) {
+    let joined: Member = serde_json::from_value(discord::member(user_id, SERVER, &[], Some(NOW as i64 + 300))).unwrap();
+    harness.handler.guild_member_addition(harness.discord.context.clone(), joined).await;
+}
+
+async fn end_raid(harness: &Harness) {
+    if let Some(raid) = harness.handler.raids.lock().await.get_mut(&GuildId::new(SERVER)) {
+        raid.until = Some(Instant::now());
+    }
+    harness.handler.end_raids().await;
+    harness.handler.wait_idle().await;
+}
+
+#[tokio::test]
+async fn raids_only_hold_back_restores() {
+    let harness = Harness::start(raid_config(100), server(&[10])).await;
+    harness.handler.save_member(&member(USER, &[10], Some(NOW - 60))).await.unwrap();
+    harness.clock.advance(600);
+
+    for user_id in [3_000, 3_001, 3_002, USER] {
+        join(&harness, user_id).await;
+    }
+    harness.handler.wait_idle().await;
+
+    // Everyone joining is recorded, but nobody's given anything back yet.
+    for user_id in [3_000, 3_001, 3_002] {
+        assert!(harness.handler.last_seen(&member(user_id, &[], None)).await.unwrap().is_some());
+    }
+    assert!(harness.added_roles(USER).is_empty());
+    assert!(harness.handler.has_pending_restore(&member(USER, &[], None)).await.unwrap());
+    assert_eq!(harness.stored_roles(USER), [10]);
+
+    end_raid(&harness).await;
+    assert_eq!(harness.added_roles(USER), [10]);
+}
+
+#[tokio::test]
+async fn restores_held_back_by_a_raid_are_capped() {
+    let harness = Harness::start(raid_config(1), server(&[10])).await;
+    for user_id in [USER, USER + 1] {
+        harness.handler.save_member(&member(user_id, &[10], Some(NOW - 60))).await.unwrap();
+    }
+    harness.clock.advance(600);
+
+    for user_id in [3_000, 3_001, USER, USER + 1] {
+        join(&harness, user_id).await;
+    }
+    harness.handler.wait_idle().await;
+
+    let deferred = harness.handler.raids.lock().await[&GuildId::new(SERVER)].deferred.len();
+    assert_eq!(deferred, 1);
+    // The one left out still has their restore waiting for when they're next
+    // seen.
+    assert!(harness.handler.has_pending_restore(&member(USER + 1, &[], None)).await.unwrap());
+
+    end_raid(&harness).await;
+    assert_eq!(harness.added_roles(USER), [10]);
+    assert!(harness.added_roles(USER + 1).is_empty());
+}
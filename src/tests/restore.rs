@@ -0,0 +1,112 @@
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+
+use serenity::model::id::GuildId;
+
+use crate::Origin;
+
+use super::discord::{self, Reply, Request};
+use super::{member, Harness, NOW, SERVER, USER};
+
+// Discord for a server with roles 10 to 14, adding each role as asked unless
+// the given function answers for it.
+fn adding(fail: impl Fn(u64) -> Option<Reply> + Send + Sync + 'static) -> impl Fn(&Request) -> Reply + Send + Sync + 'static {
+    let prefix = format!("/guilds/{}/members/{}/roles/", SERVER, USER);
+    move |request| {
+        if request.path == format!("/guilds/{}/roles", SERVER) {
+            Reply::json((10..=14).map(|role| discord::role(role, role as u16 - 9, 0)).collect())
+        } else if let Some(role) = request.path.strip_prefix(&prefix).and_then(|role| role.parse().ok()) {
+            fail(role).unwrap_or_else(Reply::empty)
+        } else {
+            Reply::error(404, 10004)
+        }
+    }
+}
+
+// Stores the test member with the given roles, then has them rejoin with
+// none in the background.
+async fn rejoin(harness: &Harness, roles: &[u64]) -> tokio::task::JoinHandle<()> {
+    harness.handler.save_member(&member(USER, roles, Some(NOW - 60))).await.unwrap();
+    harness.clock.advance(600);
+
+    let handler = harness.handler.clone();
+    let context = harness.discord.context.clone();
+    tokio::spawn(async move {
+        let mut rejoined = member(USER, &[], Some(NOW + 300));
+        handler.observe(&context, &mut rejoined, Origin::Join).await.unwrap();
+    })
+}
+
+#[tokio::test]
+async fn guild_going_unavailable_mid_restore_holds_the_rest() {
+    // Stored roles come back in no particular order otherwise.
+    let config = json!({ "priority_roles": [10, 11, 12] });
+    let harness = Harness::start(config, adding(|role| {
+        (role == 11).then(|| Reply::error(503, 0).after(Duration::from_millis(300)))
+    })).await;
+
+    let restore = rejoin(&harness, &[10, 11, 12]).await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    harness.handler.mark_unavailable(GuildId::new(SERVER)).await;
+    restore.await.unwrap();
+
+    assert!(!harness.added_roles(USER).contains(&12));
+    let held = harness.handler.unavailable_guilds.lock().await.get(&GuildId::new(SERVER)).map_or(0, Vec::len);
+    assert_eq!(held, 1);
+    assert_eq!(harness.stored_roles(USER), [10, 11, 12]);
+}
+
+#[tokio::test]
+async fn members_leaving_mid_restore_stop_it_without_saving() {
+    let config = json!({ "priority_roles": [10, 11, 12, 13, 14] });
+    let harness = Harness::start(config, adding(|role| (role > 11).then(|| Reply::error(404, 10007)))).await;
+
+    rejoin(&harness, &[10, 11, 12, 13, 14]).await.await.unwrap();
+
+    // Nothing more is tried once Discord says they're gone.
+    assert_eq!(harness.added_roles(USER), [10, 11, 12]);
+    // What they had before leaving stays, with the rejoin still there to be
+    // found when they're back.
+    assert_eq!(harness.stored_roles(USER), [10, 11, 12, 13, 14]);
+    assert_eq!(harness.handler.last_seen(&member(USER, &[], None)).await.unwrap(), Some(NOW as i64));
+}
+
+#[tokio::test]
+async fn saves_go_ahead_while_a_restore_waits_on_discord() {
+    let harness = Harness::start(json!({}), adding(|_| Some(Reply::empty().after(Duration::from_secs(1))))).await;
+
+    harness.handler.save_member(&member(USER, &[10], Some(NOW - 60))).await.unwrap();
+    let handler = harness.handler.clone();
+    let context = harness.discord.context.clone();
+    let restore = tokio::spawn(async move {
+        handler.restore_member(&context, &mut member(USER, &[], Some(NOW - 60))).await.unwrap();
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let start = Instant::now();
+    harness.handler.save_member(&member(USER + 1, &[11], Some(NOW))).await.unwrap();
+    assert_eq!(harness.stored_roles(USER + 1), [11]);
+    assert!(start.elapsed() < Duration::from_millis(500), "took {:?}", start.elapsed());
+
+    assert!(!restore.is_finished());
+    restore.await.unwrap();
+    assert_eq!(harness.added_roles(USER), [10]);
+}
+
+#[tokio::test]
+async fn roles_over_the_cap_are_kept_for_a_later_restore() {
+    let config = json!({ "priority_roles": [10, 11, 12, 13], "max_auto_restore_roles": 2 });
+    let harness = Harness::start(config, adding(|_| None)).await;
+
+    rejoin(&harness, &[10, 11, 12, 13]).await.await.unwrap();
+    assert_eq!(harness.added_roles(USER), [10, 11]);
+    assert_eq!(harness.stored_roles(USER), [10, 11]);
+
+    // As staff running /rolepersist restore.
+    let mut restored = member(USER, &[10, 11], Some(NOW + 300));
+    harness.handler.restore_member(&harness.discord.context, &mut restored).await.unwrap();
+
+    assert_eq!(harness.added_roles(USER), [10, 11, 12, 13]);
+    assert_eq!(harness.stored_roles(USER), [10, 11, 12, 13]);
+}
@@ -0,0 +1,22 @@
+use serde_json::json;
+
+use super::{member, Harness, SERVER, USER};
+
+const DAY: u64 = 24 * 60 * 60;
+
+#[tokio::test]
+async fn stats_older_than_the_history_are_dropped() {
+    let harness = Harness::start(json!({ "stats_history_days": 7 }), |_| unreachable!()).await;
+    harness.handler.save_member(&member(USER, &[10], Some(0))).await.unwrap();
+
+    harness.handler.snapshot_stats().await.unwrap();
+    harness.clock.advance(3 * DAY);
+    harness.handler.snapshot_stats().await.unwrap();
+    assert_eq!(harness.handler.stats_history(SERVER, 30).await.unwrap().len(), 2);
+
+    harness.clock.advance(5 * DAY);
+    harness.handler.snapshot_stats().await.unwrap();
+    let history = harness.handler.stats_history(SERVER, 30).await.unwrap();
+    assert_eq!(history.len(), 2);
+    assert!(history.iter().all(|snapshot| snapshot.members == 1));
+}
@@ -0,0 +1,135 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use rusqlite::Connection;
+
+use serde_json::json;
+
+use crate::clock::MockClock;
+use crate::storage::{Extras, RowStorage, Storage};
+use crate::{Config, Handler};
+
+use super::{member, Database, Harness, NOW, SERVER, USER};
+
+fn open(database: &Database, layout: &str) -> Handler {
+    let config = Config::from_json(&json!({ "token": "token", "storage": layout }).to_string()).unwrap();
+    Handler::new(config, &database.0, Arc::new(MockClock::new(NOW))).unwrap()
+}
+
+fn table_exists(handler: &Handler, table: &str) -> bool {
+    let connection = handler.data.lock().unwrap();
+    connection.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type='table' AND name=?1",
+        [table],
+        |row| row.get::<_, u64>(0),
+    ).unwrap() > 0
+}
+
+#[tokio::test]
+async fn json_snapshots_keep_nicknames_and_timeouts() {
+    let harness = Harness::start(json!({ "storage": "json", "store_profiles": true }), |_| unreachable!()).await;
+    let mut timed_out = member(USER, &[10], Some(NOW - 60));
+    timed_out.nick = Some("Nick".to_string());
+    timed_out.timeout = Some(NOW as i64 + 3_600);
+    harness.handler.save_member(&timed_out).await.unwrap();
+
+    // Only the timeout changing still gets it stored.
+    harness.clock.advance(60);
+    timed_out.timeout = None;
+    harness.handler.save_member(&timed_out).await.unwrap();
+
+    let connection = harness.handler.data.lock().unwrap();
+    let extras = harness.handler.storage.extras(&connection, USER, SERVER).unwrap();
+    assert!(extras == Some(Extras { nick: Some("Nick".to_string()), timeout: None }));
+    assert_eq!(harness.handler.stored_roles(&connection, USER, SERVER).unwrap(), [10]);
+}
+
+#[tokio::test]
+async fn switching_layouts_moves_stored_roles_over() {
+    let database = Database::new();
+    for (from, to) in [("rows", "json"), ("json", "rows")] {
+        let before = open(&database, from);
+        before.save_member(&member(USER, &[10, 11], Some(NOW - 60))).await.unwrap();
+        before.save_member(&member(USER + 1, &[12], Some(NOW - 60))).await.unwrap();
+        let old_table = before.storage.table();
+        std::mem::drop(before);
+
+        let after = open(&database, to);
+        assert!(!table_exists(&after, old_table), "{} table left behind", old_table);
+        let connection = after.data.lock().unwrap();
+        let mut roles = after.stored_roles(&connection, USER, SERVER).unwrap();
+        roles.sort_unstable();
+        assert_eq!(roles, [10, 11], "moving from {}", from);
+        assert_eq!(after.stored_roles(&connection, USER + 1, SERVER).unwrap(), [12]);
+    }
+}
+
+// Rows written since the connection was opened.
+fn changes(connection: &Connection) -> u64 {
+    connection.query_row("SELECT total_changes()", [], |row| row.get(0)).unwrap()
+}
+
+#[test]
+fn only_roles_which_changed_are_written() {
+    let connection = Connection::open_in_memory().unwrap();
+    RowStorage.create(&connection).unwrap();
+    let set = |roles: &[u64]| {
+        let roles: HashSet<u64> = roles.iter().copied().collect();
+        let before = changes(&connection);
+        let mut added = RowStorage.set_roles(&connection, USER, SERVER, &roles).unwrap();
+        added.sort_unstable();
+        (added, changes(&connection) - before)
+    };
+
+    let many: Vec<u64> = (1..=700).collect();
+    assert_eq!(set(&many), (many.clone(), 700));
+    assert_eq!(set(&many), (vec![], 0));
+    assert_eq!(set(&[2, 3, 701]), (vec![701], 698 + 1));
+    assert_eq!(set(&[3, 4, 701]), (vec![4], 2));
+
+    let mut stored = RowStorage.roles(&connection, USER, SERVER).unwrap();
+    stored.sort_unstable();
+    assert_eq!(stored, [3, 4, 701]);
+}
+
+#[tokio::test]
+async fn role_and_nickname_changes_are_both_stored_in_either_order() {
+    for roles_first in [true, false] {
+        let harness = Harness::start(json!({ "store_profiles": true }), |_| unreachable!()).await;
+        let mut saved = member(USER, &[10], Some(NOW - 60));
+        harness.handler.save_member(&saved).await.unwrap();
+
+        for change in [roles_first, !roles_first] {
+            if change {
+                saved.roles.insert(11);
+            } else {
+                saved.nick = Some("Nick".to_string());
+            }
+            harness.handler.save_member(&saved).await.unwrap();
+        }
+
+        assert_eq!(harness.stored_roles(USER), [10, 11]);
+        let connection = harness.handler.data.lock().unwrap();
+        let nick: Option<String> = connection.query_row(
+            "SELECT nick FROM last_seen WHERE user_id=?1 AND server_id=?2",
+            [USER, SERVER],
+            |row| row.get(0),
+        ).unwrap();
+        assert_eq!(nick.as_deref(), Some("Nick"), "roles first: {}", roles_first);
+    }
+}
+
+#[tokio::test]
+async fn backups_work_on_a_current_thread_runtime() {
+    let harness = Harness::start(json!({}), |_| unreachable!()).await;
+    harness.handler.save_member(&member(USER, &[10], Some(NOW - 60))).await.unwrap();
+
+    let (path, size) = harness.handler.backup().await.unwrap();
+    let backup = Connection::open(&path).unwrap();
+    let roles: u64 = backup.query_row("SELECT COUNT(*) FROM roles", [], |row| row.get(0)).unwrap();
+    drop(backup);
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(size > 0);
+    assert_eq!(roles, 1);
+}
@@ -0,0 +1,165 @@
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use serenity::client::EventHandler;
+use serenity::model::event::GuildDeleteEvent;
+use serenity::model::id::GuildId;
+
+use super::discord::{self, Reply, Request};
+use crate::Origin;
+
+use super::{Harness, NOW, SERVER};
+
+// The users in the test server's member list, in ID order.
+const MEMBERS: std::ops::Range<u64> = 3_000..5_500;
+
+// Discord listing the members of a server too big for one page, each
+// holding role 10.
+fn listing(request: &Request) -> Reply {
+    let prefix = format!("/guilds/{}/members?", SERVER);
+    let query = match request.path.strip_prefix(&prefix) {
+        Some(query) => query,
+        None => return Reply::error(404, 10004),
+    };
+    let parameter = |name: &str| query.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .and_then(|(_, value)| value.parse::<u64>().ok());
+    let limit = parameter("limit").unwrap_or(1) as usize;
+    let after = parameter("after").unwrap_or(0);
+
+    let page: Vec<Value> = MEMBERS
+        .filter(|user| *user > after)
+        .take(limit)
+        .map(|user| discord::member(user, SERVER, &[10], Some(NOW as i64 - 60)))
+        .collect();
+    Reply::json(json!(page))
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rest_syncs_page_through_every_member() {
+    let harness = Harness::start(json!({ "chunk_sync_threshold": 3_000 }), listing).await;
+    harness.discord.cache_guild(SERVER, &[(10, 1, 0)], None, MEMBERS.count() as u64);
+
+    let synced = harness.handler.save_guild(&harness.discord.context, GuildId::new(SERVER)).await.unwrap();
+    harness.handler.wait_idle().await;
+
+    assert_eq!(synced, 2_500);
+    let pages = harness.discord.requests().iter()
+        .filter(|request| request.path.starts_with(&format!("/guilds/{}/members?", SERVER)))
+        .count();
+    assert_eq!(pages, 3);
+    let connection = harness.handler.data.lock().unwrap();
+    let saved: u64 = connection.query_row(
+        "SELECT COUNT(*) FROM last_seen WHERE server_id=?1",
+        [SERVER],
+        |row| row.get(0),
+    ).unwrap();
+    assert_eq!(saved, 2_500);
+    let first = harness.handler.stored_roles(&connection, MEMBERS.start, SERVER).unwrap();
+    let last = harness.handler.stored_roles(&connection, MEMBERS.end - 1, SERVER).unwrap();
+    assert_eq!((first, last), (vec![10], vec![10]));
+}
+
+#[tokio::test]
+async fn the_initial_sync_goes_ahead_without_unavailable_guilds() {
+    const LATE: u64 = SERVER + 1;
+    let harness = Harness::start(json!({ "unavailable_guild_wait": 0 }), |request| {
+        if request.path.contains("/members?") {
+            Reply::json(json!([]))
+        } else {
+            Reply::error(404, 10004)
+        }
+    }).await;
+    let context = &harness.discord.context;
+    let listed = |server_id: u64| harness.discord.requests().iter()
+        .filter(|request| request.path.starts_with(&format!("/guilds/{}/members?", server_id)))
+        .count();
+
+    // Synced just before a restart, so only being left out earns it another.
+    harness.handler.record_sync(GuildId::new(LATE)).await.unwrap();
+    harness.discord.cache_guild(SERVER, &[], None, 1);
+    let mut outage: GuildDeleteEvent = serde_json::from_value(json!({ "id": LATE.to_string(), "unavailable": true })).unwrap();
+    context.cache.update(&mut outage);
+
+    harness.handler.shards_ready.store(true, Ordering::Relaxed);
+    harness.handler.initial_sync(context).await;
+    while !harness.handler.initial_sync_settled.load(Ordering::Relaxed) {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!((listed(SERVER), listed(LATE)), (1, 0));
+
+    harness.discord.cache_guild(LATE, &[], None, 1);
+    let guild = context.cache.guild(LATE).unwrap().clone();
+    harness.handler.guild_create(context.clone(), guild, Some(false)).await;
+    assert_eq!(listed(LATE), 1);
+}
+
+#[tokio::test]
+async fn sync_durations_leave_out_the_wait_for_a_turn() {
+    let harness = Harness::start(json!({ "sync_concurrency": 1 }), |request| {
+        if request.path.contains("/members?") {
+            Reply::json(json!([])).after(Duration::from_millis(300))
+        } else {
+            Reply::error(404, 10004)
+        }
+    }).await;
+    let context = &harness.discord.context;
+    let guilds = [SERVER, SERVER + 1, SERVER + 2];
+    for server_id in guilds {
+        harness.discord.cache_guild(server_id, &[], None, 1);
+    }
+
+    // Each takes about 300ms, but one at a time the last finishes about 900ms
+    // after they were all started.
+    let start = Instant::now();
+    let syncs = guilds.map(|server_id| harness.handler.sync_guild(context, GuildId::new(server_id), Origin::Sync));
+    for result in futures::future::join_all(syncs).await {
+        let (_, elapsed) = result.unwrap();
+        assert!(elapsed < Duration::from_millis(600), "took {:?}", elapsed);
+    }
+    assert!(start.elapsed() >= Duration::from_millis(900));
+}
+
+// Discord opening a DM channel with the guild's owner and taking messages.
+fn owner_dms(request: &Request) -> Reply {
+    match request.path.as_str() {
+        "/users/@me/channels" => Reply::json(json!({
+            "id": "50",
+            "type": 1,
+            "recipients": [{ "id": "1", "username": "owner" }],
+        })),
+        "/channels/50/messages" => Reply::json(json!({
+            "id": "60",
+            "channel_id": "50",
+            "author": { "id": "1", "username": "owner" },
+            "content": "",
+            "timestamp": "2020-01-01T00:00:00Z",
+            "edited_timestamp": null,
+            "tts": false,
+            "mention_everyone": false,
+            "mentions": [],
+            "mention_roles": [],
+            "attachments": [],
+            "embeds": [],
+            "pinned": false,
+            "type": 0,
+        })),
+        _ => Reply::error(404, 10004),
+    }
+}
+
+#[tokio::test]
+async fn readding_a_guild_due_to_be_forgotten_tells_its_owner() {
+    let harness = Harness::start(json!({ "forget_grace_secs": 3_600 }), owner_dms).await;
+    harness.discord.cache_guild(SERVER, &[(10, 1, 0)], None, 1);
+    let server_id = GuildId::new(SERVER);
+    harness.handler.schedule_forget(server_id, 3_600).await.unwrap();
+
+    harness.handler.check_readded(&harness.discord.context, server_id).await;
+
+    assert_eq!(harness.discord.count("POST", "/channels/50/messages"), 1);
+    assert!(harness.handler.forget_at(server_id).await.unwrap().is_some());
+}
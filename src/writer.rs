@@ -0,0 +1,96 @@
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+
+use rusqlite::{Connection, Result};
+
+use tokio::sync::{mpsc, oneshot};
+use tokio::sync::mpsc::error::TrySendError;
+
+// How many writes can be waiting before anything writing has to wait to
+// queue its own.
+const CAPACITY: usize = 1024;
+
+type Job = Box<dyn FnOnce(&mut Connection) + Send>;
+
+// Owns the connection every write goes through and carries them out one at a
+// time on a thread of its own, so a slow write holds up only what's waiting
+// on it rather than everything reading the database too.
+pub struct Writer {
+    jobs: mpsc::Sender<Job>,
+    thread: JoinHandle<Result<()>>,
+    // Whether the queue was found full, so that's only logged once each time
+    // it fills up.
+    backed_up: AtomicBool,
+}
+
+impl Writer {
+    pub fn start(mut connection: Connection) -> Self {
+        let (jobs, mut receiver) = mpsc::channel::<Job>(CAPACITY);
+        let thread = std::thread::Builder::new()
+            .name("database writer".to_string())
+            .spawn(move || {
+                while let Some(job) = receiver.blocking_recv() {
+                    job(&mut connection);
+                }
+                connection.close().map_err(|(_, error)| error)
+            })
+            .expect("Unable to start database writer");
+
+        Self {
+            jobs,
+            thread,
+            backed_up: AtomicBool::new(false),
+        }
+    }
+
+    // Queues a write and waits for it to be carried out, so anything read
+    // afterwards sees it.
+    pub async fn write<T, F>(&self, write: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    {
+        let (done, result) = oneshot::channel();
+        let job: Job = Box::new(move |connection| {
+            // A write which panics only fails itself, rather than taking the
+            // writer down and every write after it along with it.
+            let result = std::panic::catch_unwind(AssertUnwindSafe(|| write(connection)))
+                .unwrap_or_else(|_| Err(panicked()));
+            let _ = done.send(result);
+        });
+
+        match self.jobs.try_send(job) {
+            Ok(()) => self.backed_up.store(false, Ordering::Relaxed),
+            Err(TrySendError::Full(job)) => {
+                if !self.backed_up.swap(true, Ordering::Relaxed) {
+                    println!("Database writes are queueing up faster than they're written, {} waiting", CAPACITY);
+                }
+                self.jobs.send(job).await.map_err(|_| stopped())?;
+            },
+            Err(TrySendError::Closed(_)) => return Err(stopped()),
+        }
+
+        result.await.map_err(|_| stopped())?
+    }
+
+    // Carries out the writes already queued and closes the connection.
+    pub fn close(self) -> Result<()> {
+        std::mem::drop(self.jobs);
+        self.thread.join().unwrap_or_else(|_| Err(stopped()))
+    }
+}
+
+fn stopped() -> rusqlite::Error {
+    rusqlite::Error::SqliteFailure(
+        rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_MISUSE),
+        Some("the database writer has stopped".to_string()),
+    )
+}
+
+fn panicked() -> rusqlite::Error {
+    rusqlite::Error::SqliteFailure(
+        rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_ERROR),
+        Some("the database write panicked".to_string()),
+    )
+}